@@ -13,6 +13,8 @@
                         cargo
                         rustc
                         pkg-config
+                        # libclang for the bindings of pipewire-sys
+                        rustPlatform.bindgenHook
                         (texlive.combine {
                             inherit (texlive)
                                 scheme-basic
@@ -33,6 +35,11 @@
                         xorg.libXi
                         xorg.libX11
                         libpulseaudio
+
+                        # --features pipewire,alsa,jack
+                        pipewire
+                        alsa-lib
+                        libjack2
                     ];
                     LD_LIBRARY_PATH = "${lib.makeLibraryPath buildInputs}";
                 };
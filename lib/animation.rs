@@ -0,0 +1,222 @@
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+use anyhow::{bail, Context, Result};
+use image::{AnimationDecoder, RgbaImage};
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+
+use crate::decode;
+
+/// Number of decoded frames waiting to be shown, it bounds the memory used
+/// whatever the length of the animation
+const NB_BUFFERED_FRAMES: usize = 2;
+/// Like browsers, delays this short are treated as unset
+const MIN_DELAY: Duration = Duration::from_millis(20);
+const DEFAULT_DELAY: Duration = Duration::from_millis(100);
+
+pub struct Frame {
+    pub image: RgbaImage,
+    pub delay: Duration,
+}
+
+enum Source {
+    Gif(PathBuf),
+    Apng(PathBuf),
+    Sequence { paths: Vec<PathBuf>, delay: Duration },
+}
+
+/// Animated wallpaper, the frames are decoded ahead on a separate thread and
+/// the animation loops forever.
+pub struct Animation {
+    frames: Receiver<Result<Frame>>,
+    delay: Duration,
+    shown_at: Instant,
+}
+
+impl Animation {
+    /// Returns None for still images. A directory is played as an image
+    /// sequence, sorted by file name.
+    pub fn open(path: &Path, sequence_frame_rate: f32) -> Result<Option<Self>> {
+        let source = if path.is_dir() {
+            if sequence_frame_rate <= 0. {
+                bail!("The frame rate of image sequences has to be positive");
+            }
+            let mut paths: Vec<PathBuf> = fs::read_dir(path)
+                .with_context(|| format!("Failed to list {}", path.display()))?
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| decode::is_image(path))
+                .collect();
+            if paths.is_empty() {
+                bail!("No image found in {}", path.display());
+            }
+            paths.sort();
+            Source::Sequence { paths, delay: Duration::from_secs_f32(1. / sequence_frame_rate) }
+        } else {
+            match extension(path).as_deref() {
+                Some("gif") => Source::Gif(path.to_path_buf()),
+                Some("png") => {
+                    let file = File::open(path)
+                        .with_context(|| format!("Failed to open {}", path.display()))?;
+                    if !PngDecoder::new(BufReader::new(file))?.is_apng() {
+                        return Ok(None);
+                    }
+                    Source::Apng(path.to_path_buf())
+                },
+                _ => return Ok(None),
+            }
+        };
+
+        let (sender, frames) = mpsc::sync_channel(NB_BUFFERED_FRAMES);
+        thread::Builder::new()
+            .name("animation decoder".to_string())
+            .spawn(move || decode(source, sender))?;
+        Ok(Some(Self {
+            frames,
+            delay: Duration::ZERO,
+            shown_at: Instant::now(),
+        }))
+    }
+
+    /// Waits for the first frame, to be shown right away.
+    pub fn first_frame(&mut self) -> Result<Frame> {
+        let frame = self.frames.recv().context("The animation has no frame")??;
+        self.delay = frame.delay;
+        self.shown_at = Instant::now();
+        Ok(frame)
+    }
+
+    /// The next frame if the current one has been shown long enough at `now`.
+    pub fn poll(&mut self, now: Instant) -> Option<Frame> {
+        if now - self.shown_at < self.delay {
+            return None;
+        }
+        match self.frames.try_recv() {
+            Ok(Ok(frame)) => {
+                // keep to the schedule unless we are really late
+                self.shown_at = if now - self.shown_at < 2 * self.delay {
+                    self.shown_at + self.delay
+                } else {
+                    now
+                };
+                self.delay = frame.delay;
+                Some(frame)
+            },
+            Ok(Err(error)) => {
+                log::error!("Animation stopped: {:#}", error);
+                None
+            },
+            // the decoder is late, or done because there was a single frame
+            Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => None,
+        }
+    }
+}
+
+fn extension(path: &Path) -> Option<String> {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_lowercase())
+}
+
+fn convert_frame(frame: image::ImageResult<image::Frame>) -> Result<Frame> {
+    let frame = frame?;
+    let mut delay = Duration::from(frame.delay());
+    if delay < MIN_DELAY {
+        delay = DEFAULT_DELAY;
+    }
+    Ok(Frame { image: frame.into_buffer(), delay })
+}
+
+fn frames(source: &Source) -> Result<Box<dyn Iterator<Item = Result<Frame>> + '_>> {
+    Ok(match source {
+        Source::Gif(path) => {
+            let file = File::open(path)
+                .with_context(|| format!("Failed to open {}", path.display()))?;
+            Box::new(GifDecoder::new(BufReader::new(file))?.into_frames().map(convert_frame))
+        },
+        Source::Apng(path) => {
+            let file = File::open(path)
+                .with_context(|| format!("Failed to open {}", path.display()))?;
+            Box::new(PngDecoder::new(BufReader::new(file))?.apng().into_frames().map(convert_frame))
+        },
+        Source::Sequence { paths, delay } => Box::new(paths.iter().map(|path| {
+            let image = decode::open(path)?;
+            Ok(Frame { image: image.to_rgba8(), delay: *delay })
+        })),
+    })
+}
+
+/// Runs on the decoder thread until the animation is dropped or fails.
+fn decode(source: Source, sender: SyncSender<Result<Frame>>) {
+    loop {
+        let frames = match frames(&source) {
+            Ok(frames) => frames,
+            Err(error) => {
+                let _ = sender.send(Err(error));
+                return;
+            },
+        };
+        let mut nb_frames = 0;
+        for frame in frames {
+            let failed = frame.is_err();
+            // sending only fails once the animation is dropped
+            if sender.send(frame).is_err() || failed {
+                return;
+            }
+            nb_frames += 1;
+        }
+        // nothing to animate, the single frame stays on screen
+        if nb_frames <= 1 {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_sequence() {
+        let directory = std::env::temp_dir().join(format!("wallfuck-sequence-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        for (i, shade) in [0u8, 128, 255].iter().enumerate() {
+            RgbaImage::from_pixel(2, 2, image::Rgba([*shade, 0, 0, 255]))
+                .save(directory.join(format!("{:03}.png", i)))
+                .unwrap();
+        }
+        fs::write(directory.join("notes.txt"), "not a frame").unwrap();
+
+        let mut animation = Animation::open(&directory, 50.).unwrap().unwrap();
+        let first = animation.first_frame().unwrap();
+        assert_eq!(first.image.get_pixel(0, 0)[0], 0);
+        assert_eq!(first.delay, Duration::from_millis(20));
+
+        let start = animation.shown_at;
+        let mut shades = Vec::new();
+        for i in 1..=3 {
+            let due = start + i * Duration::from_millis(20);
+            assert!(animation.poll(due - Duration::from_millis(1)).is_none());
+            // the decoder may not be done with the frame yet
+            let frame = (0..1000).find_map(|_| animation.poll(due).or_else(|| {
+                thread::sleep(Duration::from_millis(1));
+                None
+            })).unwrap();
+            shades.push(frame.image.get_pixel(0, 0)[0]);
+        }
+        // looped back to the first frame
+        assert_eq!(shades, vec![128, 255, 0]);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn still_images_are_not_animations() {
+        let path = std::env::temp_dir().join(format!("wallfuck-still-{}.png", std::process::id()));
+        RgbaImage::new(1, 1).save(&path).unwrap();
+        assert!(Animation::open(&path, 24.).unwrap().is_none());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,208 +1,371 @@
-use std::collections::VecDeque;
-use std::rc::Rc;
 use std::cell::RefCell;
-use std::ops::Deref;
-use std::io::{Cursor, Read};
-use byteorder::{NativeEndian, ReadBytesExt};
-use pulse::mainloop::threaded::Mainloop;
-use pulse::context::{Context, introspect::ServerInfo, FlagSet as ContextFlagSet};
-use pulse::stream::{Stream, FlagSet as StreamFlagSet};
-use pulse::sample::{Spec, Format};
-use pulse::proplist::Proplist;
-use pulse::mainloop::api::Mainloop as MainloopTrait; //Needs to be in scope
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
 
 mod dsp;
-use dsp::*;
-use dsp::effects::*;
+use dsp::DSPBuilder;
+use dsp::patch::*;
 mod fft;
 mod wav;
+pub mod spectrum;
+mod source;
+pub use source::AudioSource;
+mod pulseaudio;
+#[cfg(feature = "pipewire")]
+mod pipewire;
+#[cfg(feature = "alsa")]
+mod alsa;
+#[cfg(feature = "jack")]
+mod jack;
 
-pub fn process_audio() {
-    let spec = Spec {
-        format: Format::S16NE,
-        channels: 2,
-        rate: 44100,
-    };
-    assert!(spec.is_valid());
-
-    let mut proplist = Proplist::new().unwrap();
-    proplist.set_str(pulse::proplist::properties::APPLICATION_NAME, "FooApp")
-        .unwrap();
-
-    let mut mainloop = Rc::new(RefCell::new(Mainloop::new()
-        .expect("Failed to create mainloop")));
-
-    let mut context = Rc::new(RefCell::new(Context::new_with_proplist(
-        mainloop.borrow().deref(),
-        "FooAppContext",
-        &proplist
-        ).expect("Failed to create new context")));
-
-    // Context state change callback
-    {
-        let ml_ref = Rc::clone(&mainloop);
-        let context_ref = Rc::clone(&context);
-        context.borrow_mut().set_state_callback(Some(Box::new(move || {
-            let state = unsafe { (*context_ref.as_ptr()).get_state() };
-            match state {
-                pulse::context::State::Ready |
-                pulse::context::State::Failed |
-                pulse::context::State::Terminated => {
-                    unsafe { (*ml_ref.as_ptr()).signal(false); }
-                },
-                _ => {},
-            }
-        })));
-    }
-
-    context.borrow_mut().connect(None, ContextFlagSet::NOFLAGS, None)
-        .expect("Failed to connect context");
-
-    mainloop.borrow_mut().lock();
-    mainloop.borrow_mut().start().expect("Failed to start mainloop");
-
-    // Wait for context to be ready
-    loop {
-        match context.borrow().get_state() {
-            pulse::context::State::Ready => { break; },
-            pulse::context::State::Failed |
-            pulse::context::State::Terminated => {
-                eprintln!("Context state failed/terminated, quitting...");
-                mainloop.borrow_mut().unlock();
-                mainloop.borrow_mut().stop();
-                return;
+const DEFAULT_PATCH: &str = include_str!("audio/patches/transient.ron");
+/// Number of samples kept for the analysis done on the render side
+const FEATURES_HISTORY: usize = 2048;
+/// Samples recorded to go back by the latency of the sink, 1.5s at 44100Hz
+const RECORDING_LENGTH: usize = 65536;
+/// Samples the level is measured on
+const LEVEL_WINDOW: usize = 512;
+/// Rate the patches run at, whatever the backend
+pub const SAMPLE_RATE: u32 = 44100;
+
+/// What the renderer gets to know about the captured audio.
+#[derive(Clone, Default)]
+pub struct AudioFeatures {
+    /// RMS of the latest samples
+    pub level: f64,
+    /// Output of the DSP patch for the latest sample
+    pub transient: f64,
+    /// Latest samples (both channels mixed down), oldest first
+    pub samples: VecDeque<f64>,
+    /// Rate of `samples`, the backends capture at the one of the device
+    pub sample_rate: u32,
+    /// How long ago the latest sample was captured, zero when the backend
+    /// doesn't measure it
+    pub latency: Duration,
+}
+
+/// The samples the backends record and the output of the patch for each of
+/// them, timestamped to find the ones heard when a frame is shown.
+#[derive(Default)]
+struct Recording {
+    samples: VecDeque<f64>,
+    transients: VecDeque<f64>,
+    sample_rate: f64,
+    /// When the latest sample comes out of the speakers
+    heard_at: Option<Instant>,
+    latency: Duration,
+}
+impl Recording {
+    fn new(sample_rate: u32) -> Self {
+        Self { sample_rate: sample_rate as f64, ..Default::default() }
+    }
+
+    /// Runs the patch on a block of mono samples. The latest one was
+    /// captured `latency` ago and is heard `sink_latency` after its capture,
+    /// when recording a monitor.
+    fn push(&mut self, patch: &Patch, samples: impl Iterator<Item = f64>, latency: Duration, sink_latency: Duration) {
+        let effect = patch.output().effect().unwrap();
+        for sample in samples {
+            self.transients.push_back(effect.borrow_mut().tick(sample));
+            self.samples.push_back(sample);
+        }
+        let excess = self.samples.len().saturating_sub(RECORDING_LENGTH);
+        self.samples.drain(..excess);
+        self.transients.drain(..excess);
+        let now = Instant::now();
+        self.heard_at = Some(now.checked_sub(latency).unwrap_or(now) + sink_latency);
+        self.latency = latency;
+    }
+
+    /// The features of the samples heard at `time`, the latest ones without it
+    fn features(&self, time: Option<Instant>) -> AudioFeatures {
+        let heard_later = match (time, self.heard_at) {
+            (Some(time), Some(heard_at)) => heard_at.saturating_duration_since(time),
+            _ => Duration::ZERO,
+        };
+        let end = self.samples.len().saturating_sub((heard_later.as_secs_f64() * self.sample_rate) as usize);
+        let window = end.saturating_sub(LEVEL_WINDOW)..end;
+        let sum_squares: f64 = self.samples.range(window.clone()).map(|sample| sample * sample).sum();
+        AudioFeatures {
+            level: if window.is_empty() { 0. } else { (sum_squares / window.len() as f64).sqrt() },
+            transient: end.checked_sub(1).map_or(0., |last| self.transients[last]),
+            samples: self.samples.range(end.saturating_sub(FEATURES_HISTORY)..end).copied().collect(),
+            sample_rate: self.sample_rate as u32,
+            latency: self.latency,
+        }
+    }
+}
+
+/// Sound server the audio is recorded from
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum AudioBackend {
+    /// PulseAudio, or PipeWire through pipewire-pulse
+    #[default]
+    Pulse,
+    /// PipeWire without pipewire-pulse, needs the `pipewire` feature
+    PipeWire,
+    /// A PCM device without a sound server, needs the `alsa` feature
+    Alsa,
+    /// A JACK client, needs the `jack` feature
+    Jack,
+}
+
+fn to_float(sample: i16) -> f64 {
+    if sample > 0 {
+        sample as f64 / i16::MAX as f64
+    } else {
+        -(sample as f64 / i16::MIN as f64)
+    }
+}
+
+/// The low-latency transient detector
+fn default_patch(builder: &DSPBuilder) -> Patch {
+    let description: PatchDescription = DEFAULT_PATCH.parse()
+        .expect("Failed to parse the transient detector patch");
+    builder.build_patch(&description)
+        .expect("Failed to build the transient detector patch")
+}
+
+fn build_patch(builder: &DSPBuilder, description: &PatchDescription) -> Result<Patch> {
+    let patch = builder.build_patch(description)?;
+    if patch.output().effect().is_none() {
+        bail!("The output of the patch must be an effect to process captured audio");
+    }
+    Ok(patch)
+}
+
+fn set_parameter(patch: &Patch, node: &str, parameter: &str, value: f64) -> Result<()> {
+    match patch.node(node) {
+        Some(patch_node) => match patch_node.parameter(parameter) {
+            Some(mut target) => {
+                target.value = value;
+                Ok(())
             },
-            _ => { mainloop.borrow_mut().wait(); },
+            None => Err(anyhow!("Node {} has no parameter {}", node, parameter)),
+        },
+        None => Err(anyhow!("The patch has no node {}", node)),
+    }
+}
+
+/// What the backends do, the samples going through the patch on their own thread
+trait Capture {
+    /// The features heard at `time`, the latest ones without it
+    fn features(&self, time: Option<Instant>) -> AudioFeatures;
+    /// Replaces the patch, the current one is kept if the new one can't be built
+    fn load_patch(&mut self, description: &PatchDescription) -> Result<()>;
+    fn set_parameter(&mut self, node: &str, parameter: &str, value: f64) -> Result<()>;
+    /// Saves the patch with its current parameters
+    fn save_patch(&mut self, path: &Path) -> Result<()>;
+    /// Stops recording, the features stay as they were
+    fn set_paused(&mut self, paused: bool);
+}
+
+/// Sent to the thread the patch lives on, for the backends with one
+#[cfg_attr(not(any(feature = "pipewire", feature = "alsa", feature = "jack")), allow(dead_code))]
+enum Message {
+    Patch(PatchDescription, mpsc::Sender<Result<()>>),
+    Parameter { node: String, parameter: String, value: f64, reply: mpsc::Sender<Result<()>> },
+    SavePatch(PathBuf, mpsc::Sender<Result<()>>),
+    Paused(bool),
+    Quit,
+}
+#[cfg_attr(not(any(feature = "pipewire", feature = "alsa", feature = "jack")), allow(dead_code))]
+impl Message {
+    /// Applies the messages about the patch, the others are left to the backend
+    fn apply(self, builder: &DSPBuilder, patch: &RefCell<Patch>) -> Option<Self> {
+        match self {
+            Message::Patch(description, reply) => {
+                let result = build_patch(builder, &description).map(|new_patch| {
+                    *patch.borrow_mut() = new_patch;
+                });
+                let _ = reply.send(result);
+                None
+            },
+            Message::Parameter { node, parameter, value, reply } => {
+                let _ = reply.send(set_parameter(&patch.borrow(), &node, &parameter, value));
+                None
+            },
+            Message::SavePatch(path, reply) => {
+                let _ = reply.send(patch.borrow().save(path));
+                None
+            },
+            message => Some(message),
+        }
+    }
+}
+
+/// A backend recording on a thread of its own, driven by messages
+struct ThreadCapture {
+    name: &'static str,
+    /// False once the thread is gone
+    send: Box<dyn Fn(Message) -> bool>,
+    recording: Arc<Mutex<Recording>>,
+    thread: Option<JoinHandle<()>>,
+}
+impl ThreadCapture {
+    fn send(&self, message: Message) -> Result<()> {
+        if !(self.send)(message) {
+            bail!("The {} thread stopped", self.name);
         }
+        Ok(())
     }
-    context.borrow_mut().set_state_callback(None);
 
-    let mut default_sink = Rc::new(RefCell::new(String::new()));
-    let server_info_op = {
-        let ds_ref = Rc::clone(&default_sink);
-        context.borrow().introspect().get_server_info(move |server_info: &ServerInfo<'_>| {
-            let ds = server_info.default_sink_name.as_ref().unwrap().as_ref();
-            unsafe {
-                (*ds_ref.as_ptr()).push_str(ds);
-            }
+    /// Sends a message to the thread and waits for its result
+    fn request(&self, message: impl FnOnce(mpsc::Sender<Result<()>>) -> Message) -> Result<()> {
+        let (reply, result) = mpsc::channel();
+        self.send(message(reply))?;
+        result.recv().with_context(|| format!("The {} thread stopped", self.name))?
+    }
+}
+impl Capture for ThreadCapture {
+    fn features(&self, time: Option<Instant>) -> AudioFeatures {
+        self.recording.lock().unwrap().features(time)
+    }
+
+    fn load_patch(&mut self, description: &PatchDescription) -> Result<()> {
+        self.request(|reply| Message::Patch(description.clone(), reply))
+    }
+
+    fn set_parameter(&mut self, node: &str, parameter: &str, value: f64) -> Result<()> {
+        self.request(|reply| Message::Parameter {
+            node: node.to_string(),
+            parameter: parameter.to_string(),
+            value,
+            reply,
         })
-    };
-    mainloop.borrow_mut().unlock();
-    while server_info_op.get_state() != pulse::operation::State::Done {}
-    mainloop.borrow_mut().lock();
-    default_sink.borrow_mut().push_str(".monitor");
-
-    let mut stream = Rc::new(RefCell::new(Stream::new(
-        &mut context.borrow_mut(),
-        "Audio Spy",
-        &spec,
-        None
-        ).expect("Failed to create new stream")));
-
-    // Stream state change callback
-    {
-        let ml_ref = Rc::clone(&mainloop);
-        let stream_ref = Rc::clone(&stream);
-        stream.borrow_mut().set_state_callback(Some(Box::new(move || {
-            let state = unsafe { (*stream_ref.as_ptr()).get_state() };
-            match state {
-                pulse::stream::State::Ready |
-                pulse::stream::State::Failed |
-                pulse::stream::State::Terminated => {
-                    unsafe { (*ml_ref.as_ptr()).signal(false); }
-                },
-                _ => {},
-            }
-        })));
-    }
-
-    stream.borrow_mut().connect_record(Some(default_sink.borrow().as_ref()),
-        None, StreamFlagSet::START_CORKED).expect("Failed to connect playback");
-
-    // Wait for stream to be ready
-    loop {
-        match stream.borrow().get_state() {
-            pulse::stream::State::Ready => { break; },
-            pulse::stream::State::Failed |
-            pulse::stream::State::Terminated => {
-                eprintln!("Stream state failed/terminated, quitting...");
-                mainloop.borrow_mut().unlock();
-                mainloop.borrow_mut().stop();
-                return;
+    }
+
+    fn save_patch(&mut self, path: &Path) -> Result<()> {
+        self.request(|reply| Message::SavePatch(path.to_path_buf(), reply))
+    }
+
+    fn set_paused(&mut self, paused: bool) {
+        if let Err(error) = self.send(Message::Paused(paused)) {
+            log::error!("{:#}", error);
+        }
+    }
+}
+impl Drop for ThreadCapture {
+    fn drop(&mut self) {
+        let _ = self.send(Message::Quit);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Keeps the capture stream running until dropped.
+pub struct AudioCapture {
+    backend: Box<dyn Capture>,
+}
+impl AudioCapture {
+    /// Features of the latest samples
+    pub fn features(&self) -> AudioFeatures {
+        self.backend.features(None)
+    }
+
+    /// Features of the samples coming out of the speakers at `time`, from
+    /// the latencies of the capture and of the sink. Visuals showing them at
+    /// `time` match what is heard.
+    pub fn features_at(&self, time: Instant) -> AudioFeatures {
+        self.backend.features(Some(time))
+    }
+
+    /// Swaps the DSP graph processing the captured samples. The current
+    /// graph is kept if the new one can't be built.
+    pub fn load_patch(&mut self, path: &Path) -> Result<()> {
+        let description = PatchDescription::load(path)?;
+        self.backend.load_patch(&description)
+            .with_context(|| format!("Failed to load patch {}", path.display()))
+    }
+
+    /// Sets a parameter of a node of the patch, until it is reloaded
+    pub fn set_parameter(&mut self, node: &str, parameter: &str, value: f64) -> Result<()> {
+        self.backend.set_parameter(node, parameter, value)
+    }
+
+    /// Saves the patch with the parameters set since it was loaded
+    pub fn save_patch(&mut self, path: &Path) -> Result<()> {
+        self.backend.save_patch(path)
+    }
+
+    /// Stops the capture stream, the features stay as they were
+    pub fn set_paused(&mut self, paused: bool) {
+        self.backend.set_paused(paused);
+    }
+}
+
+fn pulse_capture(audio_source: &AudioSource, fragment: Option<Duration>) -> Option<Box<dyn Capture>> {
+    pulseaudio::PulseCapture::new(audio_source, fragment).map(|capture| Box::new(capture) as Box<dyn Capture>)
+}
+
+/// The backends recording on a thread of their own, Pulse being their fallback
+#[allow(unused_variables)]
+fn thread_capture(audio_backend: AudioBackend, audio_source: &AudioSource) -> Result<ThreadCapture> {
+    match audio_backend {
+        #[cfg(feature = "pipewire")]
+        AudioBackend::PipeWire => pipewire::capture(audio_source),
+        #[cfg(feature = "alsa")]
+        AudioBackend::Alsa => alsa::capture(audio_source),
+        #[cfg(feature = "jack")]
+        AudioBackend::Jack => jack::capture(audio_source),
+        _ => bail!("The {:?} backend isn't built in, enable its feature", audio_backend),
+    }
+}
+
+/// `fragment` is the size of the reads from Pulse, the server decides without it
+pub fn process_audio(
+    patch_path: Option<&Path>,
+    audio_source: &AudioSource,
+    audio_backend: AudioBackend,
+    fragment: Option<Duration>,
+) -> Option<AudioCapture> {
+    let backend = match audio_backend {
+        AudioBackend::Pulse => pulse_capture(audio_source, fragment)?,
+        _ => match thread_capture(audio_backend, audio_source) {
+            Ok(capture) => Box::new(capture),
+            Err(error) => {
+                log::error!("{:#}, recording with Pulse", error);
+                pulse_capture(audio_source, fragment)?
             },
-            _ => { mainloop.borrow_mut().wait(); },
+        },
+    };
+    let mut capture = AudioCapture { backend };
+    if let Some(path) = patch_path {
+        if let Err(error) = capture.load_patch(path) {
+            log::error!("{:#}", error);
         }
     }
-    stream.borrow_mut().set_state_callback(None);
-
-    // create WAV file
-    wav::write_test_wav().unwrap();
-
-    // Low-latency transient detector
-    // https://www.youtube.com/watch?v=QeC_cSnF2BM&t=286s
-    let builder = DSPBuilder::new(44100);
-    let low_pass = builder.build_first_order_filter(
-        FirstOrderFilterKind::LowPass, 500.
-    );
-    let absolute = builder.build_operator(|sample| sample.abs());
-    let moving_average = builder.build_moving_average(100);
-    let slide = builder.build_slide(4000., 4000.);
-    let amplifier_1 = builder.build_amplifier(2.);
-    let clip = builder.build_operator(|sample|
-        if sample < 0. {0.}
-        else if sample > 1. {1.}
-        else {sample}
-    );
-    let chain = builder.build_fx_chain();
-    let mut bm_chain = chain.borrow_mut();
-    bm_chain.append(low_pass);
-    bm_chain.append(absolute);
-    bm_chain.append(moving_average);
-    bm_chain.append(slide);
-    bm_chain.append(amplifier_1);
-    bm_chain.append(clip);
-
-    let sample_queue = Rc::new(RefCell::new(VecDeque::<f64>::new()));
-    {
-        let stream_ref = Rc::clone(&stream);
-        let chain_ref = chain.clone();
-        let sample_queue_ref = sample_queue.clone();
-        stream.borrow_mut().set_read_callback(Some(Box::new(move |nb_bytes: usize| {
-            let peek_result = stream_ref.borrow_mut().peek().expect("Failed to read stream");
-            match peek_result {
-                pulse::stream::PeekResult::Empty => {
-                    println!("empty");
-                }
-                pulse::stream::PeekResult::Hole(size) => {
-                    println!("hole of size {}", size);
-                }
-                pulse::stream::PeekResult::Data(data) => {
-                    println!("received {} bytes", data.len());
-                    let mut cursor = Cursor::new(data); 
-                    loop {
-                        if let Ok(sample) = cursor.read_i16::<NativeEndian>() {
-                            let float_sample = if sample > 0 {
-                                sample as f64 / std::i16::MAX as f64
-                            } else {
-                                -(sample as f64 / std::i16::MIN as f64)
-                            };
-                            sample_queue_ref.borrow_mut().push_back(
-                                chain_ref.borrow_mut().tick(float_sample)
-                            );
-                        } else {
-                            break;
-                        }
-                    }
-                }
-            }
-            stream_ref.borrow_mut().discard().expect("Failed to discard current fragment");
-        })));
-    }
-    stream.borrow_mut().uncork(None);
-    mainloop.borrow_mut().unlock();
-
-    // Clean shutdown
-    mainloop.borrow_mut().lock();
-    stream.borrow_mut().disconnect().unwrap();
-    mainloop.borrow_mut().unlock();
+    Some(capture)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn features_heard_later() {
+        let patch = default_patch(&DSPBuilder::new(SAMPLE_RATE as u64));
+        let mut recording = Recording::new(1000);
+        assert_eq!(recording.features(Some(Instant::now())).level, 0.);
+        recording.push(&patch, std::iter::repeat(0.5).take(1000), Duration::ZERO, Duration::ZERO);
+        recording.push(&patch, std::iter::repeat(0.).take(1000), Duration::from_millis(10), Duration::from_secs(1));
+        let heard_at = recording.heard_at.unwrap();
+        assert_eq!(recording.latency, Duration::from_millis(10));
+
+        let latest = recording.features(None);
+        assert_eq!((latest.level, latest.samples.len()), (0., 2000));
+        // 1.2s earlier, in the middle of the first block
+        let earlier = recording.features(Some(heard_at - Duration::from_millis(1200)));
+        assert_eq!(earlier.level, 0.5);
+        assert_eq!(earlier.samples.back(), Some(&0.5));
+        assert_eq!(recording.features(Some(heard_at + Duration::from_secs(1))).level, 0.);
+        // before anything was recorded
+        assert_eq!(recording.features(Some(heard_at - Duration::from_secs(5))).samples.len(), 0);
+    }
 }
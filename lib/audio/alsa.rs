@@ -0,0 +1,182 @@
+use std::cell::RefCell;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use alsa::pcm::{Access, Format, HwParams, PCM};
+use alsa::{Direction, ValueOr};
+use anyhow::{bail, Context, Result};
+
+use super::dsp::DSPBuilder;
+use super::source::AudioSource;
+use super::{default_patch, to_float, Message, Recording, ThreadCapture, SAMPLE_RATE};
+
+/// Frames read at once, about 12ms at 44100Hz
+const PERIOD: usize = 512;
+
+/// PCM device recording `audio_source`. ALSA has no monitors, what plays is
+/// recorded from the capture side of a loopback device like `hw:Loopback,1,0`,
+/// the `default` PCM would record the microphone instead of the sink.
+fn device_name(audio_source: &AudioSource) -> Result<&str> {
+    match audio_source {
+        AudioSource::Source(name) => Ok(name),
+        AudioSource::DefaultSink | AudioSource::Sink(_) => bail!("ALSA can't record sinks, play to snd-aloop and record its capture device"),
+        AudioSource::Application(_) => bail!("Applications are only recorded alone with the Pulse backend"),
+    }
+}
+
+/// Opens the device in interleaved 16 bits, returning its channels and rate.
+/// `hw` devices may not have the ones asked for, `plughw` converts to them.
+fn open(device: &str) -> Result<(PCM, usize, u32)> {
+    let pcm = PCM::new(device, Direction::Capture, false)
+        .with_context(|| format!("Failed to open the ALSA device {}", device))?;
+    let (channels, rate) = {
+        let hw_params = HwParams::any(&pcm)?;
+        hw_params.set_access(Access::RWInterleaved)?;
+        hw_params.set_format(Format::s16())?;
+        let channels = hw_params.set_channels_near(2)?;
+        let rate = hw_params.set_rate_near(SAMPLE_RATE, ValueOr::Nearest)?;
+        hw_params.set_period_size_near(PERIOD as i64, ValueOr::Nearest)?;
+        pcm.hw_params(&hw_params)
+            .with_context(|| format!("Failed to configure the ALSA device {}", device))?;
+        (channels as usize, rate)
+    };
+    pcm.start()?;
+    Ok((pcm, channels, rate))
+}
+
+/// Mixes down interleaved frames of `channels` samples
+fn mono_samples(samples: &[i16], channels: usize) -> impl Iterator<Item = f64> + '_ {
+    samples.chunks_exact(channels).map(move |frame| {
+        frame.iter().map(|&sample| to_float(sample)).sum::<f64>() / channels as f64
+    })
+}
+
+/// Reads the device period after period, handling the messages in between
+fn run(pcm: PCM, channels: usize, rate: u32, receiver: mpsc::Receiver<Message>, recording: Arc<Mutex<Recording>>) {
+    let builder = DSPBuilder::new(rate as u64);
+    let patch = RefCell::new(default_patch(&builder));
+    let mut buffer = vec![0; PERIOD * channels];
+    let mut paused = false;
+    loop {
+        // nothing to read while paused, wait for the next message
+        let message = match paused {
+            true => Some(receiver.recv().unwrap_or(Message::Quit)),
+            false => receiver.try_recv().ok(),
+        };
+        match message.and_then(|message| message.apply(&builder, &patch)) {
+            Some(Message::Paused(pause)) if pause != paused => {
+                paused = pause;
+                let result = match paused {
+                    true => pcm.drop(),
+                    false => pcm.prepare().and_then(|_| pcm.start()),
+                };
+                if let Err(error) = result {
+                    log::error!("Failed to pause the ALSA device: {}", error);
+                }
+            },
+            Some(Message::Quit) => return,
+            _ => {},
+        }
+        if paused {
+            continue;
+        }
+
+        let result = pcm.io_i16().and_then(|io| io.readi(&mut buffer));
+        match result {
+            Ok(frames) => {
+                // frames captured and not read yet
+                let delay = pcm.delay().unwrap_or_default().max(0);
+                let latency = Duration::from_secs_f64(delay as f64 / rate as f64);
+                let samples = mono_samples(&buffer[..frames * channels], channels);
+                recording.lock().unwrap().push(&patch.borrow(), samples, latency, Duration::ZERO);
+            },
+            // overruns when the thread was late
+            Err(error) => if let Err(error) = pcm.try_recover(error, true) {
+                log::error!("ALSA capture stopped: {}", error);
+                return;
+            },
+        }
+    }
+}
+
+/// Records a PCM device directly, without a sound server, on a thread of its own.
+pub fn capture(audio_source: &AudioSource) -> Result<ThreadCapture> {
+    let device = device_name(audio_source)?.to_string();
+    let (sender, receiver) = mpsc::channel();
+    let (started, start) = mpsc::channel();
+    let recording = Arc::new(Mutex::new(Recording::default()));
+    let recording_ref = recording.clone();
+    // the PCM isn't Send, it is opened on the thread reading it
+    let thread = thread::Builder::new().name("alsa".to_string()).spawn(move || {
+        match open(&device) {
+            Ok((pcm, channels, rate)) => {
+                *recording_ref.lock().unwrap() = Recording::new(rate);
+                let _ = started.send(Ok((channels, rate)));
+                run(pcm, channels, rate, receiver, recording_ref);
+            },
+            Err(error) => {
+                let _ = started.send(Err(error));
+            },
+        }
+    })?;
+    let (channels, rate) = start.recv().context("The ALSA thread stopped")??;
+    log::info!("Recording {} channels at {}Hz with ALSA", channels, rate);
+    Ok(ThreadCapture {
+        name: "ALSA",
+        send: Box::new(move |message| sender.send(message).is_ok()),
+        recording,
+        thread: Some(thread),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::Capture;
+    use std::time::Instant;
+
+    #[test]
+    fn devices() {
+        assert_eq!(device_name(&AudioSource::Source("plughw:Loopback,1,0".to_string())).unwrap(), "plughw:Loopback,1,0");
+        assert!(device_name(&AudioSource::Sink("speakers".to_string())).is_err());
+        assert!(device_name(&AudioSource::DefaultSink).is_err());
+
+        assert_eq!(mono_samples(&[i16::MAX, 0, 0, 0, i16::MIN, i16::MIN], 3).collect::<Vec<_>>(), [1. / 3., -2. / 3.]);
+    }
+
+    #[test]
+    #[ignore = "needs the snd-aloop module loaded"]
+    fn loopback() {
+        let playing = Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let playing_ref = playing.clone();
+        let player = thread::spawn(move || {
+            let pcm = PCM::new("plughw:Loopback,0,0", Direction::Playback, false).unwrap();
+            {
+                let hw_params = HwParams::any(&pcm).unwrap();
+                hw_params.set_access(Access::RWInterleaved).unwrap();
+                hw_params.set_format(Format::s16()).unwrap();
+                hw_params.set_channels(2).unwrap();
+                hw_params.set_rate(SAMPLE_RATE, ValueOr::Nearest).unwrap();
+                pcm.hw_params(&hw_params).unwrap();
+            }
+            let io = pcm.io_i16().unwrap();
+            let square: Vec<i16> = (0..PERIOD * 2).map(|i| if i / 64 % 2 == 0 { 8192 } else { -8192 }).collect();
+            while playing_ref.load(std::sync::atomic::Ordering::Relaxed) {
+                if let Err(error) = io.writei(&square) {
+                    pcm.try_recover(error, true).unwrap();
+                }
+            }
+        });
+
+        let mut capture = capture(&AudioSource::Source("plughw:Loopback,1,0".to_string())).unwrap();
+        assert!(capture.set_parameter("nowhere", "gain", 1.).is_err());
+        let start = Instant::now();
+        let level = || capture.features(None).level;
+        while (level() - 0.25).abs() > 0.01 && start.elapsed() < Duration::from_secs(2) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!((level() - 0.25).abs() < 0.01);
+        playing.store(false, std::sync::atomic::Ordering::Relaxed);
+        player.join().unwrap();
+    }
+}
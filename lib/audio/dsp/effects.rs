@@ -9,6 +9,7 @@ use std::collections::VecDeque;
 use std::cell::RefCell;
 use std::rc::Rc;
 use std::cmp;
+use serde::{Deserialize, Serialize};
 
 //==============================================================================
 // Framework glue
@@ -196,6 +197,7 @@ impl DSPMonoEffect for DownSample {
 }
 
 //==============================================================================
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub enum FirstOrderFilterKind {
     AllPass,
     LowPass,
@@ -211,7 +213,7 @@ pub struct FirstOrderFilter {
     sample_rate: u64,
 }
 impl FirstOrderFilter {
-    fn new(kind: FirstOrderFilterKind, cut_off: Frequency, sample_rate: u64) -> Self {
+    pub(super) fn new(kind: FirstOrderFilterKind, cut_off: Frequency, sample_rate: u64) -> Self {
         Self {
             kind,
             sample_rate,
@@ -247,6 +249,7 @@ impl DSPMonoEffect for FirstOrderFilter {
 }
 
 //==============================================================================
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub enum SecondOrderFilterKind {
     AllPass,
     BandStop,
@@ -366,6 +369,7 @@ impl DSPMonoEffect for BiquadFilter {
 }
 
 //==============================================================================
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub enum ButterworthFilterKind {
     LowPass,
     HighPass,
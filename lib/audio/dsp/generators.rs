@@ -6,8 +6,9 @@ use std::cell::RefCell;
 use std::rc::Rc;
 use rand::{thread_rng, Rng};
 use rand::rngs::ThreadRng;
+use serde::{Deserialize, Serialize};
 
-use super::effects::FxChain;
+use super::effects::{FirstOrderFilter, FirstOrderFilterKind, FxChain};
 
 //==============================================================================
 // Framework glue
@@ -51,6 +52,17 @@ impl DSPBuilder {
         Rc::new(RefCell::new(ADSR::new(attack, attack_curve, peak, decay,
                     decay_curve, sustain, release, release_curve, self.sample_rate)))
     }
+    pub fn build_voice_allocator(&self,
+        nb_voices: usize,
+        voice: &VoiceDescription,
+        stealing: VoiceStealing,
+        glide: Ms,
+        detune: f64,
+        ) -> Rc<RefCell<VoiceAllocator>>
+    {
+        Rc::new(RefCell::new(VoiceAllocator::new(nb_voices, voice, stealing, glide,
+                    detune, self.sample_rate)))
+    }
 }
 
 
@@ -162,9 +174,219 @@ impl DSPMonoGenerator for Parallel {
 
 
 
+//==============================================================================
+/// Which voice a note takes when they all play
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum VoiceStealing {
+    /// The voice whose note started first
+    Oldest,
+    /// The voice with the lowest envelope, past its attack, the oldest among
+    /// the quietest
+    Quietest,
+}
+
+/// What every voice of a `VoiceAllocator` is made of: `unison` oscillators,
+/// detuned around the note, through an envelope then an optional filter.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VoiceDescription {
+    pub kind: WaveKind,
+    pub unison: usize,
+    pub attack: u64, pub attack_curve: f64,
+    pub peak: f64,
+    pub decay: u64, pub decay_curve: f64,
+    pub sustain: f64,
+    pub release: u64, pub release_curve: f64,
+    pub filter: Option<(FirstOrderFilterKind, Frequency)>,
+}
+
+/// Frequency of a MIDI note, A4 being 69
+pub fn note_frequency(note: u8) -> Frequency {
+    440. * 2f64.powf((note as f64 - 69.) / 12.)
+}
+
+struct Voice {
+    oscillators: Vec<Oscillator>,
+    envelope: ADSR,
+    filter: Option<FirstOrderFilter>,
+    /// Latest note played, held or released
+    note: Option<u8>,
+    velocity: f64,
+    frequency: Frequency,
+    target: Frequency,
+    /// Number of the note among the ones played, to steal the oldest
+    started: u64,
+    /// Latest envelope value, to steal the quietest
+    level: f64,
+}
+impl Voice {
+    fn new(description: &VoiceDescription, sample_rate: u64) -> Self {
+        let oscillators = (0..description.unison.max(1))
+            .map(|_| Oscillator::new(description.kind, 0., 1., sample_rate))
+            .collect();
+        Self {
+            oscillators,
+            envelope: ADSR::new(description.attack, description.attack_curve, description.peak,
+                description.decay, description.decay_curve, description.sustain,
+                description.release, description.release_curve, sample_rate),
+            filter: description.filter.map(|(kind, cut_off)| FirstOrderFilter::new(kind, cut_off, sample_rate)),
+            note: None,
+            velocity: 0.,
+            frequency: 0.,
+            target: 0.,
+            started: 0,
+            level: 0.,
+        }
+    }
+    fn is_free(&self) -> bool {
+        matches!(self.envelope.state, ADSRState::Off)
+    }
+    fn in_attack(&self) -> bool {
+        matches!(self.envelope.state, ADSRState::Attack(_))
+    }
+    fn is_held(&self) -> bool {
+        matches!(self.envelope.state, ADSRState::Attack(_) | ADSRState::Decay(_) | ADSRState::Sustain)
+    }
+    /// `detune` is in cents, `glide` the coefficient of the frequency
+    /// moving to the note
+    fn tick(&mut self, detune: f64, glide: f64) -> Mono {
+        if self.is_free() {
+            self.level = 0.;
+            return 0.;
+        }
+        // in octaves, so a glide takes as long whatever the interval
+        self.frequency = self.target * (self.frequency / self.target).powf(glide);
+
+        let nb_oscillators = self.oscillators.len();
+        let mut sum = 0.;
+        for (i, oscillator) in self.oscillators.iter_mut().enumerate() {
+            // spread evenly from -detune / 2 to detune / 2
+            let spread = if nb_oscillators > 1 { i as f64 / (nb_oscillators - 1) as f64 - 0.5 } else { 0. };
+            oscillator.frequency.value = self.frequency * 2f64.powf(detune * spread / 1200.);
+            sum += oscillator.tick(1).unwrap_or(0.);
+        }
+        self.level = self.envelope.tick(1).unwrap_or(0.) * self.velocity;
+        let sample = sum / nb_oscillators as f64 * self.level;
+        match &mut self.filter {
+            Some(filter) => filter.tick(sample),
+            None => sample,
+        }
+    }
+}
+
+/// Plays notes on a fixed number of voices, stealing one when they all play.
+/// The voices are mixed, `amplitude` keeps the sum in range.
+pub struct VoiceAllocator {
+    voices: Vec<Voice>,
+    stealing: VoiceStealing,
+    /// Ms for a new note to get two thirds of the way from the previous one
+    pub glide: Parameter,
+    /// Cents between the lowest and the highest oscillators of a voice
+    pub detune: Parameter,
+    pub amplitude: Parameter,
+    pub enabled: Parameter,
+    last_frequency: Option<Frequency>,
+    nb_notes: u64,
+    sample_rate: u64,
+
+    multi_hold: Mono,
+    multi_index: usize,
+}
+impl VoiceAllocator {
+    fn new(
+        nb_voices: usize,
+        voice: &VoiceDescription,
+        stealing: VoiceStealing,
+        glide: Ms,
+        detune: f64,
+        sample_rate: u64,
+        ) -> Self {
+        Self {
+            voices: (0..nb_voices.max(1)).map(|_| Voice::new(voice, sample_rate)).collect(),
+            stealing,
+            glide: Parameter::new(glide),
+            detune: Parameter::new(detune),
+            amplitude: Parameter::new(1.),
+            enabled: Parameter::new(1.),
+            last_frequency: None,
+            nb_notes: 0,
+            sample_rate,
+            multi_hold: 0.,
+            multi_index: 0,
+        }
+    }
+    /// Plays a MIDI note, `velocity` going from 0 to 1. It takes the voice
+    /// already playing it, else a free one, else steals one.
+    pub fn note_on(&mut self, note: u8, velocity: f64) {
+        let index = self.voices.iter().position(|voice| voice.note == Some(note) && !voice.is_free())
+            .or_else(|| self.voices.iter().position(Voice::is_free))
+            .unwrap_or_else(|| self.steal());
+        let target = note_frequency(note);
+        let voice = &mut self.voices[index];
+        voice.frequency = match self.last_frequency {
+            Some(frequency) if self.glide.real_value() > 0. => frequency,
+            _ => target,
+        };
+        voice.target = target;
+        voice.note = Some(note);
+        voice.velocity = velocity.clamp(0., 1.);
+        voice.started = self.nb_notes;
+        voice.envelope.state = ADSRState::Attack(0);
+        voice.level = 0.;
+        self.nb_notes += 1;
+        self.last_frequency = Some(target);
+    }
+    /// Releases a note, its voice is free once the envelope ends
+    pub fn note_off(&mut self, note: u8) {
+        for voice in &mut self.voices {
+            if voice.note == Some(note) && voice.is_held() {
+                voice.envelope.state = ADSRState::Release(0);
+            }
+        }
+    }
+    fn steal(&self) -> usize {
+        let voices = self.voices.iter().enumerate();
+        let stolen = match self.stealing {
+            VoiceStealing::Oldest => voices.min_by_key(|(_, voice)| voice.started),
+            // a note that just started is still quiet
+            VoiceStealing::Quietest => voices.min_by(|(_, a), (_, b)| a.in_attack().cmp(&b.in_attack())
+                .then(a.level.total_cmp(&b.level))
+                .then(a.started.cmp(&b.started))),
+        };
+        stolen.map(|(index, _)| index).unwrap_or(0)
+    }
+}
+impl DSPMonoGenerator for VoiceAllocator {
+    fn tick(&mut self, nb_connected: usize) -> Option<Mono> {
+        let amplitude = self.amplitude.real_value();
+        let glide = self.glide.real_value();
+        let detune = self.detune.real_value();
+        if self.enabled.real_value() == 0. {
+            return None;
+        }
+
+        self.multi_index += 1;
+        if self.multi_index >= nb_connected {
+            self.multi_index = 0;
+        }
+        if self.multi_index != 0 {
+            return Some(self.multi_hold);
+        }
+
+        let nb_samples_glide = glide / 1000. * self.sample_rate as f64;
+        let glide = if nb_samples_glide > 0. { (-1. / nb_samples_glide).exp() } else { 0. };
+        self.multi_hold = self.voices.iter_mut()
+            .map(|voice| voice.tick(detune, glide))
+            .sum::<Mono>() * amplitude;
+        Some(self.multi_hold)
+    }
+}
+
+
+
 //==============================================================================
 // Simple singal generators
 //==============================================================================
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub enum NoiseKind {
     White,
 }
@@ -214,6 +436,7 @@ impl DSPMonoGenerator for Noise {
 }
 
 //==============================================================================
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub enum WaveKind {
     Sine,
     Triangle,
@@ -396,3 +619,85 @@ impl DSPMonoGenerator for ADSR {
         Some(self.multi_hold)
     }
 }
+
+
+
+//==============================================================================
+// Tests
+//==============================================================================
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u64 = 44100;
+
+    fn synth(nb_voices: usize, stealing: VoiceStealing, glide: Ms) -> VoiceAllocator {
+        let voice = VoiceDescription {
+            kind: WaveKind::Saw,
+            unison: 3,
+            attack: 10, attack_curve: 1.,
+            peak: 1.,
+            decay: 10, decay_curve: 1.,
+            sustain: 0.5,
+            release: 10, release_curve: 1.,
+            filter: Some((FirstOrderFilterKind::LowPass, 5000.)),
+        };
+        VoiceAllocator::new(nb_voices, &voice, stealing, glide, 20., SAMPLE_RATE)
+    }
+
+    fn run(synth: &mut VoiceAllocator, nb_samples: usize) -> Mono {
+        (0..nb_samples).map(|_| synth.tick(1).unwrap()).fold(0., |peak: Mono, sample| peak.max(sample.abs()))
+    }
+
+    #[test]
+    fn voice_allocation() {
+        let mut oldest = synth(2, VoiceStealing::Oldest, 0.);
+        assert_eq!(run(&mut oldest, 100), 0.);
+        oldest.note_on(60, 1.);
+        oldest.note_on(64, 0.1);
+        // the same note takes its voice again
+        oldest.note_on(64, 0.2);
+        assert!(run(&mut oldest, 2000) > 0.);
+        oldest.note_on(67, 1.);
+        let notes = |synth: &VoiceAllocator| synth.voices.iter().map(|voice| voice.note).collect::<Vec<_>>();
+        assert_eq!(notes(&oldest), [Some(67), Some(64)]);
+
+        let mut quietest = synth(2, VoiceStealing::Quietest, 0.);
+        quietest.note_on(60, 1.);
+        quietest.note_on(64, 0.1);
+        run(&mut quietest, 2000);
+        quietest.note_on(67, 1.);
+        assert_eq!(notes(&quietest), [Some(60), Some(67)]);
+        // the voice of 60 is louder, but 67 has only just started
+        quietest.note_on(72, 1.);
+        assert_eq!(notes(&quietest), [Some(72), Some(67)]);
+        // among voices in attack, the oldest
+        quietest.note_on(76, 1.);
+        assert_eq!(notes(&quietest), [Some(72), Some(76)]);
+
+        // released voices are free once their envelope ends
+        quietest.note_off(72);
+        quietest.note_off(76);
+        run(&mut quietest, 1000);
+        assert!(quietest.voices.iter().all(Voice::is_free));
+        assert_eq!(run(&mut quietest, 100), 0.);
+    }
+
+    #[test]
+    fn glide_and_detune() {
+        let mut synth = synth(2, VoiceStealing::Oldest, 10.);
+        synth.note_on(57, 1.);
+        run(&mut synth, 100);
+        synth.note_on(69, 1.);
+        run(&mut synth, 1);
+        let voice = &synth.voices[1];
+        assert!(voice.frequency > 220. && voice.frequency < 230.);
+        run(&mut synth, SAMPLE_RATE as usize);
+        let voice = &synth.voices[1];
+        assert!((voice.frequency - 440.).abs() < 1e-6);
+        // 20 cents from the lowest oscillator to the highest
+        let frequencies: Vec<Frequency> = voice.oscillators.iter().map(|oscillator| oscillator.frequency.value).collect();
+        assert!((frequencies[2] / frequencies[0] - 2f64.powf(20. / 1200.)).abs() < 1e-9);
+        assert!((frequencies[1] - 440.).abs() < 1e-6);
+    }
+}
@@ -7,6 +7,7 @@ use mockall::{automock, predicate::*};
 //==============================================================================
 pub mod generators;
 pub mod effects;
+pub mod patch;
 
 //==============================================================================
 pub type Frequency = f64;
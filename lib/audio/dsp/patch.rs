@@ -0,0 +1,672 @@
+use super::*;
+use super::generators::*;
+use super::effects::*;
+
+use std::cell::{RefCell, RefMut};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::iter;
+use std::path::Path;
+use std::rc::Rc;
+use std::str::FromStr;
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+//==============================================================================
+// Patch description (what gets written to disk)
+//==============================================================================
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PatchDescription {
+    pub nodes: BTreeMap<String, NodeDescription>,
+    #[serde(default)]
+    pub modulations: Vec<Modulation>,
+    pub output: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum NodeDescription {
+    // Generators
+    Oscillator { kind: WaveKind, frequency: Frequency, amplitude: f64 },
+    Noise { kind: NoiseKind, amplitude: f64 },
+    ADSR {
+        attack: u64, attack_curve: f64,
+        peak: f64,
+        decay: u64, decay_curve: f64,
+        sustain: f64,
+        release: u64, release_curve: f64,
+    },
+    Parallel { modules: Vec<String> },
+    Chain { module: String, effects: Vec<String> },
+    // Effects
+    FxChain { effects: Vec<String> },
+    Amplifier { amplitude: f64 },
+    DownSample { factor: u8 },
+    FirstOrderFilter { kind: FirstOrderFilterKind, cut_off: Frequency },
+    SecondOrderFilter { kind: SecondOrderFilterKind, cut_off: Frequency, curve: f64 },
+    BiquadFilter { a1: f64, a2: f64, b0: f64, b1: f64, b2: f64 },
+    ButterworthFilter { kind: ButterworthFilterKind, cut_off: Frequency, order: u64 },
+    MovingAverage { window_size: usize },
+    Slide { slide_up: f64, slide_down: f64 },
+    Operator(OperatorKind),
+}
+
+/// Connects the output of a generator to a parameter of another node.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Modulation {
+    pub source: String,
+    pub target: String,
+    pub parameter: String,
+}
+
+/// Operators can't be closures in a file, so the usual ones are named here.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum OperatorKind {
+    Abs,
+    Negate,
+    Square,
+    Tanh,
+    Clip { min: f64, max: f64 },
+}
+impl OperatorKind {
+    fn function(self) -> Box<dyn Fn(f64) -> f64> {
+        match self {
+            OperatorKind::Abs => Box::new(|sample: f64| sample.abs()),
+            OperatorKind::Negate => Box::new(|sample: f64| -sample),
+            OperatorKind::Square => Box::new(|sample: f64| sample * sample),
+            OperatorKind::Tanh => Box::new(|sample: f64| sample.tanh()),
+            OperatorKind::Clip { min, max } => Box::new(move |sample: f64| sample.clamp(min, max)),
+        }
+    }
+}
+
+impl NodeDescription {
+    /// Nodes this one ticks
+    fn children(&self) -> Vec<&str> {
+        match self {
+            NodeDescription::Parallel { modules } => modules.iter().map(String::as_str).collect(),
+            NodeDescription::Chain { module, effects } =>
+                iter::once(module).chain(effects).map(String::as_str).collect(),
+            NodeDescription::FxChain { effects } => effects.iter().map(String::as_str).collect(),
+            _ => vec![],
+        }
+    }
+}
+
+impl PatchDescription {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read patch {}", path.display()))?;
+        text.parse()
+            .with_context(|| format!("Failed to parse patch {}", path.display()))
+    }
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        fs::write(path, self.to_string()?)
+            .with_context(|| format!("Failed to save patch {}", path.display()))
+    }
+    pub fn to_string(&self) -> Result<String> {
+        Ok(ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())?)
+    }
+}
+impl FromStr for PatchDescription {
+    type Err = anyhow::Error;
+    fn from_str(text: &str) -> Result<Self> {
+        Ok(ron::from_str(text)?)
+    }
+}
+
+
+
+//==============================================================================
+// Framework glue
+//==============================================================================
+impl DSPBuilder {
+    pub fn build_patch(&self, description: &PatchDescription) -> Result<Patch> {
+        let mut patch = Patch {
+            description: description.clone(),
+            nodes: BTreeMap::new(),
+        };
+        let mut visiting = HashSet::new();
+        let mut used_effects = HashSet::new();
+        for id in description.nodes.keys() {
+            self.build_patch_node(description, id, &mut patch.nodes, &mut visiting,
+                &mut used_effects, "the node list")?;
+        }
+        if !patch.nodes.contains_key(&description.output) {
+            bail!("Unknown node \"{}\" used as the patch output", description.output);
+        }
+
+        // before wiring anything, a cycle of modulators would never be freed
+        check_modulation_cycles(description)?;
+        for (i, modulation) in description.modulations.iter().enumerate() {
+            let source = patch.nodes.get(&modulation.source)
+                .ok_or_else(|| anyhow!("Unknown node \"{}\" used as the source of modulation #{}",
+                    modulation.source, i))?
+                .generator()
+                .ok_or_else(|| anyhow!("Node \"{}\" is an effect and can't modulate a parameter",
+                    modulation.source))?;
+            let target = patch.nodes.get(&modulation.target)
+                .ok_or_else(|| anyhow!("Unknown node \"{}\" used as the target of modulation #{}",
+                    modulation.target, i))?;
+            target.parameter(&modulation.parameter)
+                .ok_or_else(|| anyhow!("Node \"{}\" has no parameter named \"{}\"",
+                    modulation.target, modulation.parameter))?
+                .add_modulator(source);
+        }
+        Ok(patch)
+    }
+
+    fn build_patch_node(&self,
+        description: &PatchDescription,
+        id: &str,
+        built: &mut BTreeMap<String, PatchNode>,
+        visiting: &mut HashSet<String>,
+        used_effects: &mut HashSet<String>,
+        referrer: &str,
+        ) -> Result<()>
+    {
+        if built.contains_key(id) {
+            return Ok(());
+        }
+        let node = description.nodes.get(id)
+            .ok_or_else(|| anyhow!("Unknown node \"{}\" referenced by {}", id, referrer))?;
+        if !visiting.insert(id.to_string()) {
+            bail!("Node \"{}\" is part of a cycle", id);
+        }
+
+        let referrer = format!("node \"{}\"", id);
+        let generator = |module: &str,
+            built: &mut BTreeMap<String, PatchNode>,
+            visiting: &mut HashSet<String>,
+            used_effects: &mut HashSet<String>| -> Result<Rc<RefCell<dyn DSPMonoGenerator>>>
+        {
+            self.build_patch_node(description, module, built, visiting, used_effects, &referrer)?;
+            built[module].generator()
+                .ok_or_else(|| anyhow!("Node \"{}\" is an effect but {} expects a generator",
+                    module, referrer))
+        };
+        let new_node = match node {
+            NodeDescription::Oscillator { kind, frequency, amplitude } =>
+                PatchNode::Oscillator(self.build_oscillator(*kind, *frequency, *amplitude)),
+            NodeDescription::Noise { kind, amplitude } =>
+                PatchNode::Noise(self.build_noise(*kind, *amplitude)),
+            NodeDescription::ADSR { attack, attack_curve, peak, decay, decay_curve,
+                sustain, release, release_curve } =>
+                PatchNode::ADSR(self.build_adsr(*attack, *attack_curve, *peak, *decay,
+                    *decay_curve, *sustain, *release, *release_curve)),
+            NodeDescription::Parallel { modules } => {
+                let parallel = self.build_parallel();
+                for module in modules {
+                    let module = generator(module, built, visiting, used_effects)?;
+                    parallel.borrow_mut().add(module);
+                }
+                PatchNode::Parallel(parallel)
+            },
+            NodeDescription::Chain { module, effects } => {
+                let module = generator(module, built, visiting, used_effects)?;
+                let chain = self.build_chain(module);
+                for effect in effects {
+                    let effect = self.build_patch_effect(description, effect, built,
+                        visiting, used_effects, &referrer)?;
+                    chain.borrow_mut().fx_chain.append(effect);
+                }
+                PatchNode::Chain(chain)
+            },
+            NodeDescription::FxChain { effects } => {
+                let fx_chain = self.build_fx_chain();
+                for effect in effects {
+                    let effect = self.build_patch_effect(description, effect, built,
+                        visiting, used_effects, &referrer)?;
+                    fx_chain.borrow_mut().append(effect);
+                }
+                PatchNode::FxChain(fx_chain)
+            },
+            NodeDescription::Amplifier { amplitude } =>
+                PatchNode::Amplifier(self.build_amplifier(*amplitude)),
+            NodeDescription::DownSample { factor } => {
+                if *factor == 0 {
+                    bail!("Node \"{}\" has a down sampling factor of 0", id);
+                }
+                PatchNode::DownSample(self.build_down_sample(*factor))
+            },
+            NodeDescription::FirstOrderFilter { kind, cut_off } =>
+                PatchNode::FirstOrderFilter(self.build_first_order_filter(*kind, *cut_off)),
+            NodeDescription::SecondOrderFilter { kind, cut_off, curve } =>
+                PatchNode::SecondOrderFilter(self.build_second_order_filter(*kind, *cut_off, *curve)),
+            NodeDescription::BiquadFilter { a1, a2, b0, b1, b2 } =>
+                PatchNode::BiquadFilter(self.build_biquad_filter(*a1, *a2, *b0, *b1, *b2)),
+            NodeDescription::ButterworthFilter { kind, cut_off, order } =>
+                PatchNode::ButterworthFilter(self.build_butterworth_filter(*kind, *cut_off, *order)),
+            NodeDescription::MovingAverage { window_size } => {
+                if *window_size == 0 {
+                    bail!("Node \"{}\" has a moving average window of 0 samples", id);
+                }
+                PatchNode::MovingAverage(self.build_moving_average(*window_size))
+            },
+            NodeDescription::Slide { slide_up, slide_down } =>
+                PatchNode::Slide(self.build_slide(*slide_up, *slide_down)),
+            NodeDescription::Operator(kind) =>
+                PatchNode::Operator(self.build_operator(kind.function())),
+        };
+        visiting.remove(id);
+        built.insert(id.to_string(), new_node);
+        Ok(())
+    }
+
+    fn build_patch_effect(&self,
+        description: &PatchDescription,
+        id: &str,
+        built: &mut BTreeMap<String, PatchNode>,
+        visiting: &mut HashSet<String>,
+        used_effects: &mut HashSet<String>,
+        referrer: &str,
+        ) -> Result<Rc<RefCell<dyn DSPMonoEffect>>>
+    {
+        self.build_patch_node(description, id, built, visiting, used_effects, referrer)?;
+        let effect = built[id].effect()
+            .ok_or_else(|| anyhow!("Node \"{}\" is a generator but {} expects an effect",
+                id, referrer))?;
+        // Effects have no multi-connection logic, sharing one would tick it twice
+        if !used_effects.insert(id.to_string()) {
+            bail!("Effect \"{}\" is used more than once", id);
+        }
+        Ok(effect)
+    }
+}
+/// A node stays borrowed while it ticks its children and the modulators of
+/// its parameters, reaching it again through either would panic.
+fn check_modulation_cycles(description: &PatchDescription) -> Result<()> {
+    let mut edges: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for (id, node) in &description.nodes {
+        edges.entry(id).or_default().extend(node.children());
+    }
+    for modulation in &description.modulations {
+        if modulation.source == modulation.target {
+            bail!("Node \"{}\" can't modulate itself", modulation.source);
+        }
+        edges.entry(&modulation.target).or_default().push(&modulation.source);
+    }
+
+    fn visit<'a>(
+        id: &'a str,
+        edges: &BTreeMap<&'a str, Vec<&'a str>>,
+        path: &mut Vec<&'a str>,
+        done: &mut HashSet<&'a str>,
+        ) -> Result<()>
+    {
+        if let Some(start) = path.iter().position(|visited| *visited == id) {
+            let cycle: Vec<String> = path[start..].iter().chain([&id])
+                .map(|id| format!("\"{}\"", id))
+                .collect();
+            bail!("Modulations make a cycle through nodes {}", cycle.join(" -> "));
+        }
+        if done.contains(id) {
+            return Ok(());
+        }
+        path.push(id);
+        for next in edges.get(id).into_iter().flatten() {
+            visit(next, edges, path, done)?;
+        }
+        path.pop();
+        done.insert(id);
+        Ok(())
+    }
+    let mut done = HashSet::new();
+    for id in edges.keys() {
+        visit(id, &edges, &mut vec![], &mut done)?;
+    }
+    Ok(())
+}
+
+
+
+//==============================================================================
+// Runnable patch
+//==============================================================================
+/// Operators of a patch, named in the file rather than written as closures
+pub type PatchOperator = Operator<Box<dyn Fn(f64) -> f64>>;
+
+pub enum PatchNode {
+    Oscillator(Rc<RefCell<Oscillator>>),
+    Noise(Rc<RefCell<Noise>>),
+    ADSR(Rc<RefCell<ADSR>>),
+    Parallel(Rc<RefCell<Parallel>>),
+    Chain(Rc<RefCell<Chain>>),
+    FxChain(Rc<RefCell<FxChain>>),
+    Amplifier(Rc<RefCell<Amplifier>>),
+    DownSample(Rc<RefCell<DownSample>>),
+    FirstOrderFilter(Rc<RefCell<FirstOrderFilter>>),
+    SecondOrderFilter(Rc<RefCell<SecondOrderFilter>>),
+    BiquadFilter(Rc<RefCell<BiquadFilter>>),
+    ButterworthFilter(Rc<RefCell<ButterworthFilter>>),
+    MovingAverage(Rc<RefCell<MovingAverage>>),
+    Slide(Rc<RefCell<Slide>>),
+    Operator(Rc<RefCell<PatchOperator>>),
+}
+impl PatchNode {
+    pub fn generator(&self) -> Option<Rc<RefCell<dyn DSPMonoGenerator>>> {
+        match self {
+            PatchNode::Oscillator(node) => Some(node.clone()),
+            PatchNode::Noise(node) => Some(node.clone()),
+            PatchNode::ADSR(node) => Some(node.clone()),
+            PatchNode::Parallel(node) => Some(node.clone()),
+            PatchNode::Chain(node) => Some(node.clone()),
+            _ => None,
+        }
+    }
+    pub fn effect(&self) -> Option<Rc<RefCell<dyn DSPMonoEffect>>> {
+        match self {
+            PatchNode::FxChain(node) => Some(node.clone()),
+            PatchNode::Amplifier(node) => Some(node.clone()),
+            PatchNode::DownSample(node) => Some(node.clone()),
+            PatchNode::FirstOrderFilter(node) => Some(node.clone()),
+            PatchNode::SecondOrderFilter(node) => Some(node.clone()),
+            PatchNode::BiquadFilter(node) => Some(node.clone()),
+            PatchNode::ButterworthFilter(node) => Some(node.clone()),
+            PatchNode::MovingAverage(node) => Some(node.clone()),
+            PatchNode::Slide(node) => Some(node.clone()),
+            PatchNode::Operator(node) => Some(node.clone()),
+            _ => None,
+        }
+    }
+    pub fn parameter(&self, name: &str) -> Option<RefMut<'_, Parameter>> {
+        fn map<'a, T>(
+            node: &'a Rc<RefCell<T>>,
+            f: impl FnOnce(&mut T) -> Option<&mut Parameter>,
+            ) -> Option<RefMut<'a, Parameter>>
+        {
+            RefMut::filter_map(node.borrow_mut(), f).ok()
+        }
+        match self {
+            PatchNode::Oscillator(node) => map(node, |n| match name {
+                "frequency" => Some(&mut n.frequency),
+                "amplitude" => Some(&mut n.amplitude),
+                "enabled" => Some(&mut n.enabled),
+                _ => None,
+            }),
+            PatchNode::Noise(node) => map(node, |n| match name {
+                "amplitude" => Some(&mut n.amplitude),
+                "enabled" => Some(&mut n.enabled),
+                _ => None,
+            }),
+            PatchNode::ADSR(node) => map(node, |n| match name {
+                "enabled" => Some(&mut n.enabled),
+                _ => None,
+            }),
+            PatchNode::Parallel(node) => map(node, |n| match name {
+                "enabled" => Some(&mut n.enabled),
+                _ => None,
+            }),
+            PatchNode::Chain(node) => map(node, |n| match name {
+                "enabled" => Some(&mut n.enabled),
+                _ => None,
+            }),
+            PatchNode::FxChain(node) => map(node, |n| match name {
+                "enabled" => Some(&mut n.enabled),
+                _ => None,
+            }),
+            PatchNode::Amplifier(node) => map(node, |n| match name {
+                "amplitude" => Some(&mut n.amplitude),
+                "enabled" => Some(&mut n.enabled),
+                _ => None,
+            }),
+            PatchNode::DownSample(node) => map(node, |n| match name {
+                "factor" => Some(&mut n.factor),
+                "enabled" => Some(&mut n.enabled),
+                _ => None,
+            }),
+            PatchNode::FirstOrderFilter(node) => map(node, |n| match name {
+                "cut_off" => Some(&mut n.cut_off),
+                "enabled" => Some(&mut n.enabled),
+                _ => None,
+            }),
+            PatchNode::SecondOrderFilter(node) => map(node, |n| match name {
+                "cut_off" => Some(&mut n.cut_off),
+                "curve" => Some(&mut n.curve),
+                "enabled" => Some(&mut n.enabled),
+                _ => None,
+            }),
+            PatchNode::BiquadFilter(node) => map(node, |n| match name {
+                "a1" => Some(&mut n.a1),
+                "a2" => Some(&mut n.a2),
+                "b0" => Some(&mut n.b0),
+                "b1" => Some(&mut n.b1),
+                "b2" => Some(&mut n.b2),
+                "enabled" => Some(&mut n.enabled),
+                _ => None,
+            }),
+            PatchNode::ButterworthFilter(node) => map(node, |n| match name {
+                "cut_off" => Some(&mut n.cut_off),
+                "order" => Some(&mut n.order),
+                "enabled" => Some(&mut n.enabled),
+                _ => None,
+            }),
+            PatchNode::MovingAverage(node) => map(node, |n| match name {
+                "enabled" => Some(&mut n.enabled),
+                _ => None,
+            }),
+            PatchNode::Slide(node) => map(node, |n| match name {
+                "slide_up" => Some(&mut n.slide_up),
+                "slide_down" => Some(&mut n.slide_down),
+                "enabled" => Some(&mut n.enabled),
+                _ => None,
+            }),
+            PatchNode::Operator(node) => map(node, |n| match name {
+                "enabled" => Some(&mut n.enabled),
+                _ => None,
+            }),
+        }
+    }
+}
+
+pub struct Patch {
+    description: PatchDescription,
+    nodes: BTreeMap<String, PatchNode>,
+}
+impl Patch {
+    pub fn node(&self, id: &str) -> Option<&PatchNode> {
+        self.nodes.get(id)
+    }
+    pub fn output(&self) -> &PatchNode {
+        &self.nodes[&self.description.output]
+    }
+    /// Saves the patch with the current (unmodulated) parameter values
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.description().save(path)
+    }
+    /// Description of the patch with the current (unmodulated) parameter values.
+    pub fn description(&self) -> PatchDescription {
+        let mut description = self.description.clone();
+        for (id, node) in description.nodes.iter_mut() {
+            let value = |name: &str| self.nodes[id].parameter(name).unwrap().value;
+            match node {
+                NodeDescription::Oscillator { frequency, amplitude, .. } => {
+                    *frequency = value("frequency");
+                    *amplitude = value("amplitude");
+                },
+                NodeDescription::Noise { amplitude, .. } => *amplitude = value("amplitude"),
+                NodeDescription::Amplifier { amplitude } => *amplitude = value("amplitude"),
+                NodeDescription::DownSample { factor } => *factor = value("factor") as u8,
+                NodeDescription::FirstOrderFilter { cut_off, .. } => *cut_off = value("cut_off"),
+                NodeDescription::SecondOrderFilter { cut_off, curve, .. } => {
+                    *cut_off = value("cut_off");
+                    *curve = value("curve");
+                },
+                NodeDescription::BiquadFilter { a1, a2, b0, b1, b2 } => {
+                    *a1 = value("a1");
+                    *a2 = value("a2");
+                    *b0 = value("b0");
+                    *b1 = value("b1");
+                    *b2 = value("b2");
+                },
+                NodeDescription::ButterworthFilter { cut_off, order, .. } => {
+                    *cut_off = value("cut_off");
+                    *order = value("order") as u64;
+                },
+                NodeDescription::Slide { slide_up, slide_down } => {
+                    *slide_up = value("slide_up");
+                    *slide_down = value("slide_down");
+                },
+                _ => (),
+            }
+        }
+        description
+    }
+}
+
+
+
+//==============================================================================
+// Tests
+//==============================================================================
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u64 = 44100;
+
+    fn build(text: &str) -> Result<Patch> {
+        let description: PatchDescription = text.parse()?;
+        DSPBuilder::new(SAMPLE_RATE).build_patch(&description)
+    }
+
+    #[test]
+    fn transient_patch_builds() {
+        let patch = build(include_str!("../patches/transient.ron")).unwrap();
+        let effect = patch.output().effect().unwrap();
+        let mut actual = 0.;
+        for _ in 0..1000 {
+            actual = effect.borrow_mut().tick(1.);
+        }
+        assert!(actual > 0. && actual <= 1.);
+    }
+
+    #[test]
+    fn fx_chain_order() {
+        let patch = build(r#"(
+            nodes: {
+                "clip": Operator(Clip(min: 0., max: 1.)),
+                "double": Amplifier(amplitude: 2.),
+                "chain": FxChain(effects: ["double", "clip"]),
+            },
+            output: "chain",
+        )"#).unwrap();
+        let effect = patch.output().effect().unwrap();
+        assert_eq!(effect.borrow_mut().tick(0.25), 0.5);
+        assert_eq!(effect.borrow_mut().tick(3.), 1.);
+    }
+
+    #[test]
+    fn modulation_connects_parameter() {
+        let patch = build(r#"(
+            nodes: {
+                "carrier": Oscillator(kind: Square, frequency: 100., amplitude: 0.),
+                "offset": Oscillator(kind: Square, frequency: 100., amplitude: 1.),
+            },
+            modulations: [(source: "offset", target: "carrier", parameter: "amplitude")],
+            output: "carrier",
+        )"#).unwrap();
+        let generator = patch.output().generator().unwrap();
+        assert_eq!(generator.borrow_mut().tick(1), Some(1.));
+    }
+
+    #[test]
+    fn unknown_node_type() {
+        let error = build(r#"(
+            nodes: { "a": Reverb(size: 1.) },
+            output: "a",
+        )"#).err().unwrap();
+        assert!(format!("{:#}", error).contains("Reverb"));
+    }
+
+    #[test]
+    fn unknown_reference() {
+        let error = build(r#"(
+            nodes: { "chain": FxChain(effects: ["missing"]) },
+            output: "chain",
+        )"#).err().unwrap();
+        assert_eq!(error.to_string(), "Unknown node \"missing\" referenced by node \"chain\"");
+    }
+
+    #[test]
+    fn unknown_parameter() {
+        let error = build(r#"(
+            nodes: {
+                "lfo": Oscillator(kind: Sine, frequency: 1., amplitude: 1.),
+                "gain": Amplifier(amplitude: 1.),
+            },
+            modulations: [(source: "lfo", target: "gain", parameter: "cut_off")],
+            output: "gain",
+        )"#).err().unwrap();
+        assert_eq!(error.to_string(), "Node \"gain\" has no parameter named \"cut_off\"");
+    }
+
+    #[test]
+    fn effect_used_as_generator() {
+        let error = build(r#"(
+            nodes: {
+                "gain": Amplifier(amplitude: 1.),
+                "mix": Parallel(modules: ["gain"]),
+            },
+            output: "mix",
+        )"#).err().unwrap();
+        assert_eq!(error.to_string(),
+            "Node \"gain\" is an effect but node \"mix\" expects a generator");
+    }
+
+    #[test]
+    fn cycle_detected() {
+        let error = build(r#"(
+            nodes: {
+                "a": Parallel(modules: ["b"]),
+                "b": Parallel(modules: ["a"]),
+            },
+            output: "a",
+        )"#).err().unwrap();
+        assert_eq!(error.to_string(), "Node \"a\" is part of a cycle");
+    }
+
+    #[test]
+    fn modulation_cycle_detected() {
+        let error = build(r#"(
+            nodes: {
+                "a": Oscillator(kind: Sine, frequency: 1., amplitude: 1.),
+                "b": Oscillator(kind: Sine, frequency: 1., amplitude: 1.),
+            },
+            modulations: [
+                (source: "a", target: "b", parameter: "frequency"),
+                (source: "b", target: "a", parameter: "frequency"),
+            ],
+            output: "a",
+        )"#).err().unwrap();
+        assert_eq!(error.to_string(), "Modulations make a cycle through nodes \"a\" -> \"b\" -> \"a\"");
+
+        // a container modulating its own child
+        let error = build(r#"(
+            nodes: {
+                "osc": Oscillator(kind: Sine, frequency: 1., amplitude: 1.),
+                "mix": Parallel(modules: ["osc"]),
+            },
+            modulations: [(source: "mix", target: "osc", parameter: "amplitude")],
+            output: "mix",
+        )"#).err().unwrap();
+        assert_eq!(error.to_string(), "Modulations make a cycle through nodes \"mix\" -> \"osc\" -> \"mix\"");
+    }
+
+    #[test]
+    fn save_round_trip() {
+        let patch = build(include_str!("../patches/transient.ron")).unwrap();
+        if let Some(PatchNode::Amplifier(amplifier)) = patch.node("gain") {
+            amplifier.borrow_mut().amplitude.value = 3.;
+        } else {
+            panic!("gain should be an amplifier");
+        }
+        let path = std::env::temp_dir().join(format!("wallfuck-patch-{}.ron", std::process::id()));
+        patch.save(&path).unwrap();
+        let reloaded = build(&fs::read_to_string(&path).unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(reloaded.node("gain").unwrap().parameter("amplitude").unwrap().value, 3.);
+        assert_eq!(reloaded.description().nodes.len(), patch.description().nodes.len());
+    }
+}
@@ -0,0 +1,249 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use anyhow::{bail, Context, Result};
+use jack::PortSpec;
+use regex::Regex;
+
+use super::dsp::DSPBuilder;
+use super::source::AudioSource;
+use super::{default_patch, Message, Recording, ThreadCapture};
+
+/// Mono samples waiting for the patch, about a second at 48000Hz
+const RING_SIZE: usize = 4 * 65536;
+/// How often the ring is emptied
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Ports grouped by client, in the order they come in. A mono port playing
+/// to both speakers is only kept once.
+fn by_client(ports: Vec<String>) -> Vec<Vec<String>> {
+    let mut clients: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for port in ports {
+        let client = clients.entry(port.split(':').next().unwrap_or_default().to_string()).or_default();
+        if !client.contains(&port) {
+            client.push(port);
+        }
+    }
+    clients.into_values().collect()
+}
+
+/// The output ports of the graph our inputs get connected to, per client
+fn source_ports(client: &jack::Client, audio_source: &AudioSource) -> Result<Vec<Vec<String>>> {
+    let audio = jack::AudioIn::default().jack_port_type();
+    let outputs = |pattern: Option<&str>| client.ports(pattern, Some(audio), jack::PortFlags::IS_OUTPUT);
+    // what plays to the input ports of the sinks, as there are no monitors
+    let playing_to = |sinks: Vec<String>| -> Vec<String> {
+        sinks.iter()
+            .filter_map(|name| client.port_by_name(name))
+            .flat_map(|port| port.get_connections())
+            .collect()
+    };
+    let ports = match audio_source {
+        AudioSource::DefaultSink => playing_to(client.ports(None, Some(audio),
+            jack::PortFlags::IS_INPUT | jack::PortFlags::IS_PHYSICAL)),
+        AudioSource::Sink(pattern) => playing_to(client.ports(Some(pattern), Some(audio), jack::PortFlags::IS_INPUT)),
+        AudioSource::Source(pattern) => outputs(Some(pattern)),
+        AudioSource::Application(pattern) => {
+            let pattern = Regex::new(pattern)?;
+            outputs(None).into_iter()
+                .filter(|port| pattern.is_match(port.split(':').next().unwrap_or_default()))
+                .collect()
+        },
+    };
+    Ok(by_client(ports))
+}
+
+/// Our input each port is connected to, a mono port goes to both. The
+/// channels of a client alternate without mixing with the other clients.
+fn connections(clients: &[Vec<String>], inputs: &[String; 2]) -> Vec<(String, String)> {
+    clients.iter().flat_map(|ports| match ports.as_slice() {
+        [port] => inputs.iter().map(|input| (port.clone(), input.clone())).collect(),
+        _ => ports.iter().zip(inputs.iter().cycle()).map(|(port, input)| (port.clone(), input.clone())).collect::<Vec<_>>(),
+    }).collect()
+}
+
+/// Connects the ports `audio_source` selects to `inputs`, keeping the
+/// connections already made. Returns the newly connected ports.
+fn connect(client: &jack::Client, audio_source: &AudioSource, inputs: &[String; 2]) -> Result<Vec<String>> {
+    let mut connected = Vec::new();
+    for (port, input) in connections(&source_ports(client, audio_source)?, inputs) {
+        match client.connect_ports_by_name(&port, &input) {
+            Ok(()) if !connected.contains(&port) => connected.push(port),
+            Ok(()) | Err(jack::Error::PortAlreadyConnected(..)) => {},
+            Err(error) => return Err(error).with_context(|| format!("Failed to connect {} to {}", port, input)),
+        }
+    }
+    Ok(connected)
+}
+
+/// Flags the ports registered and connected, for what starts playing after
+/// us to be connected too
+struct GraphChanges(Arc<AtomicBool>);
+
+impl jack::NotificationHandler for GraphChanges {
+    fn port_registration(&mut self, _: &jack::Client, _: jack::PortId, is_registered: bool) {
+        if is_registered {
+            self.0.store(true, Ordering::Relaxed);
+        }
+    }
+
+    fn ports_connected(&mut self, _: &jack::Client, _: jack::PortId, _: jack::PortId, are_connected: bool) {
+        if are_connected {
+            self.0.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Runs the patch on what the process callback left in the ring until `Quit`,
+/// calling `reconnect` when the graph changed
+fn run(
+    mut ring: jack::RingBufferReader,
+    builder: DSPBuilder,
+    receiver: mpsc::Receiver<Message>,
+    recording: Arc<Mutex<Recording>>,
+    graph_changed: &AtomicBool,
+    mut reconnect: impl FnMut(),
+) {
+    let patch = RefCell::new(default_patch(&builder));
+    let mut bytes = vec![0; RING_SIZE];
+    let mut paused = false;
+    loop {
+        match receiver.recv_timeout(POLL_INTERVAL) {
+            Ok(message) => match message.apply(&builder, &patch) {
+                Some(Message::Paused(pause)) => paused = pause,
+                Some(Message::Quit) => return,
+                _ => {},
+            },
+            Err(mpsc::RecvTimeoutError::Timeout) => {},
+            Err(mpsc::RecvTimeoutError::Disconnected) => return,
+        }
+        if graph_changed.swap(false, Ordering::Relaxed) {
+            reconnect();
+        }
+        // the callback keeps running while paused, its samples are dropped
+        let length = ring.read_buffer(&mut bytes) / 4 * 4;
+        if !paused {
+            let samples = bytes[..length].chunks_exact(4)
+                .map(|sample| f32::from_ne_bytes([sample[0], sample[1], sample[2], sample[3]]) as f64);
+            // at most a poll interval late, what the ports add isn't known here
+            recording.lock().unwrap().push(&patch.borrow(), samples, Duration::ZERO, Duration::ZERO);
+        }
+    }
+}
+
+/// Registers a client with two input ports, connected to the ports
+/// `audio_source` selects, now and when they appear. The server isn't
+/// started when it isn't running.
+pub fn capture(audio_source: &AudioSource) -> Result<ThreadCapture> {
+    let (client, _) = jack::Client::new("wallfuck", jack::ClientOptions::NO_START_SERVER)
+        .context("Failed to connect to the JACK server")?;
+    let left = client.register_port("in_left", jack::AudioIn::default())?;
+    let right = client.register_port("in_right", jack::AudioIn::default())?;
+    let inputs = [left.name()?, right.name()?];
+    let rate = client.sample_rate();
+
+    let (reader, mut writer) = jack::RingBuffer::new(RING_SIZE)?.into_reader_writer();
+    // no allocations or locks on the realtime thread, the samples go through the ring
+    let process = jack::ClosureProcessHandler::new(move |_: &jack::Client, scope: &jack::ProcessScope| {
+        for (left, right) in left.as_slice(scope).iter().zip(right.as_slice(scope)) {
+            // whole samples only, the rest is lost when the ring is full
+            if writer.space() < 4 {
+                break;
+            }
+            writer.write_buffer(&((left + right) / 2.).to_ne_bytes());
+        }
+        jack::Control::Continue
+    });
+    let graph_changed = Arc::new(AtomicBool::new(false));
+    let active = client.activate_async(GraphChanges(graph_changed.clone()), process)
+        .context("Failed to activate the JACK client")?;
+    let ports = connect(active.as_client(), audio_source, &inputs)?;
+    match audio_source {
+        // sinks are recorded silent until something plays to them
+        AudioSource::DefaultSink | AudioSource::Sink(_) if ports.is_empty() =>
+            log::info!("Nothing plays to {:?} yet, recording at {}Hz with JACK", audio_source, rate),
+        _ if ports.is_empty() => bail!("No JACK port matches {:?}", audio_source),
+        _ => log::info!("Recording {} at {}Hz with JACK", ports.join(", "), rate),
+    }
+
+    let recording = Arc::new(Mutex::new(Recording::new(rate as u32)));
+    let (sender, receiver) = mpsc::channel();
+    let recording_ref = recording.clone();
+    let audio_source = audio_source.clone();
+    let thread = thread::Builder::new().name("jack".to_string()).spawn(move || {
+        // connecting isn't allowed from the notification callbacks
+        let reconnect = || match connect(active.as_client(), &audio_source, &inputs) {
+            Ok(ports) if !ports.is_empty() => log::info!("Recording {} too", ports.join(", ")),
+            Ok(_) => {},
+            Err(error) => log::warn!("{:#}", error),
+        };
+        run(reader, DSPBuilder::new(rate as u64), receiver, recording_ref, &graph_changed, reconnect);
+        // deactivates the client
+        drop(active);
+    })?;
+    Ok(ThreadCapture {
+        name: "JACK",
+        send: Box::new(move |message| sender.send(message).is_ok()),
+        recording,
+        thread: Some(thread),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::Capture;
+    use std::time::Instant;
+
+    #[test]
+    fn port_connections() {
+        let inputs = ["wallfuck:in_left".to_string(), "wallfuck:in_right".to_string()];
+        let names = |names: &[&str]| names.iter().map(|name| name.to_string()).collect::<Vec<_>>();
+        let pairs = |pairs: &[(&str, &str)]| pairs.iter().map(|(a, b)| (a.to_string(), b.to_string())).collect::<Vec<_>>();
+        assert_eq!(connections(&[names(&["mpv:out_0"])], &inputs),
+            pairs(&[("mpv:out_0", "wallfuck:in_left"), ("mpv:out_0", "wallfuck:in_right")]));
+        assert_eq!(connections(&[names(&["mpv:out_0", "mpv:out_1", "mpv:out_2"])], &inputs),
+            pairs(&[("mpv:out_0", "wallfuck:in_left"), ("mpv:out_1", "wallfuck:in_right"), ("mpv:out_2", "wallfuck:in_left")]));
+
+        // a mono player doesn't shift the channels of the other
+        let clients = by_client(names(&["mpv:out_0", "beep:out", "mpv:out_1", "beep:out"]));
+        assert_eq!(clients, [names(&["beep:out"]), names(&["mpv:out_0", "mpv:out_1"])]);
+        assert_eq!(connections(&clients, &inputs), pairs(&[("beep:out", "wallfuck:in_left"), ("beep:out", "wallfuck:in_right"),
+            ("mpv:out_0", "wallfuck:in_left"), ("mpv:out_1", "wallfuck:in_right")]));
+    }
+
+    #[test]
+    #[ignore = "needs a JACK server, like jackd -d dummy"]
+    fn dummy_server() {
+        let (player, _) = jack::Client::new("wallfuck-test", jack::ClientOptions::NO_START_SERVER).unwrap();
+        let mut output = player.register_port("out", jack::AudioOut::default()).unwrap();
+        let process = jack::ClosureProcessHandler::new(move |_: &jack::Client, scope: &jack::ProcessScope| {
+            output.as_mut_slice(scope).fill(0.5);
+            jack::Control::Continue
+        });
+        let player = player.activate_async((), process).unwrap();
+        // silence until the ports are connected
+        let reaches_half = |capture: &ThreadCapture| {
+            let start = Instant::now();
+            while (capture.features(None).level - 0.5).abs() > 1e-6 && start.elapsed() < Duration::from_secs(2) {
+                thread::sleep(Duration::from_millis(10));
+            }
+            (capture.features(None).level - 0.5).abs() < 1e-6
+        };
+
+        assert!(capture(&AudioSource::Application("^nobody$".to_string())).is_err());
+        let mut application = capture(&AudioSource::Application("^wallfuck-test$".to_string())).unwrap();
+        assert!(application.set_parameter("nowhere", "gain", 1.).is_err());
+        assert!(reaches_half(&application));
+
+        // the player starts playing to the speakers after the capture
+        let speakers = capture(&AudioSource::DefaultSink).unwrap();
+        let playback = player.as_client().ports(None, None, jack::PortFlags::IS_INPUT | jack::PortFlags::IS_PHYSICAL);
+        player.as_client().connect_ports_by_name("wallfuck-test:out", &playback[0]).unwrap();
+        assert!(reaches_half(&speakers));
+        drop(player);
+    }
+}
@@ -0,0 +1,209 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use anyhow::{anyhow, bail, Context, Result};
+use pw::spa;
+use pw::spa::param::audio::{AudioFormat, AudioInfoRaw};
+use pw::spa::pod::Pod;
+
+use super::dsp::DSPBuilder;
+use super::source::AudioSource;
+use super::{default_patch, Message, Recording, ThreadCapture, SAMPLE_RATE};
+
+const CHANNELS: u32 = 2;
+
+/// Properties of the stream choosing what it records. Without a target, the
+/// session manager links it to the default sink and moves it when it changes.
+fn target_properties(audio_source: &AudioSource) -> Result<Vec<(&'static str, String)>> {
+    let capture_sink = ("stream.capture.sink", "true".to_string());
+    Ok(match audio_source {
+        AudioSource::DefaultSink => vec![capture_sink],
+        AudioSource::Sink(name) => vec![capture_sink, ("target.object", name.clone())],
+        AudioSource::Source(name) => vec![("target.object", name.clone())],
+        AudioSource::Application(_) => bail!("Applications are only recorded alone with the Pulse backend"),
+    })
+}
+
+/// EnumFormat parameter asking for interleaved float32 at the rate of the patches
+fn format() -> Result<Vec<u8>> {
+    let mut audio_info = AudioInfoRaw::new();
+    audio_info.set_format(AudioFormat::F32LE);
+    audio_info.set_rate(SAMPLE_RATE);
+    audio_info.set_channels(CHANNELS);
+    let object = spa::pod::Object {
+        type_: spa::utils::SpaTypes::ObjectParamFormat.as_raw(),
+        id: spa::param::ParamType::EnumFormat.as_raw(),
+        properties: audio_info.into(),
+    };
+    let (cursor, _) = spa::pod::serialize::PodSerializer::serialize(
+        std::io::Cursor::new(Vec::new()),
+        &spa::pod::Value::Object(object),
+    ).map_err(|error| anyhow!("Failed to serialize the audio format: {:?}", error))?;
+    Ok(cursor.into_inner())
+}
+
+/// Mixes down interleaved float32 frames
+fn mono_samples(bytes: &[u8]) -> impl Iterator<Item = f64> + '_ {
+    bytes.chunks_exact(4 * CHANNELS as usize).map(|frame| {
+        let sample = |i: usize| f32::from_le_bytes([frame[i], frame[i + 1], frame[i + 2], frame[i + 3]]) as f64;
+        (sample(0) + sample(4)) / 2.
+    })
+}
+
+/// Latency and sink latency of the stream from its timing. The delay from the
+/// device is negative when recording the monitor of a sink, the samples are
+/// heard that long after their capture.
+fn latencies(stream: &pw::stream::StreamRef) -> (Duration, Duration) {
+    let mut time: pw::sys::pw_time = unsafe { std::mem::zeroed() };
+    let size = std::mem::size_of::<pw::sys::pw_time>();
+    if unsafe { pw::sys::pw_stream_get_time_n(stream.as_raw_ptr(), &mut time, size) } < 0 || time.rate.denom == 0 {
+        return (Duration::ZERO, Duration::ZERO);
+    }
+    let delay = time.delay as f64 * time.rate.num as f64 / time.rate.denom as f64;
+    // frames held back by the resampler
+    let buffered = time.buffered as f64 / SAMPLE_RATE as f64;
+    (Duration::from_secs_f64(delay.max(0.) + buffered), Duration::from_secs_f64((-delay).max(0.)))
+}
+
+/// Sets up the stream, reports it on `started` and runs the loop until `Quit`
+fn run(
+    properties: Vec<(&'static str, String)>,
+    receiver: pw::channel::Receiver<Message>,
+    recording: Arc<Mutex<Recording>>,
+    started: &mpsc::Sender<Result<()>>,
+) -> Result<()> {
+    pw::init();
+    let mainloop = pw::main_loop::MainLoop::new(None).context("Failed to create the PipeWire loop")?;
+    let context = pw::context::Context::new(&mainloop).context("Failed to create the PipeWire context")?;
+    let core = context.connect(None).context("Failed to connect to PipeWire")?;
+
+    let mut stream_properties = pw::properties::properties! {
+        *pw::keys::MEDIA_TYPE => "Audio",
+        *pw::keys::MEDIA_CATEGORY => "Capture",
+        *pw::keys::MEDIA_ROLE => "Music",
+        *pw::keys::NODE_NAME => "wallfuck",
+    };
+    for (key, value) in properties {
+        stream_properties.insert(key, value);
+    }
+    let stream = Rc::new(pw::stream::Stream::new(&core, "Audio Spy", stream_properties)
+        .context("Failed to create the PipeWire stream")?);
+
+    let builder = DSPBuilder::new(SAMPLE_RATE as u64);
+    let patch = Rc::new(RefCell::new(default_patch(&builder)));
+    let patch_ref = patch.clone();
+    let _listener = stream.add_local_listener_with_user_data(())
+        .state_changed(|_, _, _, state| {
+            if let pw::stream::StreamState::Error(error) = state {
+                log::error!("PipeWire stream: {}", error);
+            }
+        })
+        .process(move |stream, _| {
+            let Some(mut buffer) = stream.dequeue_buffer() else { return };
+            let Some(data) = buffer.datas_mut().first_mut() else { return };
+            let chunk = data.chunk();
+            let (offset, size) = (chunk.offset() as usize, chunk.size() as usize);
+            let Some(bytes) = data.data() else { return };
+            let bytes = bytes.get(offset..offset + size).unwrap_or_default();
+            let (latency, sink_latency) = latencies(stream);
+            recording.lock().unwrap().push(&patch_ref.borrow(), mono_samples(bytes), latency, sink_latency);
+        })
+        .register()
+        .context("Failed to listen to the PipeWire stream")?;
+
+    let format = format()?;
+    let mut params = [Pod::from_bytes(&format).context("Invalid audio format")?];
+    stream.connect(
+        spa::utils::Direction::Input,
+        None,
+        // without RT_PROCESS, process runs on this loop like the messages
+        // changing the patch, and may lock and allocate
+        pw::stream::StreamFlags::AUTOCONNECT | pw::stream::StreamFlags::MAP_BUFFERS,
+        &mut params,
+    ).context("Failed to connect the PipeWire stream")?;
+
+    let (mainloop_ref, stream_ref) = (mainloop.clone(), stream.clone());
+    let _receiver = receiver.attach(mainloop.loop_(), move |message| match message.apply(&builder, &patch) {
+        Some(Message::Paused(paused)) => {
+            if let Err(error) = stream_ref.set_active(!paused) {
+                log::error!("Failed to pause the PipeWire stream: {}", error);
+            }
+        },
+        Some(Message::Quit) => mainloop_ref.quit(),
+        _ => {},
+    });
+
+    let _ = started.send(Ok(()));
+    mainloop.run();
+    Ok(())
+}
+
+/// Records from PipeWire without going through pipewire-pulse, in float32
+/// on a thread of its own.
+pub fn capture(audio_source: &AudioSource) -> Result<ThreadCapture> {
+    let properties = target_properties(audio_source)?;
+    let recording = Arc::new(Mutex::new(Recording::new(SAMPLE_RATE)));
+    let (sender, receiver) = pw::channel::channel();
+    let (started, start) = mpsc::channel();
+    let recording_ref = recording.clone();
+    let thread = thread::Builder::new().name("pipewire".to_string()).spawn(move || {
+        if let Err(error) = run(properties, receiver, recording_ref, &started) {
+            let _ = started.send(Err(error));
+        }
+    })?;
+    start.recv().context("The PipeWire thread stopped")??;
+    log::info!("Recording with PipeWire");
+    Ok(ThreadCapture {
+        name: "PipeWire",
+        send: Box::new(move |message| sender.send(message).is_ok()),
+        recording,
+        thread: Some(thread),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::Capture;
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+    use std::time::Instant;
+
+    #[test]
+    fn stream_targets() {
+        let properties = target_properties(&AudioSource::Sink("null".to_string())).unwrap();
+        assert_eq!(properties, [("stream.capture.sink", "true".to_string()), ("target.object", "null".to_string())]);
+        let properties = target_properties(&AudioSource::Source("mic".to_string())).unwrap();
+        assert_eq!(properties, [("target.object", "mic".to_string())]);
+        assert!(target_properties(&AudioSource::Application("mpv".to_string())).is_err());
+
+        let frames: Vec<u8> = [0.5f32, 0.25, -1., 1.].iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        assert_eq!(mono_samples(&frames).collect::<Vec<_>>(), [0.375, 0.]);
+    }
+
+    #[test]
+    #[ignore = "needs a PipeWire daemon"]
+    fn null_sink() {
+        // the sink lives as long as pw-cli is connected
+        let mut pw_cli = Command::new("pw-cli").stdin(Stdio::piped()).stdout(Stdio::null()).spawn().unwrap();
+        writeln!(pw_cli.stdin.as_mut().unwrap(), "create-node adapter {{ factory.name=support.null-audio-sink \
+            node.name=wallfuck-test-sink media.class=Audio/Sink audio.position=[FL FR] }}").unwrap();
+        thread::sleep(Duration::from_millis(200));
+
+        let result = capture(&AudioSource::Sink("wallfuck-test-sink".to_string())).and_then(|mut capture| {
+            assert!(capture.set_parameter("nowhere", "gain", 1.).is_err());
+            let start = Instant::now();
+            while capture.features(None).samples.is_empty() && start.elapsed() < Duration::from_secs(2) {
+                thread::sleep(Duration::from_millis(10));
+            }
+            Ok(capture.features(None))
+        });
+        pw_cli.kill().unwrap();
+        let features = result.unwrap();
+        assert!(!features.samples.is_empty());
+        // nothing plays on the sink
+        assert_eq!(features.level, 0.);
+    }
+}
@@ -0,0 +1,406 @@
+use std::rc::Rc;
+use std::cell::{Cell, RefCell};
+use std::ops::Deref;
+use std::io::Cursor;
+use std::iter;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, Result};
+use byteorder::{NativeEndian, ReadBytesExt};
+use pulse::mainloop::threaded::Mainloop;
+use pulse::context::{Context, FlagSet as ContextFlagSet};
+use pulse::def::BufferAttr;
+use pulse::stream::{Latency, PeekResult, Stream, FlagSet as StreamFlagSet};
+use pulse::sample::{Spec, Format};
+use pulse::proplist::Proplist;
+use pulse::time::MicroSeconds;
+use pulse::mainloop::api::Mainloop as MainloopTrait; //Needs to be in scope
+
+use super::dsp::*;
+use super::dsp::patch::*;
+use super::source::{self, AudioSource, Target};
+use super::{build_patch, default_patch, set_parameter, to_float, AudioFeatures, Capture, Recording, SAMPLE_RATE};
+
+/// Bytes of a frame, two channels of 16 bits
+const FRAME_SIZE: usize = 4;
+
+/// Records from a PulseAudio server, or PipeWire through pipewire-pulse.
+pub struct PulseCapture {
+    mainloop: Rc<RefCell<Mainloop>>,
+    context: Rc<RefCell<Context>>,
+    stream: Rc<RefCell<Stream>>,
+    patch: Rc<RefCell<Patch>>,
+    recording: Rc<RefCell<Recording>>,
+    builder: DSPBuilder,
+    /// Streams replacing a corked one start corked
+    paused: Rc<Cell<bool>>,
+}
+impl Capture for PulseCapture {
+    fn features(&self, time: Option<Instant>) -> AudioFeatures {
+        self.mainloop.borrow_mut().lock();
+        let features = self.recording.borrow().features(time);
+        self.mainloop.borrow_mut().unlock();
+        features
+    }
+
+    fn load_patch(&mut self, description: &PatchDescription) -> Result<()> {
+        let patch = build_patch(&self.builder, description)?;
+        // The read callback runs on the mainloop thread
+        self.mainloop.borrow_mut().lock();
+        *self.patch.borrow_mut() = patch;
+        self.mainloop.borrow_mut().unlock();
+        Ok(())
+    }
+
+    fn set_parameter(&mut self, node: &str, parameter: &str, value: f64) -> Result<()> {
+        self.mainloop.borrow_mut().lock();
+        let result = set_parameter(&self.patch.borrow(), node, parameter, value);
+        self.mainloop.borrow_mut().unlock();
+        result
+    }
+
+    fn save_patch(&mut self, path: &Path) -> Result<()> {
+        self.mainloop.borrow_mut().lock();
+        let result = self.patch.borrow().save(path);
+        self.mainloop.borrow_mut().unlock();
+        result
+    }
+
+    fn set_paused(&mut self, paused: bool) {
+        self.mainloop.borrow_mut().lock();
+        self.paused.set(paused);
+        if paused {
+            self.stream.borrow_mut().cork(None);
+        } else {
+            self.stream.borrow_mut().uncork(None);
+        }
+        self.mainloop.borrow_mut().unlock();
+    }
+}
+impl Drop for PulseCapture {
+    fn drop(&mut self) {
+        // Clean shutdown
+        self.mainloop.borrow_mut().lock();
+        self.context.borrow_mut().set_subscribe_callback(None);
+        self.stream.borrow_mut().disconnect().unwrap();
+        self.mainloop.borrow_mut().unlock();
+        self.mainloop.borrow_mut().stop();
+    }
+}
+
+
+/// What the read callback of the stream uses, `stream` being the cell the
+/// stream is kept in so it can be replaced when the target changes
+#[derive(Clone)]
+struct Shared {
+    stream: Rc<RefCell<Stream>>,
+    patch: Rc<RefCell<Patch>>,
+    recording: Rc<RefCell<Recording>>,
+}
+
+/// How long ago the latest sample read was captured, from the stream clock,
+/// and how long it takes to be heard, for the monitors of sinks
+fn latencies(stream: &mut Stream) -> (Duration, Duration) {
+    // interpolated from the timing updates of the server
+    let latency = match stream.get_latency() {
+        Ok(Latency::Positive(latency)) => Duration::from_micros(latency.0),
+        _ => Duration::ZERO,
+    };
+    let sink_latency = stream.get_timing_info()
+        .map_or(Duration::ZERO, |timing| Duration::from_micros(timing.sink_usec.0));
+    (latency, sink_latency)
+}
+
+/// Runs the patch on the captured samples
+fn reader(shared: Shared) -> Box<dyn FnMut(usize)> {
+    let Shared { stream, patch, recording } = shared;
+    Box::new(move |_| {
+        let mut stream = stream.borrow_mut();
+        let (latency, sink_latency) = latencies(&mut stream);
+        match stream.peek() {
+            // nothing to discard
+            Ok(PeekResult::Empty) => {
+                log::trace!("Empty read");
+                return;
+            },
+            // samples the server lost when we didn't read in time, the silence
+            // keeps the analysis in time
+            Ok(PeekResult::Hole(size)) => {
+                log::debug!("Hole of {} bytes in the stream", size);
+                let silence = iter::repeat(0.).take(size / FRAME_SIZE);
+                recording.borrow_mut().push(&patch.borrow(), silence, latency, sink_latency);
+            },
+            Ok(PeekResult::Data(data)) => {
+                log::trace!("Read {} bytes", data.len());
+                let mut cursor = Cursor::new(data);
+                // Both channels are mixed down as the analysis is mono
+                let samples = iter::from_fn(|| {
+                    let left = cursor.read_i16::<NativeEndian>().ok()?;
+                    let right = cursor.read_i16::<NativeEndian>().ok()?;
+                    Some((to_float(left) + to_float(right)) / 2.)
+                });
+                recording.borrow_mut().push(&patch.borrow(), samples, latency, sink_latency);
+            },
+            Err(error) => {
+                log::error!("Failed to read the stream: {}", error);
+                return;
+            },
+        }
+        if let Err(error) = stream.discard() {
+            log::error!("Failed to discard the fragment read: {}", error);
+        }
+    })
+}
+
+/// Fragments of `duration` instead of the server default, the other
+/// attributes are left to the server
+fn fragment_attr(spec: &Spec, duration: Duration) -> BufferAttr {
+    let fragment = spec.usec_to_bytes(MicroSeconds(duration.as_micros() as u64));
+    BufferAttr {
+        maxlength: u32::MAX,
+        tlength: u32::MAX,
+        prebuf: u32::MAX,
+        minreq: u32::MAX,
+        // at least a frame
+        fragsize: fragment.max(FRAME_SIZE) as u32,
+    }
+}
+
+/// Creates a stream recording `target`, started corked if `paused`
+fn record(context: &mut Context, spec: &Spec, buffer_attr: Option<&BufferAttr>, target: &Target, paused: bool) -> Result<Stream> {
+    let mut stream = Stream::new(context, "Audio Spy", spec, None)
+        .ok_or_else(|| anyhow!("Failed to create the stream recording {}", target.source))?;
+    if let Some(index) = target.sink_input {
+        stream.set_monitor_stream(index)
+            .map_err(|error| anyhow!("Failed to record sink input {}: {}", index, error))?;
+    }
+    // the latencies are measured to timestamp the samples
+    let mut flags = StreamFlagSet::INTERPOLATE_TIMING | StreamFlagSet::AUTO_TIMING_UPDATE;
+    if paused {
+        flags |= StreamFlagSet::START_CORKED;
+    }
+    // the server sizes its buffers after the fragments
+    if buffer_attr.is_some() {
+        flags |= StreamFlagSet::ADJUST_LATENCY;
+    }
+    stream.connect_record(Some(&target.source), buffer_attr, flags)
+        .map_err(|error| anyhow!("Failed to record {}: {}", target.source, error))?;
+    Ok(stream)
+}
+
+/// Records the new target of `audio_source` when the devices change, like
+/// the default sink or the application starting to play.
+fn follow_source(
+    context: &Rc<RefCell<Context>>,
+    audio_source: AudioSource,
+    spec: Spec,
+    buffer_attr: Option<BufferAttr>,
+    target: Target,
+    shared: Shared,
+    paused: Rc<Cell<bool>>,
+) {
+    let interest = audio_source.interest();
+    let target = Rc::new(RefCell::new(target));
+    let context_ref = Rc::clone(context);
+    context.borrow_mut().set_subscribe_callback(Some(Box::new(move |facility, operation, _| {
+        if !audio_source.follows(facility, operation) {
+            return;
+        }
+        let context = Rc::clone(&context_ref);
+        let (audio_source, target, shared, paused) =
+            (audio_source.clone(), target.clone(), shared.clone(), paused.clone());
+        let introspector = unsafe { (*context_ref.as_ptr()).introspect() };
+        source::query_devices(&introspector, move |devices| {
+            let (new_target, fallback) = match audio_source.resolve_or_default(&devices) {
+                Ok(resolved) => resolved,
+                Err(error) => {
+                    log::error!("{:#}", error);
+                    return;
+                },
+            };
+            if new_target == *target.borrow() {
+                return;
+            }
+            if let Some(error) = fallback {
+                log::warn!("{:#}, recording the default sink", error);
+            }
+            // runs on the mainloop thread, the stream can't be waited for
+            let result = record(unsafe { &mut *context.as_ptr() }, &spec, buffer_attr.as_ref(), &new_target, paused.get());
+            match result {
+                Ok(mut new_stream) => {
+                    let stream = shared.stream.clone();
+                    new_stream.set_read_callback(Some(reader(shared)));
+                    let mut old_stream = std::mem::replace(&mut *stream.borrow_mut(), new_stream);
+                    let _ = old_stream.disconnect();
+                    log::info!("Recording {}", new_target.source);
+                    *target.borrow_mut() = new_target;
+                },
+                Err(error) => log::error!("{:#}", error),
+            }
+        });
+    })));
+    context.borrow_mut().subscribe(interest, |_| {});
+}
+
+impl PulseCapture {
+    /// `fragment` is how much audio the server sends at once, the lower the
+    /// sooner the visuals react to it
+    pub fn new(audio_source: &AudioSource, fragment: Option<Duration>) -> Option<Self> {
+        let spec = Spec {
+            format: Format::S16NE,
+            channels: 2,
+            rate: SAMPLE_RATE,
+        };
+        assert!(spec.is_valid());
+        let buffer_attr = fragment.map(|fragment| fragment_attr(&spec, fragment));
+
+        let mut proplist = Proplist::new().unwrap();
+        proplist.set_str(pulse::proplist::properties::APPLICATION_NAME, "FooApp")
+            .unwrap();
+
+        let mut mainloop = Rc::new(RefCell::new(Mainloop::new()
+            .expect("Failed to create mainloop")));
+
+        let mut context = Rc::new(RefCell::new(Context::new_with_proplist(
+            mainloop.borrow().deref(),
+            "FooAppContext",
+            &proplist
+            ).expect("Failed to create new context")));
+
+        // Context state change callback
+        {
+            let ml_ref = Rc::clone(&mainloop);
+            let context_ref = Rc::clone(&context);
+            context.borrow_mut().set_state_callback(Some(Box::new(move || {
+                let state = unsafe { (*context_ref.as_ptr()).get_state() };
+                match state {
+                    pulse::context::State::Ready |
+                    pulse::context::State::Failed |
+                    pulse::context::State::Terminated => {
+                        unsafe { (*ml_ref.as_ptr()).signal(false); }
+                    },
+                    _ => {},
+                }
+            })));
+        }
+
+        context.borrow_mut().connect(None, ContextFlagSet::NOFLAGS, None)
+            .expect("Failed to connect context");
+
+        mainloop.borrow_mut().lock();
+        mainloop.borrow_mut().start().expect("Failed to start mainloop");
+
+        // Wait for context to be ready
+        loop {
+            match context.borrow().get_state() {
+                pulse::context::State::Ready => { break; },
+                pulse::context::State::Failed |
+                pulse::context::State::Terminated => {
+                    log::error!("Failed to connect to the Pulse server");
+                    mainloop.borrow_mut().unlock();
+                    mainloop.borrow_mut().stop();
+                    return None;
+                },
+                _ => { mainloop.borrow_mut().wait(); },
+            }
+        }
+        context.borrow_mut().set_state_callback(None);
+
+        // the lock is released while waiting for the devices
+        let devices = Rc::new(RefCell::new(None));
+        {
+            let ml_ref = Rc::clone(&mainloop);
+            let devices_ref = Rc::clone(&devices);
+            source::query_devices(&context.borrow().introspect(), move |found| {
+                *devices_ref.borrow_mut() = Some(found);
+                unsafe { (*ml_ref.as_ptr()).signal(false); }
+            });
+        }
+        while devices.borrow().is_none() {
+            mainloop.borrow_mut().wait();
+        }
+        let devices = devices.take().unwrap();
+        let target = match audio_source.resolve_or_default(&devices) {
+            Ok((target, fallback)) => {
+                if let Some(error) = fallback {
+                    log::warn!("{:#}, recording the default sink", error);
+                }
+                target
+            },
+            Err(error) => {
+                log::error!("{:#}", error);
+                mainloop.borrow_mut().unlock();
+                mainloop.borrow_mut().stop();
+                return None;
+            },
+        };
+        log::info!("Recording {}", target.source);
+
+        let stream = match record(&mut context.borrow_mut(), &spec, buffer_attr.as_ref(), &target, true) {
+            Ok(stream) => Rc::new(RefCell::new(stream)),
+            Err(error) => {
+                log::error!("{:#}", error);
+                mainloop.borrow_mut().unlock();
+                mainloop.borrow_mut().stop();
+                return None;
+            },
+        };
+
+        // Stream state change callback
+        {
+            let ml_ref = Rc::clone(&mainloop);
+            let stream_ref = Rc::clone(&stream);
+            stream.borrow_mut().set_state_callback(Some(Box::new(move || {
+                let state = unsafe { (*stream_ref.as_ptr()).get_state() };
+                match state {
+                    pulse::stream::State::Ready |
+                    pulse::stream::State::Failed |
+                    pulse::stream::State::Terminated => {
+                        unsafe { (*ml_ref.as_ptr()).signal(false); }
+                    },
+                    _ => {},
+                }
+            })));
+        }
+
+        // Wait for stream to be ready
+        loop {
+            match stream.borrow().get_state() {
+                pulse::stream::State::Ready => { break; },
+                pulse::stream::State::Failed |
+                pulse::stream::State::Terminated => {
+                    log::error!("Failed to record {}", target.source);
+                    mainloop.borrow_mut().unlock();
+                    mainloop.borrow_mut().stop();
+                    return None;
+                },
+                _ => { mainloop.borrow_mut().wait(); },
+            }
+        }
+        stream.borrow_mut().set_state_callback(None);
+
+        // create WAV file
+        super::wav::write_test_wav().unwrap();
+
+        let builder = DSPBuilder::new(SAMPLE_RATE as u64);
+        let patch = Rc::new(RefCell::new(default_patch(&builder)));
+
+        let recording = Rc::new(RefCell::new(Recording::new(SAMPLE_RATE)));
+        let shared = Shared { stream: stream.clone(), patch: patch.clone(), recording: recording.clone() };
+        stream.borrow_mut().set_read_callback(Some(reader(shared.clone())));
+        let paused = Rc::new(Cell::new(false));
+        follow_source(&context, audio_source.clone(), spec, buffer_attr, target, shared, paused.clone());
+        stream.borrow_mut().uncork(None);
+        mainloop.borrow_mut().unlock();
+
+        Some(Self {
+            mainloop,
+            context,
+            stream,
+            patch,
+            recording,
+            builder,
+            paused,
+        })
+    }
+}
@@ -0,0 +1,255 @@
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use anyhow::{anyhow, Context, Result};
+use pulse::callbacks::ListResult;
+use pulse::context::introspect::Introspector;
+use pulse::context::subscribe::{Facility, InterestMaskSet, Operation};
+use pulse::proplist::properties;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+/// What is recorded. Names are matched exactly, else searched as a regex in
+/// the names and the descriptions.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum AudioSource {
+    /// Monitor of the default sink, following it when it changes
+    #[default]
+    DefaultSink,
+    /// Monitor of a sink
+    Sink(String),
+    /// Microphone or any other source
+    Source(String),
+    /// Only what an application plays, matched on its name or the name of its stream
+    Application(String),
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Sink {
+    pub index: u32,
+    pub name: String,
+    pub description: String,
+    /// Name of the source recording what the sink plays
+    pub monitor: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Source {
+    pub name: String,
+    pub description: String,
+}
+
+/// Stream played by an application
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SinkInput {
+    pub index: u32,
+    pub application: String,
+    pub name: String,
+    /// Index of the sink it plays on
+    pub sink: u32,
+}
+
+/// What the server can record from
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Devices {
+    pub default_sink: Option<String>,
+    pub sinks: Vec<Sink>,
+    pub sources: Vec<Source>,
+    pub sink_inputs: Vec<SinkInput>,
+}
+
+/// Where the capture stream is connected
+#[derive(Clone, Debug, PartialEq)]
+pub struct Target {
+    pub source: String,
+    /// Sink input recorded alone from the monitor of its sink
+    pub sink_input: Option<u32>,
+}
+
+/// The item named `text`, else the first one whose names contain the regex `text`
+fn find<'a, T>(items: &'a [T], text: &str, names: impl Fn(&T) -> [&str; 2]) -> Option<&'a T> {
+    items.iter().find(|item| names(item)[0] == text).or_else(|| {
+        // names aren't always valid regexes
+        let pattern = Regex::new(text).ok()?;
+        items.iter().find(|item| names(item).iter().any(|name| pattern.is_match(name)))
+    })
+}
+
+impl AudioSource {
+    /// Where to record from, fails when nothing matches
+    pub fn resolve(&self, devices: &Devices) -> Result<Target> {
+        let monitor = |sink: &Sink| Target { source: sink.monitor.clone(), sink_input: None };
+        match self {
+            AudioSource::DefaultSink => {
+                let default = devices.default_sink.as_deref().context("There is no default sink")?;
+                Ok(devices.sinks.iter()
+                    .find(|sink| sink.name == default)
+                    .map(monitor)
+                    .unwrap_or_else(|| Target { source: format!("{}.monitor", default), sink_input: None }))
+            },
+            AudioSource::Sink(text) => find(&devices.sinks, text, |sink| [&sink.name, &sink.description])
+                .map(monitor)
+                .ok_or_else(|| anyhow!("No sink matches {:?}", text)),
+            AudioSource::Source(text) => find(&devices.sources, text, |source| [&source.name, &source.description])
+                .map(|source| Target { source: source.name.clone(), sink_input: None })
+                .ok_or_else(|| anyhow!("No source matches {:?}", text)),
+            AudioSource::Application(text) => {
+                let input = find(&devices.sink_inputs, text, |input| [&input.application, &input.name])
+                    .ok_or_else(|| anyhow!("No application playing matches {:?}", text))?;
+                let sink = devices.sinks.iter()
+                    .find(|sink| sink.index == input.sink)
+                    .with_context(|| format!("The sink of {} is gone", input.application))?;
+                Ok(Target { source: sink.monitor.clone(), sink_input: Some(input.index) })
+            },
+        }
+    }
+
+    /// Like `resolve`, falling back to the monitor of the default sink. The
+    /// reason is given with the fallback, for the caller to log it once.
+    pub fn resolve_or_default(&self, devices: &Devices) -> Result<(Target, Option<anyhow::Error>)> {
+        match self.resolve(devices) {
+            Ok(target) => Ok((target, None)),
+            Err(error) if *self == AudioSource::DefaultSink => Err(error),
+            Err(error) => Ok((AudioSource::DefaultSink.resolve(devices)?, Some(error))),
+        }
+    }
+
+    /// Events after which the target may change
+    pub fn interest(&self) -> InterestMaskSet {
+        // the default sink is the fallback of all of them
+        InterestMaskSet::SERVER | match self {
+            AudioSource::DefaultSink | AudioSource::Sink(_) => InterestMaskSet::SINK,
+            AudioSource::Source(_) => InterestMaskSet::SOURCE,
+            // moving to another sink changes the sink input
+            AudioSource::Application(_) => InterestMaskSet::SINK_INPUT,
+        }
+    }
+
+    /// Whether an event of `interest` may change the target, sinks and
+    /// sources are changed all the time by their volume
+    pub fn follows(&self, facility: Option<Facility>, operation: Option<Operation>) -> bool {
+        match facility {
+            Some(Facility::Server) => true,
+            Some(Facility::Sink) | Some(Facility::Source) => !matches!(operation, Some(Operation::Changed)),
+            Some(Facility::SinkInput) => matches!(self, AudioSource::Application(_)),
+            _ => false,
+        }
+    }
+}
+
+/// Lists the sinks, the sources and the sink inputs, `done` is called on the
+/// mainloop thread once they all are.
+pub fn query_devices(introspector: &Introspector, done: impl FnOnce(Devices) + 'static) {
+    let devices = Rc::new(RefCell::new(Devices::default()));
+    let pending = Cell::new(4);
+    let done = Cell::new(Some(done));
+    let finished = {
+        let devices = devices.clone();
+        Rc::new(move || {
+            pending.set(pending.get() - 1);
+            if pending.get() == 0 {
+                if let Some(done) = done.take() {
+                    done(devices.take());
+                }
+            }
+        })
+    };
+    let owned = |text: &Option<std::borrow::Cow<str>>| text.as_deref().unwrap_or_default().to_string();
+
+    let (devices_ref, finished_ref) = (devices.clone(), finished.clone());
+    introspector.get_server_info(move |info| {
+        devices_ref.borrow_mut().default_sink = info.default_sink_name.as_deref().map(str::to_string);
+        finished_ref();
+    });
+    let (devices_ref, finished_ref) = (devices.clone(), finished.clone());
+    introspector.get_sink_info_list(move |result| match result {
+        ListResult::Item(sink) => devices_ref.borrow_mut().sinks.push(Sink {
+            index: sink.index,
+            name: owned(&sink.name),
+            description: owned(&sink.description),
+            monitor: owned(&sink.monitor_source_name),
+        }),
+        ListResult::End | ListResult::Error => finished_ref(),
+    });
+    let (devices_ref, finished_ref) = (devices.clone(), finished.clone());
+    introspector.get_source_info_list(move |result| match result {
+        ListResult::Item(source) => devices_ref.borrow_mut().sources.push(Source {
+            name: owned(&source.name),
+            description: owned(&source.description),
+        }),
+        ListResult::End | ListResult::Error => finished_ref(),
+    });
+    introspector.get_sink_input_info_list(move |result| match result {
+        ListResult::Item(input) => devices.borrow_mut().sink_inputs.push(SinkInput {
+            index: input.index,
+            application: input.proplist.get_str(properties::APPLICATION_NAME).unwrap_or_default(),
+            name: owned(&input.name),
+            sink: input.sink,
+        }),
+        ListResult::End | ListResult::Error => finished(),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn devices() -> Devices {
+        let sink = |index: u32, name: &str, description: &str| Sink {
+            index,
+            name: name.to_string(),
+            description: description.to_string(),
+            monitor: format!("{}.monitor", name),
+        };
+        Devices {
+            default_sink: Some("alsa_output.usb".to_string()),
+            sinks: vec![
+                sink(0, "alsa_output.pci-0000_00_1f.3.analog-stereo", "Built-in Audio Analog Stereo"),
+                sink(1, "alsa_output.usb", "USB Headphones"),
+            ],
+            sources: vec![Source { name: "alsa_input.mic".to_string(), description: "Microphone".to_string() }],
+            sink_inputs: vec![SinkInput {
+                index: 7,
+                application: "Firefox".to_string(),
+                name: "AudioStream".to_string(),
+                sink: 0,
+            }],
+        }
+    }
+
+    #[test]
+    fn resolve_sources() {
+        let devices = devices();
+        let source = |source: &str| Target { source: source.to_string(), sink_input: None };
+        assert_eq!(AudioSource::DefaultSink.resolve(&devices).unwrap(), source("alsa_output.usb.monitor"));
+        assert_eq!(AudioSource::Sink("Built-in".to_string()).resolve(&devices).unwrap(),
+            source("alsa_output.pci-0000_00_1f.3.analog-stereo.monitor"));
+        assert_eq!(AudioSource::Source("(?i)microphone".to_string()).resolve(&devices).unwrap(),
+            source("alsa_input.mic"));
+        assert_eq!(AudioSource::Application("^Fire".to_string()).resolve(&devices).unwrap(), Target {
+            source: "alsa_output.pci-0000_00_1f.3.analog-stereo.monitor".to_string(),
+            sink_input: Some(7),
+        });
+
+        assert!(AudioSource::Application("mpv".to_string()).resolve(&devices).is_err());
+        assert!(AudioSource::Sink("(".to_string()).resolve(&devices).is_err());
+        // exact names first
+        assert_eq!(AudioSource::Sink("alsa_output.usb".to_string()).resolve(&devices).unwrap(),
+            source("alsa_output.usb.monitor"));
+        let (target, fallback) = AudioSource::Application("mpv".to_string()).resolve_or_default(&devices).unwrap();
+        assert_eq!(target, source("alsa_output.usb.monitor"));
+        assert_eq!(fallback.unwrap().to_string(), "No application playing matches \"mpv\"");
+        let no_default = Devices { default_sink: None, ..devices };
+        assert!(AudioSource::Application("mpv".to_string()).resolve_or_default(&no_default).is_err());
+    }
+
+    #[test]
+    fn followed_events() {
+        let application = AudioSource::Application("mpv".to_string());
+        assert!(application.follows(Some(Facility::SinkInput), Some(Operation::New)));
+        assert!(application.follows(Some(Facility::Server), Some(Operation::Changed)));
+        // volume changes
+        assert!(!AudioSource::DefaultSink.follows(Some(Facility::Sink), Some(Operation::Changed)));
+        assert!(AudioSource::DefaultSink.follows(Some(Facility::Sink), Some(Operation::Removed)));
+        assert!(!AudioSource::DefaultSink.follows(Some(Facility::SinkInput), Some(Operation::New)));
+    }
+}
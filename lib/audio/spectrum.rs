@@ -0,0 +1,103 @@
+use super::fft::{FourierTransform, WindowMode};
+
+pub const MIN_DECIBELS: f64 = -80.;
+pub const MAX_DECIBELS: f64 = 0.;
+
+/// Magnitude spectrum of the latest samples, mapped from
+/// [MIN_DECIBELS, MAX_DECIBELS] to [0, 1] like the Web Audio analyser.
+pub struct SpectrumAnalyser {
+    fourier: FourierTransform,
+    size: usize,
+    sample_rate: u64,
+    /// 0 follows the input instantly, close to 1 barely moves
+    pub smoothing: f64,
+    magnitudes: Vec<f64>,
+}
+impl SpectrumAnalyser {
+    /// `size` has to be a power of 2, it gives `size / 2` frequency bins.
+    pub fn new(size: usize, sample_rate: u64, smoothing: f64) -> Self {
+        Self {
+            fourier: FourierTransform::new(WindowMode::Hann, size, sample_rate),
+            size,
+            sample_rate,
+            smoothing,
+            magnitudes: vec![0.; size / 2],
+        }
+    }
+    /// Follows the rate of the captured samples, 0 keeps the current one.
+    pub fn set_sample_rate(&mut self, sample_rate: u64) {
+        if sample_rate != 0 && sample_rate != self.sample_rate {
+            self.fourier = FourierTransform::new(WindowMode::Hann, self.size, sample_rate);
+            self.sample_rate = sample_rate;
+        }
+    }
+    /// Hz between two frequency bins
+    pub fn bin_width(&self) -> f64 {
+        self.sample_rate as f64 / self.size as f64
+    }
+    pub fn process(&mut self, samples: &[f64]) -> &[f64] {
+        let start = samples.len().saturating_sub(self.size);
+        self.fourier.process(&samples[start..]).unwrap();
+        let bins = self.fourier.bins();
+        for (magnitude, bin) in self.magnitudes.iter_mut().zip(bins) {
+            // The Hann window halves the amplitude and the other half
+            // of the energy is in the negative frequencies
+            let decibels = 20. * (4. * bin.norm()).max(1e-10).log10();
+            let normalised = ((decibels - MIN_DECIBELS) / (MAX_DECIBELS - MIN_DECIBELS))
+                .clamp(0., 1.);
+            *magnitude = self.smoothing * *magnitude + (1. - self.smoothing) * normalised;
+        }
+        &self.magnitudes
+    }
+}
+
+
+
+//==============================================================================
+// Tests
+//==============================================================================
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    const SAMPLE_RATE: u64 = 44100;
+    const SIZE: usize = 1024;
+
+    #[test]
+    fn full_scale_sine_peaks_at_its_bin() {
+        let bin = 64;
+        let frequency = bin as f64 * SAMPLE_RATE as f64 / SIZE as f64;
+        let samples: Vec<f64> = (0..SIZE)
+            .map(|i| (2. * PI * frequency * i as f64 / SAMPLE_RATE as f64).sin())
+            .collect();
+        let mut analyser = SpectrumAnalyser::new(SIZE, SAMPLE_RATE, 0.);
+
+        let magnitudes = analyser.process(&samples);
+        assert!((magnitudes[bin] - 1.).abs() <= 0.01);
+        assert!(magnitudes[bin * 4] < 0.1);
+    }
+
+    #[test]
+    fn silence_and_smoothing() {
+        let mut analyser = SpectrumAnalyser::new(SIZE, SAMPLE_RATE, 0.5);
+        assert!(analyser.process(&[0.; SIZE]).iter().all(|&magnitude| magnitude == 0.));
+
+        let samples: Vec<f64> = (0..SIZE)
+            .map(|i| (2. * PI * 16. * i as f64 / SIZE as f64).sin())
+            .collect();
+        let first = analyser.process(&samples)[16];
+        let second = analyser.process(&samples)[16];
+        assert!((first - 0.5).abs() <= 0.01);
+        assert!((second - 0.75).abs() <= 0.01);
+    }
+
+    #[test]
+    fn follows_the_sample_rate() {
+        let mut analyser = SpectrumAnalyser::new(SIZE, SAMPLE_RATE, 0.);
+        analyser.set_sample_rate(48000);
+        assert_eq!(analyser.bin_width(), 48000. / SIZE as f64);
+        analyser.set_sample_rate(0);
+        assert_eq!(analyser.bin_width(), 48000. / SIZE as f64);
+    }
+}
@@ -32,41 +32,32 @@ pub fn write_test_wav() -> io::Result<()> {
 
     // use dsp lib to render sound
     let dsp_builder = DSPBuilder::new(44100);
-    let adsr = dsp_builder.build_adsr(
-        300, 0.5,
-        0.4,
-        100, 0.5,
-        0.32,
-        100, 0.5);
     let noise_adsr = dsp_builder.build_adsr(
         300, 0.5,
         0.2,
         100, 0.5,
         0.12,
         100, 0.5);
-    adsr.borrow_mut().state = ADSRState::Attack(0);
-    let modulator = dsp_builder.build_oscillator(WaveKind::Sine, 4., 2.);
-
-    let d = dsp_builder.build_oscillator(WaveKind::Saw, 261.6256, 0.);
-    d.borrow_mut().frequency.add_modulator(modulator.clone());
-    d.borrow_mut().amplitude.add_modulator(adsr.clone());
-    let e = dsp_builder.build_oscillator(WaveKind::Saw, 329.6276, 0.);
-    e.borrow_mut().frequency.add_modulator(modulator.clone());
-    e.borrow_mut().amplitude.add_modulator(adsr.clone());
-    let g = dsp_builder.build_oscillator(WaveKind::Saw, 391.9954, 0.);
-    g.borrow_mut().frequency.add_modulator(modulator.clone());
-    g.borrow_mut().amplitude.add_modulator(adsr.clone());
-    let h = dsp_builder.build_oscillator(WaveKind::Saw, 493.8833, 0.);
-    h.borrow_mut().frequency.add_modulator(modulator.clone());
-    h.borrow_mut().amplitude.add_modulator(adsr.clone());
+    let voice = VoiceDescription {
+        kind: WaveKind::Saw,
+        unison: 1,
+        attack: 300, attack_curve: 0.5,
+        peak: 0.4,
+        decay: 100, decay_curve: 0.5,
+        sustain: 0.32,
+        release: 100, release_curve: 0.5,
+        filter: None,
+    };
+    let synth = dsp_builder.build_voice_allocator(4, &voice, VoiceStealing::Oldest, 0., 0.);
+    // C major seventh
+    for note in [60, 64, 67, 71] {
+        synth.borrow_mut().note_on(note, 1.);
+    }
     let noise = dsp_builder.build_noise(NoiseKind::White, 1.0);
     noise.borrow_mut().amplitude.add_modulator(noise_adsr.clone());
 
     let parallel = dsp_builder.build_parallel();
-    //parallel.borrow_mut().add(d.clone());
-    //parallel.borrow_mut().add(e.clone());
-    //parallel.borrow_mut().add(g.clone());
-    //parallel.borrow_mut().add(h.clone());
+    //parallel.borrow_mut().add(synth.clone());
     parallel.borrow_mut().add(noise.clone());
 
     let butterworth = dsp_builder.build_butterworth_filter(ButterworthFilterKind::LowPass, 500., 17);
@@ -78,11 +69,11 @@ pub fn write_test_wav() -> io::Result<()> {
 
     for i in 0..88200 {
         if i == 83000 {
-            adsr.borrow_mut().state = ADSRState::Release(0);
+            synth.borrow_mut().note_off(60);
         } else if i == 44100 {
-            e.borrow_mut().enabled.value = 0.;
-            g.borrow_mut().enabled.value = 0.;
-            h.borrow_mut().enabled.value = 0.;
+            for note in [64, 67, 71] {
+                synth.borrow_mut().note_off(note);
+            }
             noise.borrow_mut().enabled.value = 0.;
         }
         let sample = chain.borrow_mut().tick(1).unwrap_or(0.);
@@ -0,0 +1,138 @@
+use std::f32::consts::TAU;
+use std::time::Instant;
+use serde::{Deserialize, Serialize};
+
+/// Vertical field of view of the camera, in degrees
+pub const FOVY: f32 = 45.;
+/// Time for the parallax to cover most of the way to the mouse, in seconds
+const PARALLAX_EASING: f32 = 0.3;
+/// The camera stops following the mouse closer than this
+const PARALLAX_EPSILON: f32 = 1e-4;
+/// Radians between the pans of two Ken Burns cycles, so they don't repeat
+const GOLDEN_ANGLE: f32 = 2.399_963;
+
+/// Distance at which the wallpaper plane, 2 units high, fills the view
+pub fn plane_distance() -> f32 {
+    1. / (FOVY.to_radians() / 2.).tan()
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KenBurns {
+    /// Seconds to zoom in and back out
+    pub period: f32,
+    /// Zoom added at the middle of the period, 0.2 being 20% closer
+    pub zoom: f32,
+}
+impl Default for KenBurns {
+    fn default() -> Self {
+        Self { period: 30., zoom: 0.2 }
+    }
+}
+
+/// Camera motion as written in the config, the wallpaper plane being 2
+/// units high.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CameraConfig {
+    /// Slow zoom towards a different point every period
+    pub ken_burns: Option<KenBurns>,
+    /// Distance the camera moves when the mouse is at an edge of the window
+    pub parallax: f32,
+    /// Distance the camera shakes for a transient of 1
+    pub shake: f32,
+}
+
+/// Zoom and pan of the Ken Burns effect after `time` seconds. The pan stays
+/// within the margin the zoom leaves, so no border shows.
+fn ken_burns(ken_burns: &KenBurns, time: f32) -> ([f32; 2], f32) {
+    let cycles = time / ken_burns.period.max(1.);
+    let progress = 0.5 - 0.5 * (cycles.fract() * TAU).cos();
+    let zoom = 1. + ken_burns.zoom.max(0.) * progress;
+    let angle = cycles.floor() * GOLDEN_ANGLE;
+    let margin = 1. - 1. / zoom;
+    ([margin * angle.cos(), margin * angle.sin()], zoom)
+}
+
+/// Moves the camera from frame to frame: Ken Burns, mouse parallax and
+/// audio shake.
+pub struct CameraMotion {
+    config: CameraConfig,
+    start: Instant,
+    last_update: Instant,
+    /// From -1 to 1, y up
+    mouse_target: [f32; 2],
+    mouse: [f32; 2],
+}
+
+impl CameraMotion {
+    pub fn new(config: &CameraConfig) -> Self {
+        let now = Instant::now();
+        Self {
+            config: config.clone(),
+            start: now,
+            last_update: now,
+            mouse_target: [0., 0.],
+            mouse: [0., 0.],
+        }
+    }
+
+    /// `x` and `y` in pixels from the top left corner
+    pub fn set_mouse(&mut self, x: f32, y: f32, size: winit::dpi::PhysicalSize<u32>) {
+        self.mouse_target = [
+            (x / size.width as f32 * 2. - 1.).clamp(-1., 1.),
+            (1. - y / size.height as f32 * 2.).clamp(-1., 1.),
+        ];
+    }
+
+    /// Whether the camera moves without new input
+    pub fn is_animated(&self) -> bool {
+        let following = (0..2).any(|i| (self.mouse_target[i] - self.mouse[i]).abs() > PARALLAX_EPSILON);
+        self.config.ken_burns.is_some() || self.config.shake > 0. || (self.config.parallax > 0. && following)
+    }
+
+    /// Pan and zoom of the camera, `transient` being the output of the DSP patch
+    pub fn update(&mut self, transient: f32) -> ([f32; 2], f32) {
+        let now = Instant::now();
+        let time = (now - self.start).as_secs_f32();
+        let easing = 1. - (-(now - self.last_update).as_secs_f32() * 3. / PARALLAX_EASING).exp();
+        self.last_update = now;
+        for i in 0..2 {
+            self.mouse[i] += (self.mouse_target[i] - self.mouse[i]) * easing;
+        }
+
+        let (mut pan, mut zoom) = match &self.config.ken_burns {
+            Some(settings) => ken_burns(settings, time),
+            None => ([0., 0.], 1.),
+        };
+        let shake = self.config.shake.max(0.) * transient.clamp(0., 1.);
+        // pseudo-random direction, fast enough to look like a shake
+        let shake_direction = [(time * 97.).sin(), (time * 89. + 1.).cos()];
+        for i in 0..2 {
+            pan[i] += self.config.parallax * self.mouse[i] + shake * shake_direction[i];
+        }
+        // zoomed in enough that the parallax and the shake never show a border
+        let margin = (self.config.parallax.max(0.) + self.config.shake.max(0.)).min(0.5);
+        zoom /= 1. - margin;
+        (pan, zoom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ken_burns_stays_inside_the_wallpaper() {
+        let settings = KenBurns { period: 10., zoom: 0.5 };
+        let (pan, zoom) = ken_burns(&settings, 0.);
+        assert_eq!((pan, zoom), ([0., 0.], 1.));
+        let (pan, zoom) = ken_burns(&settings, 5.);
+        assert_eq!(zoom, 1.5);
+        // the view is 1 / zoom high, the pan is what's left
+        assert!((pan[0].hypot(pan[1]) - (1. - 1. / 1.5)).abs() < 1e-6);
+        // the next cycle goes somewhere else
+        let (next_pan, _) = ken_burns(&settings, 15.);
+        assert!((next_pan[0] - pan[0]).abs() > 0.1);
+    }
+}
@@ -0,0 +1,123 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::audio::{AudioBackend, AudioSource};
+use crate::camera::CameraConfig;
+use crate::globals::Tonemap;
+use crate::gpu::GpuConfig;
+use crate::layers::LayerDescription;
+use crate::render_graph::PassDescription;
+use crate::slideshow::SlideshowDescription;
+use crate::visualiser::VisualiserDescription;
+
+const DEFAULT_WALLPAPER_PATH: &str = "/etc/nixos/resources/wallpapers/space.jpg";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Still image (PNG, JPEG, WebP, AVIF, TIFF, BMP, Radiance HDR or OpenEXR), animated GIF
+    /// or APNG, directory of frames or video (YUV4MPEG2 or Motion JPEG AVI)
+    pub wallpaper: PathBuf,
+    /// Frames per second of the wallpaper when it is a directory of frames
+    pub sequence_frame_rate: f32,
+    /// Speed of video wallpapers, 1 being their normal speed
+    pub playback_rate: f32,
+    /// Whether video wallpapers start over at the end or stay on their last frame
+    pub loop_video: bool,
+    /// Downscales still wallpapers to the window with a Lanczos filter on the
+    /// CPU, sharper than the mipmaps but slower to load
+    pub prescale: bool,
+    /// How 16-bit and float wallpapers are brought to the screen
+    pub tonemap: Tonemap,
+    /// Stops added to high dynamic range wallpapers before tonemapping
+    pub exposure: f32,
+    /// Frames per second drawn at most, when the wallpaper moves
+    pub max_fps: f32,
+    /// Cap while running on battery
+    pub battery_max_fps: f32,
+    /// Ken Burns, mouse parallax and audio shake
+    pub camera: CameraConfig,
+    /// Backend, adapter, power preference and present mode
+    pub gpu: GpuConfig,
+    /// Rotates wallpapers instead of showing `wallpaper`
+    pub slideshow: Option<SlideshowDescription>,
+    /// WGSL shader replacing the built-in one, it needs the same entry points and bindings
+    pub shader: Option<PathBuf>,
+    /// DSP patch replacing the built-in transient detector
+    pub patch: Option<PathBuf>,
+    /// Sound server recording the audio
+    pub audio_backend: AudioBackend,
+    /// Sink monitor, source or application recorded for the audio inputs
+    pub audio_source: AudioSource,
+    /// Seconds of audio Pulse sends at once, lower for visuals reacting
+    /// sooner at the cost of CPU time. The server decides without it.
+    pub audio_fragment: Option<f32>,
+    /// Seconds the visuals are shown after the sound they react to, on top
+    /// of the latencies measured. Negative to show them sooner.
+    pub av_offset: f32,
+    /// Named values of `globals.parameters` in the shaders, in order, they
+    /// can be changed from the control socket
+    pub parameters: Vec<(String, f32)>,
+    /// Textures bound to the fixed channel slots of the shader, in order
+    pub textures: Vec<PathBuf>,
+    /// Offscreen passes run after the wallpaper is drawn, one of them has to output to the screen
+    pub passes: Vec<PassDescription>,
+    /// Images, shaders and visualisers composited over the wallpaper, in order
+    pub layers: Vec<LayerDescription>,
+    /// Spectrum and waveform visualisers drawn over the wallpaper
+    pub visualisers: Vec<VisualiserDescription>,
+}
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            wallpaper: PathBuf::from(DEFAULT_WALLPAPER_PATH),
+            sequence_frame_rate: 24.,
+            playback_rate: 1.,
+            loop_video: true,
+            prescale: false,
+            tonemap: Tonemap::default(),
+            exposure: 0.,
+            max_fps: 60.,
+            battery_max_fps: 15.,
+            camera: CameraConfig::default(),
+            gpu: GpuConfig::default(),
+            slideshow: None,
+            shader: None,
+            patch: None,
+            audio_backend: AudioBackend::default(),
+            audio_source: AudioSource::default(),
+            audio_fragment: None,
+            av_offset: 0.,
+            parameters: Vec::new(),
+            textures: Vec::new(),
+            passes: Vec::new(),
+            layers: Vec::new(),
+            visualisers: Vec::new(),
+        }
+    }
+}
+impl Config {
+    /// $XDG_CONFIG_HOME/wallfuck/config.ron, falling back to ~/.config
+    pub fn path() -> PathBuf {
+        let config_home = env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+            .unwrap_or_default();
+        config_home.join("wallfuck").join("config.ron")
+    }
+
+    /// Loads the user's configuration, a missing file gives the defaults.
+    pub fn load() -> Result<Self> {
+        let path = Self::path();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let text = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read config {}", path.display()))?;
+        ron::from_str(&text)
+            .with_context(|| format!("Failed to parse config {}", path.display()))
+    }
+}
@@ -0,0 +1,158 @@
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+use anyhow::{Context, Result};
+use image::{DynamicImage, ImageDecoder, ImageFormat, Rgba32FImage};
+
+/// Still image files that can be decoded, AVIF needs the `avif` feature
+pub const EXTENSIONS: &[&str] = &[
+    "png", "jpg", "jpeg", "gif", "webp", "avif", "tif", "tiff", "bmp", "hdr", "exr",
+];
+
+pub fn is_image(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| EXTENSIONS.contains(&extension.to_lowercase().as_str()))
+}
+
+pub fn open(path: &Path) -> Result<DynamicImage> {
+    let bytes = fs::read(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    decode(&bytes).with_context(|| format!("Failed to decode {}", path.display()))
+}
+
+/// Decodes an image upright and in sRGB: the EXIF orientation is applied and
+/// the embedded ICC profile converted. 16-bit and float images are kept as
+/// they are to be uploaded in a float texture.
+pub fn decode(bytes: &[u8]) -> Result<DynamicImage> {
+    let format = image::guess_format(bytes)?;
+    let mut image = image::load_from_memory_with_format(bytes, format)?;
+    if let Some(profile) = icc_profile(bytes, format) {
+        if is_high_dynamic_range(&image) {
+            log::warn!("Ignoring the ICC profile of a high dynamic range image");
+        } else {
+            image = to_srgb(image, &profile);
+        }
+    }
+    if let Some(orientation) = orientation(bytes) {
+        image = orient(image, orientation);
+    }
+    Ok(image)
+}
+
+/// Whether the image has more precision than an 8-bit sRGB texture can hold
+pub fn is_high_dynamic_range(image: &DynamicImage) -> bool {
+    matches!(
+        image,
+        DynamicImage::ImageLuma16(_) | DynamicImage::ImageLumaA16(_)
+            | DynamicImage::ImageRgb16(_) | DynamicImage::ImageRgba16(_)
+            | DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_)
+    )
+}
+
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Linear RGBA for a float texture: integer images are sRGB encoded, float
+/// ones (Radiance HDR, OpenEXR) are already linear.
+pub fn to_linear(image: &DynamicImage) -> Rgba32FImage {
+    let mut linear = image.to_rgba32f();
+    if !matches!(image, DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_)) {
+        for pixel in linear.pixels_mut() {
+            for channel in &mut pixel.0[..3] {
+                *channel = srgb_to_linear(*channel);
+            }
+        }
+    }
+    linear
+}
+
+fn icc_profile(bytes: &[u8], format: ImageFormat) -> Option<Vec<u8>> {
+    let cursor = Cursor::new(bytes);
+    match format {
+        ImageFormat::Png => image::codecs::png::PngDecoder::new(cursor).ok()?.icc_profile(),
+        ImageFormat::Jpeg => image::codecs::jpeg::JpegDecoder::new(cursor).ok()?.icc_profile(),
+        ImageFormat::WebP => image::codecs::webp::WebPDecoder::new(cursor).ok()?.icc_profile(),
+        ImageFormat::Tiff => image::codecs::tiff::TiffDecoder::new(cursor).ok()?.icc_profile(),
+        #[cfg(feature = "avif")]
+        ImageFormat::Avif => image::codecs::avif::AvifDecoder::new(cursor).ok()?.icc_profile(),
+        _ => None,
+    }
+}
+
+fn to_srgb(image: DynamicImage, profile: &[u8]) -> DynamicImage {
+    let transform = qcms::Profile::new_from_slice(profile, false).and_then(|profile| {
+        let srgb = qcms::Profile::new_sRGB();
+        qcms::Transform::new(&profile, &srgb, qcms::DataType::RGBA8, qcms::Intent::Perceptual)
+    });
+    match transform {
+        Some(transform) => {
+            let mut rgba = image.into_rgba8();
+            transform.apply(&mut rgba);
+            DynamicImage::ImageRgba8(rgba)
+        },
+        None => {
+            // CMYK, grayscale or broken profiles
+            log::warn!("Unsupported ICC profile, the colors may be off");
+            image
+        },
+    }
+}
+
+fn orientation(bytes: &[u8]) -> Option<u32> {
+    let exif = exif::Reader::new().read_from_container(&mut Cursor::new(bytes)).ok()?;
+    exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)?.value.get_uint(0)
+}
+
+/// Applies an EXIF orientation, 1 being upright
+fn orient(image: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GenericImageView, Luma, Rgba};
+
+    #[test]
+    fn exif_orientation() {
+        // 2x1: a black pixel then a white one
+        let image = DynamicImage::ImageLuma8(image::ImageBuffer::from_fn(2, 1, |x, _| Luma([x as u8 * 255])));
+        let rotated = orient(image.clone(), 6);
+        assert_eq!(rotated.dimensions(), (1, 2));
+        assert_eq!(rotated.get_pixel(0, 1), Rgba([255, 255, 255, 255]));
+        // transposed, the white pixel ends up at the bottom as well
+        let transposed = orient(image.clone(), 5);
+        assert_eq!(transposed.get_pixel(0, 1), Rgba([255, 255, 255, 255]));
+        let transversed = orient(image, 7);
+        assert_eq!(transversed.get_pixel(0, 0), Rgba([255, 255, 255, 255]));
+    }
+
+    #[test]
+    fn linear_colors() {
+        let srgb = DynamicImage::ImageRgba16(image::ImageBuffer::from_pixel(1, 1, Rgba([32768, 0, 65535, 32768])));
+        let linear = to_linear(&srgb).get_pixel(0, 0).0;
+        assert!((linear[0] - 0.214).abs() < 1e-3);
+        assert_eq!(linear[2], 1.);
+        // alpha isn't gamma encoded
+        assert!((linear[3] - 0.5).abs() < 1e-3);
+
+        let float = DynamicImage::ImageRgb32F(image::ImageBuffer::from_pixel(1, 1, image::Rgb([4., 0.5, 0.])));
+        assert_eq!(to_linear(&float).get_pixel(0, 0).0, [4., 0.5, 0., 1.]);
+        assert!(is_high_dynamic_range(&float));
+    }
+}
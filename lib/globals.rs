@@ -0,0 +1,331 @@
+use std::fs;
+use std::path::Path;
+use std::time::Instant;
+use anyhow::{bail, Context, Result};
+use chrono::{Datelike, Local, Timelike};
+use serde::{Deserialize, Serialize};
+
+use crate::audio::{AudioFeatures, SAMPLE_RATE};
+use crate::audio::spectrum::SpectrumAnalyser;
+use crate::texture;
+
+/// Width of the spectrum texture, row 0 is the spectrum and row 1 the waveform
+pub const SPECTRUM_WIDTH: u32 = 512;
+/// Number of fixed slots for user textures (`t_channel0` to `t_channel3`)
+pub const NB_CHANNELS: usize = 4;
+/// Number of values in `parameters`
+pub const NB_PARAMETERS: usize = 16;
+const SPECTRUM_SMOOTHING: f64 = 0.8;
+/// Declarations of the bind groups 2 and 3, prepended to the shaders using them
+pub const GLOBALS_SHADER: &str = include_str!("shaders/globals.wgsl");
+
+/// Operator bringing high dynamic range wallpapers to the screen, the values
+/// match the constants of the shaders.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Tonemap {
+    /// Colors over 1 are clipped
+    None,
+    Reinhard,
+    #[default]
+    Aces,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct GlobalsUniform {
+    resolution: [f32; 2],
+    mouse: [f32; 2],
+    time: f32,
+    time_delta: f32,
+    frame: u32,
+    audio_level: f32,
+    audio_transient: f32,
+    // date is a vec4 so it has to be aligned on 16 bytes
+    _padding: [f32; 3],
+    date: [f32; 4],
+    tonemap: u32,
+    exposure: f32,
+    _padding2: [f32; 2],
+    parameters: [[f32; 4]; NB_PARAMETERS / 4],
+}
+
+/// Shadertoy-like inputs available to every shader in bind groups 2 and 3.
+pub struct Globals {
+    uniform: GlobalsUniform,
+    buffer: wgpu::Buffer,
+    start: Instant,
+    last_frame: Instant,
+    analyser: SpectrumAnalyser,
+    spectrum_texture: wgpu::Texture,
+    spectrum_data: Vec<u8>,
+    pub bind_group_layout: wgpu::BindGroupLayout,
+    pub bind_group: wgpu::BindGroup,
+
+    channels: Vec<texture::Texture>,
+    pub channels_bind_group_layout: wgpu::BindGroupLayout,
+    pub channels_bind_group: wgpu::BindGroup,
+}
+
+impl Globals {
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue) -> Self {
+        let uniform = GlobalsUniform {
+            resolution: [0., 0.],
+            mouse: [0., 0.],
+            time: 0.,
+            time_delta: 0.,
+            frame: 0,
+            audio_level: 0.,
+            audio_transient: 0.,
+            _padding: [0.; 3],
+            date: [0.; 4],
+            tonemap: Tonemap::None as u32,
+            exposure: 0.,
+            _padding2: [0.; 2],
+            parameters: [[0.; 4]; NB_PARAMETERS / 4],
+        };
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Globals Buffer"),
+            size: std::mem::size_of::<GlobalsUniform>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let spectrum_texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("spectrum-texture"),
+            size: wgpu::Extent3d {
+                width: SPECTRUM_WIDTH,
+                height: 2,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::R8Unorm,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+        let spectrum_view = spectrum_texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let spectrum_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+            label: Some("globals_bind_group_layout"),
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&spectrum_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(&spectrum_sampler),
+                },
+            ],
+            label: Some("globals_bind_group"),
+        });
+
+        // Every slot holds a texture so the layout is the same with or without user textures
+        let channels: Vec<_> = (0..NB_CHANNELS).map(|_| {
+            let black = texture::Texture::new(
+                device, (1, 1), texture::Texture::FORMAT, false, Some("channel-texture")
+            );
+            black.write(queue, &image::RgbaImage::new(1, 1));
+            black
+        }).collect();
+        let channels_bind_group_layout = device.create_bind_group_layout(
+            &wgpu::BindGroupLayoutDescriptor {
+                entries: &(0..NB_CHANNELS as u32).flat_map(|i| [
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2 * i,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2 * i + 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                        count: None,
+                    },
+                ]).collect::<Vec<_>>(),
+                label: Some("channels_bind_group_layout"),
+            }
+        );
+        let channels_bind_group = Self::create_channels_bind_group(
+            device, &channels_bind_group_layout, &channels
+        );
+
+        Self {
+            uniform,
+            buffer,
+            start: Instant::now(),
+            last_frame: Instant::now(),
+            analyser: SpectrumAnalyser::new(2 * SPECTRUM_WIDTH as usize, SAMPLE_RATE.into(), SPECTRUM_SMOOTHING),
+            spectrum_texture,
+            spectrum_data: vec![0; 2 * SPECTRUM_WIDTH as usize],
+            bind_group_layout,
+            bind_group,
+            channels,
+            channels_bind_group_layout,
+            channels_bind_group,
+        }
+    }
+
+    fn create_channels_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        channels: &[texture::Texture],
+    ) -> wgpu::BindGroup {
+        let entries: Vec<_> = channels.iter().enumerate().flat_map(|(i, channel)| [
+            wgpu::BindGroupEntry {
+                binding: 2 * i as u32,
+                resource: wgpu::BindingResource::TextureView(&channel.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2 * i as u32 + 1,
+                resource: wgpu::BindingResource::Sampler(&channel.sampler),
+            },
+        ]).collect();
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &entries,
+            label: Some("channels_bind_group"),
+        })
+    }
+
+    /// Loads a user texture in one of the fixed slots.
+    pub fn load_channel(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        mipmap_generator: &texture::MipmapGenerator,
+        slot: usize,
+        path: &Path,
+    ) -> Result<()> {
+        if slot >= NB_CHANNELS {
+            bail!("Texture {} doesn't fit in the {} channels", path.display(), NB_CHANNELS);
+        }
+        let bytes = fs::read(path)
+            .with_context(|| format!("Failed to read texture {}", path.display()))?;
+        self.channels[slot] = texture::Texture::from_bytes(device, queue, mipmap_generator, &bytes, "channel-texture")
+            .with_context(|| format!("Failed to decode texture {}", path.display()))?;
+        self.channels_bind_group = Self::create_channels_bind_group(
+            device, &self.channels_bind_group_layout, &self.channels
+        );
+        Ok(())
+    }
+
+    pub fn set_mouse(&mut self, x: f32, y: f32) {
+        self.uniform.mouse = [x, y];
+    }
+
+    /// `index` below `NB_PARAMETERS`
+    pub fn set_parameter(&mut self, index: usize, value: f32) {
+        self.uniform.parameters[index / 4][index % 4] = value;
+    }
+
+    /// Exposure in stops, applied before the operator
+    pub fn set_tonemap(&mut self, tonemap: Tonemap, exposure: f32) {
+        self.uniform.tonemap = tonemap as u32;
+        self.uniform.exposure = exposure;
+    }
+
+    pub fn update(
+        &mut self,
+        queue: &wgpu::Queue,
+        size: winit::dpi::PhysicalSize<u32>,
+        audio: Option<&AudioFeatures>,
+    ) {
+        let now = Instant::now();
+        let date = Local::now();
+        self.uniform.resolution = [size.width as f32, size.height as f32];
+        self.uniform.time = (now - self.start).as_secs_f32();
+        self.uniform.time_delta = (now - self.last_frame).as_secs_f32();
+        self.uniform.frame = self.uniform.frame.wrapping_add(1);
+        self.uniform.date = [
+            date.year() as f32,
+            date.month() as f32,
+            date.day() as f32,
+            date.num_seconds_from_midnight() as f32 + date.nanosecond() as f32 / 1e9,
+        ];
+        self.last_frame = now;
+
+        if let Some(audio) = audio {
+            self.uniform.audio_level = audio.level as f32;
+            self.uniform.audio_transient = audio.transient as f32;
+
+            self.analyser.set_sample_rate(audio.sample_rate.into());
+            let samples: Vec<f64> = audio.samples.iter().copied().collect();
+            let (spectrum, waveform) = self.spectrum_data.split_at_mut(SPECTRUM_WIDTH as usize);
+            for (texel, magnitude) in spectrum.iter_mut().zip(self.analyser.process(&samples)) {
+                *texel = (magnitude * 255.) as u8;
+            }
+            let start = samples.len().saturating_sub(SPECTRUM_WIDTH as usize);
+            for (texel, sample) in waveform.iter_mut().zip(&samples[start..]) {
+                *texel = ((sample.clamp(-1., 1.) + 1.) / 2. * 255.) as u8;
+            }
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    aspect: wgpu::TextureAspect::All,
+                    texture: &self.spectrum_texture,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d::ZERO,
+                },
+                &self.spectrum_data,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(SPECTRUM_WIDTH),
+                    rows_per_image: Some(2),
+                },
+                self.spectrum_texture.size(),
+            );
+        }
+
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&[self.uniform]));
+    }
+}
@@ -0,0 +1,208 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Backend {
+    Vulkan,
+    Metal,
+    Dx12,
+    Gl,
+    BrowserWebGpu,
+}
+
+impl Backend {
+    fn flag(self) -> wgpu::Backends {
+        match self {
+            Backend::Vulkan => wgpu::Backends::VULKAN,
+            Backend::Metal => wgpu::Backends::METAL,
+            Backend::Dx12 => wgpu::Backends::DX12,
+            Backend::Gl => wgpu::Backends::GL,
+            Backend::BrowserWebGpu => wgpu::Backends::BROWSER_WEBGPU,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum PowerPreference {
+    /// Integrated GPU when there is one, a wallpaper runs all the time
+    #[default]
+    LowPower,
+    HighPerformance,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum PresentMode {
+    /// Vsync, supported everywhere
+    #[default]
+    Fifo,
+    /// Vsync without waiting, the latest frame is shown
+    Mailbox,
+    /// No vsync, may tear
+    Immediate,
+}
+
+impl PresentMode {
+    fn mode(self) -> wgpu::PresentMode {
+        match self {
+            PresentMode::Fifo => wgpu::PresentMode::Fifo,
+            PresentMode::Mailbox => wgpu::PresentMode::Mailbox,
+            PresentMode::Immediate => wgpu::PresentMode::Immediate,
+        }
+    }
+}
+
+/// GPU selection as written in the config.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GpuConfig {
+    /// Backends allowed to render, all of them if empty
+    pub backends: Vec<Backend>,
+    /// Picks the adapter whose name contains this, ignoring case
+    pub adapter: Option<String>,
+    pub power_preference: PowerPreference,
+    pub present_mode: PresentMode,
+    /// Renders on the CPU when no GPU can be used
+    pub software_fallback: bool,
+}
+impl Default for GpuConfig {
+    fn default() -> Self {
+        Self {
+            backends: Vec::new(),
+            adapter: None,
+            power_preference: PowerPreference::default(),
+            present_mode: PresentMode::default(),
+            software_fallback: true,
+        }
+    }
+}
+
+impl GpuConfig {
+    fn backends(&self) -> wgpu::Backends {
+        if self.backends.is_empty() {
+            wgpu::Backends::all()
+        } else {
+            self.backends.iter().fold(wgpu::Backends::empty(), |backends, backend| backends | backend.flag())
+        }
+    }
+
+    pub fn create_instance(&self) -> wgpu::Instance {
+        wgpu::Instance::new(wgpu::InstanceDescriptor {
+            backends: self.backends(),
+            ..Default::default()
+        })
+    }
+
+    /// The adapter named in the config, or the one wgpu prefers, able to
+    /// present to `surface` if there is one. With `software_first`, a
+    /// software adapter is tried before the others.
+    pub async fn select_adapter(
+        &self,
+        instance: &wgpu::Instance,
+        surface: Option<&wgpu::Surface>,
+        software_first: bool,
+    ) -> Result<wgpu::Adapter> {
+        let compatible = |adapter: &wgpu::Adapter| surface.map_or(true, |surface| adapter.is_surface_supported(surface));
+        if let Some(name) = &self.adapter {
+            let name = name.to_lowercase();
+            return instance.enumerate_adapters(self.backends())
+                .filter(compatible)
+                .find(|adapter| adapter.get_info().name.to_lowercase().contains(&name))
+                .with_context(|| format!(
+                    "No adapter named {:?}{}", name, self.diagnostics(instance, surface)
+                ));
+        }
+
+        let power_preference = match self.power_preference {
+            PowerPreference::LowPower => wgpu::PowerPreference::LowPower,
+            PowerPreference::HighPerformance => wgpu::PowerPreference::HighPerformance,
+        };
+        let options = |force_fallback_adapter| wgpu::RequestAdapterOptions {
+            power_preference,
+            compatible_surface: surface,
+            force_fallback_adapter,
+        };
+        let mut attempts = vec![false];
+        if software_first {
+            attempts.insert(0, true);
+        } else if self.software_fallback {
+            attempts.push(true);
+        }
+        for force_fallback_adapter in attempts {
+            if let Some(adapter) = instance.request_adapter(&options(force_fallback_adapter)).await {
+                if force_fallback_adapter {
+                    log::warn!("Rendering on the software adapter {}", describe(&adapter.get_info()));
+                }
+                return Ok(adapter);
+            }
+        }
+        bail!("No usable graphics adapter{}", self.diagnostics(instance, surface))
+    }
+
+    /// Every adapter of the allowed backends and why it can't be used
+    fn diagnostics(&self, instance: &wgpu::Instance, surface: Option<&wgpu::Surface>) -> String {
+        let adapters: Vec<String> = instance.enumerate_adapters(self.backends())
+            .map(|adapter| {
+                let supported = surface.map_or(true, |surface| adapter.is_surface_supported(surface));
+                let description = describe(&adapter.get_info());
+                if supported { description } else { format!("{}, can't present to the window", description) }
+            })
+            .collect();
+        if adapters.is_empty() {
+            format!(", no adapter found for the backends {:?}", self.backends())
+        } else {
+            format!(", the adapters are:\n  {}", adapters.join("\n  "))
+        }
+    }
+
+    /// The configured present mode, or Fifo which is always supported
+    pub fn present_mode(&self, available: &[wgpu::PresentMode]) -> wgpu::PresentMode {
+        let mode = self.present_mode.mode();
+        if available.contains(&mode) {
+            mode
+        } else {
+            log::warn!("{:?} isn't supported by the surface, using Fifo", self.present_mode);
+            wgpu::PresentMode::Fifo
+        }
+    }
+}
+
+pub fn describe(info: &wgpu::AdapterInfo) -> String {
+    format!("{} ({:?}, {:?}, {} {})", info.name, info.backend, info.device_type, info.driver, info.driver_info)
+}
+
+pub async fn request_device(adapter: &wgpu::Adapter) -> Result<(wgpu::Device, wgpu::Queue)> {
+    adapter.request_device(
+        &wgpu::DeviceDescriptor {
+            features: wgpu::Features::empty(),
+            // WebGL doesn't support all of wgpu's features, so if
+            // we're building for the web, we'll have to disable some.
+            limits: if cfg!(target_arch = "wasm32") {
+                wgpu::Limits::downlevel_webgl2_defaults()
+            } else {
+                // the software and GL adapters don't reach the defaults,
+                // only textures as big as the adapter allows are needed
+                wgpu::Limits::downlevel_defaults().using_resolution(adapter.limits())
+            },
+            label: None,
+        },
+        None, // Trace path
+    ).await.with_context(|| format!("Failed to open the device of {}", describe(&adapter.get_info())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backends_and_present_mode() {
+        let config: GpuConfig = ron::from_str("(backends: [Vulkan, Gl], present_mode: Mailbox)").unwrap();
+        assert_eq!(config.backends(), wgpu::Backends::VULKAN | wgpu::Backends::GL);
+        assert_eq!(config.power_preference, PowerPreference::LowPower);
+        assert!(config.software_fallback);
+        assert_eq!(GpuConfig::default().backends(), wgpu::Backends::all());
+
+        let modes = [wgpu::PresentMode::Fifo, wgpu::PresentMode::Mailbox];
+        assert_eq!(config.present_mode(&modes), wgpu::PresentMode::Mailbox);
+        assert_eq!(config.present_mode(&modes[..1]), wgpu::PresentMode::Fifo);
+    }
+}
@@ -0,0 +1,254 @@
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// A client sending nothing is dropped after this, so it doesn't block the others
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub const COMMANDS: &str = "\
+    wallpaper <path>                    show an image, animation or video until the next slide
+    next, previous                      skip to another wallpaper of the slideshow
+    pause, resume                       stop drawing and capturing audio
+    shader <name> <value>               set a parameter of the config read by the shaders
+    dsp <node> <parameter> <value>      set a parameter of a node of the DSP patch
+    patch <path>                        save the DSP patch with its current parameters
+    status                              wallpaper, size, FPS, audio latency and whether it is paused
+    screenshot <path>                   save the next frame
+    reload                              load the config again";
+
+/// $XDG_RUNTIME_DIR/wallfuck.sock, falling back to the temporary directory
+pub fn socket_path() -> PathBuf {
+    env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(env::temp_dir)
+        .join("wallfuck.sock")
+}
+
+/// A request of the control socket, one per line.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    SetWallpaper(PathBuf),
+    NextSlide,
+    PreviousSlide,
+    Pause,
+    Resume,
+    SetShaderParameter { name: String, value: f32 },
+    SetDspParameter { node: String, parameter: String, value: f64 },
+    SavePatch(PathBuf),
+    Status,
+    Screenshot(PathBuf),
+    ReloadConfig,
+}
+
+fn number<T: std::str::FromStr>(word: &str) -> Result<T> {
+    word.parse().ok().with_context(|| format!("Invalid number {}", word))
+}
+
+impl Command {
+    /// Words separated by spaces, paths being the rest of the line
+    pub fn parse(line: &str) -> Result<Self> {
+        let line = line.trim();
+        let (verb, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let rest = rest.trim();
+        let arguments: Vec<&str> = rest.split_whitespace().collect();
+        let path = || match rest {
+            "" => Err(anyhow!("{} needs a path", verb)),
+            path => Ok(PathBuf::from(path)),
+        };
+        let command = match (verb, arguments.as_slice()) {
+            ("wallpaper", _) => Command::SetWallpaper(path()?),
+            ("next", []) => Command::NextSlide,
+            ("previous", []) => Command::PreviousSlide,
+            ("pause", []) => Command::Pause,
+            ("resume", []) => Command::Resume,
+            ("shader", [name, value]) => Command::SetShaderParameter {
+                name: name.to_string(),
+                value: number(value)?,
+            },
+            ("dsp", [node, parameter, value]) => Command::SetDspParameter {
+                node: node.to_string(),
+                parameter: parameter.to_string(),
+                value: number(value)?,
+            },
+            ("patch", _) => Command::SavePatch(path()?),
+            ("status", []) => Command::Status,
+            ("screenshot", _) => Command::Screenshot(path()?),
+            ("reload", []) => Command::ReloadConfig,
+            _ => bail!("Invalid command {:?}, expected one of:\n{}", line, COMMANDS),
+        };
+        Ok(command)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Status {
+    pub wallpaper: PathBuf,
+    pub width: u32,
+    pub height: u32,
+    /// Frames drawn in the last second, 0 when nothing moves
+    pub fps: usize,
+    pub paused: bool,
+    pub slideshow: bool,
+    pub audio: bool,
+    /// Seconds between the capture of the audio and its analysis
+    pub audio_latency: Option<f64>,
+}
+
+/// Answer to a command, a line of JSON.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<Status>,
+}
+
+impl From<Result<Option<Status>>> for Response {
+    fn from(result: Result<Option<Status>>) -> Self {
+        match result {
+            Ok(status) => Response { ok: true, error: None, status },
+            Err(error) => Response { ok: false, error: Some(format!("{:#}", error)), status: None },
+        }
+    }
+}
+
+/// A command waiting for the render loop to answer it
+#[derive(Debug)]
+pub struct Request {
+    pub command: Command,
+    reply: mpsc::Sender<Response>,
+}
+
+impl Request {
+    pub fn reply(self, result: Result<Option<Status>>) {
+        // the client may be gone
+        let _ = self.reply.send(result.into());
+    }
+}
+
+fn serve(stream: UnixStream, send: &impl Fn(Request) -> bool) -> Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match Command::parse(&line) {
+            Ok(command) => {
+                let (reply, receiver) = mpsc::channel();
+                if !send(Request { command, reply }) {
+                    bail!("The render loop is gone");
+                }
+                receiver.recv().context("The render loop dropped the request")?
+            },
+            Err(error) => Err(error).into(),
+        };
+        writeln!(writer, "{}", serde_json::to_string(&response)?)?;
+    }
+    Ok(())
+}
+
+/// Listens on the control socket on a thread, the socket file is removed
+/// once dropped.
+pub struct Server {
+    path: PathBuf,
+}
+
+impl Server {
+    /// `send` hands the requests to the render loop, false once it is gone.
+    /// A socket left by an instance which crashed is replaced, not the one
+    /// of a running instance.
+    pub fn start(path: &Path, send: impl Fn(Request) -> bool + Send + 'static) -> Result<Self> {
+        if path.exists() {
+            if UnixStream::connect(path).is_ok() {
+                bail!("Another instance is listening on {}", path.display());
+            }
+            fs::remove_file(path).with_context(|| format!("Failed to remove {}", path.display()))?;
+        }
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("Failed to listen on {}", path.display()))?;
+        thread::spawn(move || {
+            // one client at a time, the commands are quick
+            for stream in listener.incoming() {
+                let result = stream.map_err(Into::into).and_then(|stream| serve(stream, &send));
+                if let Err(error) = result {
+                    log::warn!("Control socket: {:#}", error);
+                }
+            }
+        });
+        Ok(Self { path: path.to_path_buf() })
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Sends a command to the instance listening on `path`
+pub fn send(path: &Path, command: &str) -> Result<Response> {
+    let mut stream = UnixStream::connect(path)
+        .with_context(|| format!("Failed to connect to {}, is wallfuck running?", path.display()))?;
+    writeln!(stream, "{}", command)?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    serde_json::from_str(&line).with_context(|| format!("Invalid response {:?}", line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(Command::parse("wallpaper /a b.png").unwrap(), Command::SetWallpaper(PathBuf::from("/a b.png")));
+        assert_eq!(Command::parse(" next\n").unwrap(), Command::NextSlide);
+        assert_eq!(Command::parse("shader speed 0.5").unwrap(),
+            Command::SetShaderParameter { name: "speed".to_string(), value: 0.5 });
+        assert_eq!(Command::parse("dsp lpf cut_off 200").unwrap(),
+            Command::SetDspParameter { node: "lpf".to_string(), parameter: "cut_off".to_string(), value: 200. });
+        assert!(Command::parse("shader speed fast").is_err());
+        assert_eq!(Command::parse("patch /tmp/live.ron").unwrap(), Command::SavePatch(PathBuf::from("/tmp/live.ron")));
+        assert!(Command::parse("screenshot").is_err());
+        assert!(Command::parse("pause now").is_err());
+    }
+
+    #[test]
+    fn socket_round_trip() {
+        let path = env::temp_dir().join(format!("wallfuck-ipc-{}.sock", std::process::id()));
+        let (sender, receiver) = mpsc::channel();
+        let server = Server::start(&path, move |request| sender.send(request).is_ok()).unwrap();
+        // the render loop
+        thread::spawn(move || {
+            for request in receiver {
+                let result = match request.command {
+                    Command::Status => Ok(Some(Status { fps: 30, ..Default::default() })),
+                    _ => Err(anyhow!("Not now")),
+                };
+                request.reply(result);
+            }
+        });
+
+        let status = send(&path, "status").unwrap();
+        assert!(status.ok);
+        assert_eq!(status.status.unwrap().fps, 30);
+        let refused = send(&path, "pause").unwrap();
+        assert_eq!(refused.error.as_deref(), Some("Not now"));
+        assert!(!send(&path, "jump").unwrap().ok);
+        // a second instance can't take the socket
+        assert!(Server::start(&path, |_| true).is_err());
+        drop(server);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,627 @@
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::audio::AudioFeatures;
+use crate::camera;
+use crate::decode;
+use crate::mpris::{self, NowPlaying};
+use crate::particles::{ParticleSystem, ParticleSystemDescription};
+use crate::render_graph::{self, TARGET_FORMAT};
+use crate::text::{TextDescription, TextRenderer};
+use crate::texture::{MipmapGenerator, Texture};
+use crate::visualiser::{VisualiserDescription, VisualiserRenderer};
+
+const LAYER_SHADER: &str = include_str!("shaders/layer.wgsl");
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum LayerSource {
+    /// Image filling the height of the window like the wallpaper
+    Image(PathBuf),
+    /// Fragment shader drawn over the whole window, with the same prelude as
+    /// the passes of the render graph but no inputs
+    Shader(PathBuf),
+    Visualiser(VisualiserDescription),
+    Particles(ParticleSystemDescription),
+    /// Text drawn with a font, formatted with the local time so it can be a clock
+    Text(TextDescription),
+    /// Album art of the active MPRIS player, like an image layer
+    AlbumArt,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum BlendMode {
+    #[default]
+    Normal,
+    Add,
+    Multiply,
+    Screen,
+}
+
+impl BlendMode {
+    const ALL: [BlendMode; 4] = [BlendMode::Normal, BlendMode::Add, BlendMode::Multiply, BlendMode::Screen];
+
+    /// The layer shader outputs premultiplied alpha
+    fn state(self) -> wgpu::BlendState {
+        let (src_factor, dst_factor) = match self {
+            BlendMode::Normal => (wgpu::BlendFactor::One, wgpu::BlendFactor::OneMinusSrcAlpha),
+            BlendMode::Add => (wgpu::BlendFactor::One, wgpu::BlendFactor::One),
+            // dst * (src + 1 - alpha), 1 where the layer is transparent
+            BlendMode::Multiply => (wgpu::BlendFactor::Dst, wgpu::BlendFactor::OneMinusSrcAlpha),
+            // 1 - (1 - dst) * (1 - src)
+            BlendMode::Screen => (wgpu::BlendFactor::One, wgpu::BlendFactor::OneMinusSrc),
+        };
+        wgpu::BlendState {
+            color: wgpu::BlendComponent { src_factor, dst_factor, operation: wgpu::BlendOperation::Add },
+            alpha: wgpu::BlendComponent::OVER,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Transform {
+    /// Centre of the layer, from (0, 0) at the top left of the window to (1, 1)
+    pub position: [f32; 2],
+    pub scale: [f32; 2],
+    /// Degrees, counter-clockwise
+    pub rotation: f32,
+}
+impl Default for Transform {
+    fn default() -> Self {
+        Self {
+            position: [0.5, 0.5],
+            scale: [1., 1.],
+            rotation: 0.,
+        }
+    }
+}
+
+/// A layer as written in the config, drawn over the wallpaper and the
+/// layers before it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LayerDescription {
+    pub source: LayerSource,
+    #[serde(default)]
+    pub transform: Transform,
+    #[serde(default = "default_opacity")]
+    pub opacity: f32,
+    #[serde(default)]
+    pub blend: BlendMode,
+    /// Greyscale image stretched over the layer, black hides it
+    #[serde(default)]
+    pub mask: Option<PathBuf>,
+    /// Distance in front of the wallpaper, the wallpaper being 2 units high.
+    /// Layers keep their size but move more with the camera when closer.
+    #[serde(default)]
+    pub depth: f32,
+}
+fn default_opacity() -> f32 {
+    1.
+}
+impl LayerDescription {
+    /// Files the layer is loaded from, to watch for changes
+    pub fn files(&self) -> impl Iterator<Item = &Path> {
+        let source = match &self.source {
+            LayerSource::Image(path) | LayerSource::Shader(path) => Some(path.as_path()),
+            LayerSource::Particles(particles) => particles.atlas.as_deref(),
+            LayerSource::Text(text) => Some(text.font.as_path()),
+            LayerSource::Visualiser(_) | LayerSource::AlbumArt => None,
+        };
+        source.into_iter().chain(self.mask.as_deref())
+    }
+
+    /// Whether the layer shows what the MPRIS players play
+    pub fn uses_now_playing(&self) -> bool {
+        match &self.source {
+            LayerSource::Text(text) => mpris::has_placeholders(&text.text),
+            LayerSource::AlbumArt => true,
+            _ => false,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct LayerUniform {
+    x_axis: [f32; 2],
+    y_axis: [f32; 2],
+    centre: [f32; 2],
+    opacity: f32,
+    premultiplied: u32,
+    depth: f32,
+    _padding: u32,
+}
+
+/// Half axes and centre of the quad in clip space, `half_size` being half
+/// of its untransformed size in pixels
+fn quad(transform: &Transform, half_size: [f32; 2], size: winit::dpi::PhysicalSize<u32>) -> [[f32; 2]; 3] {
+    let (width, height) = (size.width as f32, size.height as f32);
+    let (sin, cos) = transform.rotation.to_radians().sin_cos();
+    // rotated in pixels so the layer isn't sheared by the aspect ratio
+    let half_x = half_size[0] * transform.scale[0];
+    let half_y = half_size[1] * transform.scale[1];
+    let to_clip = |x: f32, y: f32| [x * 2. / width, y * 2. / height];
+    [
+        to_clip(cos * half_x, sin * half_x),
+        to_clip(-sin * half_y, cos * half_y),
+        [transform.position[0] * 2. - 1., 1. - transform.position[1] * 2.],
+    ]
+}
+
+enum Content {
+    Image,
+    /// Local file of the art shown, a transparent texel without one
+    AlbumArt(Option<PathBuf>),
+    Shader(wgpu::RenderPipeline),
+    Visualiser(VisualiserRenderer),
+    Particles(Box<ParticleSystem>),
+    Text(Box<TextRenderer>),
+}
+
+struct Layer {
+    content: Content,
+    description: LayerDescription,
+    /// The image, or where the other layers are drawn
+    texture: Texture,
+    mask: Texture,
+    uniform_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+}
+
+impl Layer {
+    fn uniform(&self, size: winit::dpi::PhysicalSize<u32>) -> LayerUniform {
+        let half_size = match self.content {
+            Content::Image | Content::AlbumArt(_) => {
+                let (width, height) = self.texture.dimensions;
+                let half_height = size.height as f32 / 2.;
+                [half_height * width as f32 / height as f32, half_height]
+            },
+            _ => [size.width as f32 / 2., size.height as f32 / 2.],
+        };
+        // from clip space to the world, scaled down with the distance to the
+        // camera so the layer looks the same size as on the wallpaper plane
+        let distance = camera::plane_distance();
+        let depth = self.description.depth.min(distance * 0.9);
+        let scale = (distance - depth) / distance;
+        let aspect = size.width as f32 / size.height as f32;
+        let [x_axis, y_axis, centre] = quad(&self.description.transform, half_size, size)
+            .map(|[x, y]| [x * aspect * scale, y * scale]);
+        LayerUniform {
+            x_axis,
+            y_axis,
+            centre,
+            opacity: self.description.opacity,
+            // the visualisers, the particles and the text blend over a transparent target
+            premultiplied: matches!(self.content, Content::Visualiser(_) | Content::Particles(_) | Content::Text(_)) as u32,
+            depth,
+            _padding: 0,
+        }
+    }
+}
+
+/// Draws the layers of the config in order over the scene, all but the
+/// image layers being first rendered in a texture of their own.
+pub struct Compositor {
+    layers: Vec<Layer>,
+    pipelines: Vec<wgpu::RenderPipeline>,
+    bind_group_layout: wgpu::BindGroupLayout,
+    // shader layers have no inputs
+    empty_bind_group_layout: wgpu::BindGroupLayout,
+    empty_bind_group: wgpu::BindGroup,
+    size: winit::dpi::PhysicalSize<u32>,
+}
+
+impl Compositor {
+    /// Layers which can't be loaded are left out. `shared_layouts` are the
+    /// layouts of the bind groups 1 to 3 of the shader layers, like for the
+    /// render graph.
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        mipmap_generator: &MipmapGenerator,
+        descriptions: &[LayerDescription],
+        shared_layouts: &[&wgpu::BindGroupLayout; 3],
+        format: wgpu::TextureFormat,
+        size: winit::dpi::PhysicalSize<u32>,
+    ) -> Self {
+        let texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                multisampled: false,
+                view_dimension: wgpu::TextureViewDimension::D2,
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+            },
+            count: None,
+        };
+        let sampler_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+            count: None,
+        };
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                texture_entry(1),
+                sampler_entry(2),
+                texture_entry(3),
+                sampler_entry(4),
+            ],
+            label: Some("layer_bind_group_layout"),
+        });
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Layer Shader"),
+            source: wgpu::ShaderSource::Wgsl(LAYER_SHADER.into()),
+        });
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Layer Pipeline Layout"),
+            bind_group_layouts: &[&bind_group_layout, shared_layouts[0]],
+            push_constant_ranges: &[],
+        });
+        // one per blend mode, in the order of BlendMode::ALL
+        let pipelines = BlendMode::ALL.iter().map(|blend| {
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some("Layer Pipeline"),
+                layout: Some(&layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: "vs_main",
+                    buffers: &[],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point: "fs_main",
+                    targets: &[Some(wgpu::ColorTargetState {
+                        format,
+                        blend: Some(blend.state()),
+                        write_mask: wgpu::ColorWrites::ALL,
+                    })],
+                }),
+                primitive: wgpu::PrimitiveState::default(),
+                depth_stencil: None,
+                multisample: wgpu::MultisampleState::default(),
+                multiview: None,
+            })
+        }).collect();
+
+        let empty_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[],
+            label: Some("empty_bind_group_layout"),
+        });
+        let empty_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &empty_bind_group_layout,
+            entries: &[],
+            label: Some("empty_bind_group"),
+        });
+
+        let mut compositor = Self {
+            layers: Vec::with_capacity(descriptions.len()),
+            pipelines,
+            bind_group_layout,
+            empty_bind_group_layout,
+            empty_bind_group,
+            size,
+        };
+        for (i, description) in descriptions.iter().enumerate() {
+            match compositor.create_layer(device, queue, mipmap_generator, description, shared_layouts) {
+                Ok(layer) => compositor.layers.push(layer),
+                Err(error) => log::error!("{:#}, skipping the layer #{}", error, i),
+            }
+        }
+        compositor
+    }
+
+    fn create_layer(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        mipmap_generator: &MipmapGenerator,
+        description: &LayerDescription,
+        shared_layouts: &[&wgpu::BindGroupLayout; 3],
+    ) -> Result<Layer> {
+        let (content, texture) = match &description.source {
+            LayerSource::Image(path) => (Content::Image, load_image(device, queue, mipmap_generator, path)?),
+            LayerSource::AlbumArt => (Content::AlbumArt(None), transparent(device, queue)),
+            LayerSource::Shader(path) => {
+                let pipeline = render_graph::create_pass_pipeline(
+                    device, path, 0, &self.empty_bind_group_layout, shared_layouts, TARGET_FORMAT
+                )?;
+                (Content::Shader(pipeline), self.create_target(device))
+            },
+            LayerSource::Visualiser(visualiser) => {
+                let renderer = VisualiserRenderer::new(device, std::slice::from_ref(visualiser), TARGET_FORMAT, self.size);
+                (Content::Visualiser(renderer), self.create_target(device))
+            },
+            LayerSource::Particles(particles) => {
+                let system = ParticleSystem::new(device, queue, mipmap_generator, particles, TARGET_FORMAT)?;
+                (Content::Particles(Box::new(system)), self.create_target(device))
+            },
+            LayerSource::Text(text) => {
+                let renderer = TextRenderer::new(device, queue, text, TARGET_FORMAT)?;
+                (Content::Text(Box::new(renderer)), self.create_target(device))
+            },
+        };
+        let mask = match &description.mask {
+            Some(path) => load_image(device, queue, mipmap_generator, path)?,
+            None => {
+                let white = Texture::new(device, (1, 1), Texture::FORMAT, false, Some("layer-mask"));
+                white.write(queue, &image::RgbaImage::from_pixel(1, 1, image::Rgba([255; 4])));
+                white
+            },
+        };
+        let uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Layer Buffer"),
+            size: std::mem::size_of::<LayerUniform>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let bind_group = self.create_bind_group(device, &uniform_buffer, &texture, &mask);
+        let layer = Layer {
+            content,
+            description: description.clone(),
+            texture,
+            mask,
+            uniform_buffer,
+            bind_group,
+        };
+        queue.write_buffer(&layer.uniform_buffer, 0, bytemuck::cast_slice(&[layer.uniform(self.size)]));
+        Ok(layer)
+    }
+
+    fn create_target(&self, device: &wgpu::Device) -> Texture {
+        Texture::new(device, (self.size.width, self.size.height), TARGET_FORMAT, false, Some("layer-target"))
+    }
+
+    fn create_bind_group(
+        &self,
+        device: &wgpu::Device,
+        uniform_buffer: &wgpu::Buffer,
+        texture: &Texture,
+        mask: &Texture,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(&texture.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::TextureView(&mask.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: wgpu::BindingResource::Sampler(&mask.sampler),
+                },
+            ],
+            label: Some("layer_bind_group"),
+        })
+    }
+
+    /// Reloads the layers using this file, a layer is left untouched if its
+    /// new version can't be loaded.
+    pub fn reload(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        mipmap_generator: &MipmapGenerator,
+        path: &Path,
+        shared_layouts: &[&wgpu::BindGroupLayout; 3],
+    ) -> Result<()> {
+        for i in 0..self.layers.len() {
+            if self.layers[i].description.files().any(|file| file == path) {
+                let description = self.layers[i].description.clone();
+                self.layers[i] = self.create_layer(device, queue, mipmap_generator, &description, shared_layouts)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn resize(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, size: winit::dpi::PhysicalSize<u32>) {
+        self.size = size;
+        for i in 0..self.layers.len() {
+            if !matches!(self.layers[i].content, Content::Image | Content::AlbumArt(_)) {
+                let texture = self.create_target(device);
+                let layer = &self.layers[i];
+                let bind_group = self.create_bind_group(device, &layer.uniform_buffer, &texture, &layer.mask);
+                let layer = &mut self.layers[i];
+                layer.texture = texture;
+                layer.bind_group = bind_group;
+            }
+            let layer = &mut self.layers[i];
+            if let Content::Visualiser(renderer) = &mut layer.content {
+                renderer.resize(size);
+            }
+            queue.write_buffer(&layer.uniform_buffer, 0, bytemuck::cast_slice(&[layer.uniform(size)]));
+        }
+    }
+
+    /// Whether some layers change from frame to frame, the text only changes
+    /// from time to time
+    pub fn is_animated(&self) -> bool {
+        self.layers.iter().any(|layer| !matches!(layer.content, Content::Image | Content::AlbumArt(_) | Content::Text(_)))
+    }
+
+    /// True if some layers have to be drawn again
+    pub fn update(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        mipmap_generator: &MipmapGenerator,
+        audio: Option<&AudioFeatures>,
+        now_playing: Option<&NowPlaying>,
+    ) -> bool {
+        let mut changed = false;
+        for i in 0..self.layers.len() {
+            if let Content::AlbumArt(shown) = &self.layers[i].content {
+                let art = now_playing.and_then(NowPlaying::art_path);
+                if art != *shown {
+                    self.show_album_art(device, queue, mipmap_generator, i, art);
+                    changed = true;
+                }
+                continue;
+            }
+            changed |= match &mut self.layers[i].content {
+                Content::Visualiser(renderer) => {
+                    renderer.update(queue, audio);
+                    true
+                },
+                Content::Particles(system) => {
+                    system.update(queue, self.size, audio);
+                    true
+                },
+                Content::Text(renderer) => renderer.update(queue, self.size, now_playing),
+                Content::Shader(_) => true,
+                Content::Image | Content::AlbumArt(_) => false,
+            };
+        }
+        changed
+    }
+
+    /// Replaces the image of an album art layer, left transparent if the art
+    /// can't be loaded
+    fn show_album_art(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        mipmap_generator: &MipmapGenerator,
+        i: usize,
+        art: Option<PathBuf>,
+    ) {
+        let texture = match art.as_deref().map(|path| load_image(device, queue, mipmap_generator, path)) {
+            Some(Ok(texture)) => texture,
+            Some(Err(error)) => {
+                log::error!("{:#}", error);
+                transparent(device, queue)
+            },
+            None => transparent(device, queue),
+        };
+        let layer = &self.layers[i];
+        let bind_group = self.create_bind_group(device, &layer.uniform_buffer, &texture, &layer.mask);
+        let layer = &mut self.layers[i];
+        layer.texture = texture;
+        layer.bind_group = bind_group;
+        layer.content = Content::AlbumArt(art);
+        queue.write_buffer(&layer.uniform_buffer, 0, bytemuck::cast_slice(&[layer.uniform(self.size)]));
+    }
+
+    /// Renders the shader, visualiser, particle and text layers, before the scene
+    /// render pass. `shared_bind_groups` match the `shared_layouts` given to
+    /// `new`.
+    pub fn prepare(&self, encoder: &mut wgpu::CommandEncoder, shared_bind_groups: &[&wgpu::BindGroup; 3]) {
+        for layer in &self.layers {
+            match &layer.content {
+                Content::Image | Content::AlbumArt(_) => continue,
+                Content::Particles(system) => system.simulate(encoder),
+                Content::Shader(_) | Content::Visualiser(_) | Content::Text(_) => (),
+            }
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Layer Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &layer.texture.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                occlusion_query_set: None,
+                timestamp_writes: None,
+            });
+            match &layer.content {
+                Content::Shader(pipeline) => {
+                    render_pass.set_pipeline(pipeline);
+                    render_pass.set_bind_group(0, &self.empty_bind_group, &[]);
+                    for (i, bind_group) in shared_bind_groups.iter().enumerate() {
+                        render_pass.set_bind_group(i as u32 + 1, bind_group, &[]);
+                    }
+                    render_pass.draw(0..3, 0..1);
+                },
+                Content::Visualiser(renderer) => renderer.draw(&mut render_pass),
+                Content::Particles(system) => system.draw(&mut render_pass),
+                Content::Text(renderer) => renderer.draw(&mut render_pass),
+                Content::Image | Content::AlbumArt(_) => (),
+            }
+        }
+    }
+
+    /// Composites the layers in the scene render pass, seen from the camera
+    pub fn draw<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>, camera_bind_group: &'a wgpu::BindGroup) {
+        for layer in &self.layers {
+            let blend = BlendMode::ALL.iter().position(|&blend| blend == layer.description.blend).unwrap();
+            render_pass.set_pipeline(&self.pipelines[blend]);
+            render_pass.set_bind_group(0, &layer.bind_group, &[]);
+            render_pass.set_bind_group(1, camera_bind_group, &[]);
+            render_pass.draw(0..6, 0..1);
+        }
+    }
+}
+
+fn transparent(device: &wgpu::Device, queue: &wgpu::Queue) -> Texture {
+    let texture = Texture::new(device, (1, 1), Texture::FORMAT, false, Some("transparent"));
+    texture.write(queue, &image::RgbaImage::from_pixel(1, 1, image::Rgba([0; 4])));
+    texture
+}
+
+fn load_image(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    mipmap_generator: &MipmapGenerator,
+    path: &Path,
+) -> Result<Texture> {
+    let image = decode::open(path)?;
+    Texture::from_image(device, queue, mipmap_generator, &image, path.to_str())
+        .with_context(|| format!("Failed to load {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: [f32; 2], expected: [f32; 2]) {
+        assert!((actual[0] - expected[0]).abs() < 1e-6 && (actual[1] - expected[1]).abs() < 1e-6,
+            "{:?} != {:?}", actual, expected);
+    }
+
+    #[test]
+    fn layer_quad() {
+        let size = winit::dpi::PhysicalSize::new(200, 100);
+        // untransformed, the full window
+        let [x_axis, y_axis, centre] = quad(&Transform::default(), [100., 50.], size);
+        assert_close(x_axis, [1., 0.]);
+        assert_close(y_axis, [0., 1.]);
+        assert_close(centre, [0., 0.]);
+
+        // a quarter turn keeps the size in pixels, in the top left corner
+        let transform = Transform { position: [0.25, 0.25], scale: [0.5, 1.], rotation: 90. };
+        let [x_axis, y_axis, centre] = quad(&transform, [50., 50.], size);
+        assert_close(x_axis, [0., 0.5]);
+        assert_close(y_axis, [-0.5, 0.]);
+        assert_close(centre, [-0.5, 0.5]);
+
+        let description: LayerDescription = ron::from_str("(source: Image(\"a.png\"), blend: Screen)").unwrap();
+        assert_eq!(description.opacity, 1.);
+        assert_eq!(description.transform.scale, [1., 1.]);
+    }
+}
@@ -1,11 +1,35 @@
 use winit::{
     event::*,
-    event_loop::{ControlFlow, EventLoop},
+    event_loop::{ControlFlow, EventLoopBuilder},
     window::{WindowBuilder, Window},
 };
 use wgpu::util::DeviceExt;
+use image::GenericImageView;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use anyhow::{bail, Context, Result};
 mod texture;
+mod camera;
+mod decode;
+mod animation;
+mod video;
+mod slideshow;
+mod transition;
 mod audio;
+mod config;
+mod globals;
+mod gpu;
+mod render_graph;
+mod watcher;
+mod scheduler;
+mod visualiser;
+mod layers;
+mod particles;
+mod text;
+mod mpris;
+mod ipc;
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
@@ -35,8 +59,7 @@ impl Vertex {
     }
 }
 
-const WALLPAPER_PATH: &'static str = "/etc/nixos/resources/wallpapers/space.jpg";
-
+const DEFAULT_SHADER: &str = include_str!("shader.wgsl");
 
 const INDICES: &[u16] = &[
     0, 1, 3,
@@ -47,8 +70,8 @@ const INDICES: &[u16] = &[
 pub const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
     1.0, 0.0, 0.0, 0.0,
     0.0, 1.0, 0.0, 0.0,
-    0.0, 0.0, 0.5, 0.5,
-    0.0, 0.0, 0.0, 1.0,
+    0.0, 0.0, 0.5, 0.0,
+    0.0, 0.0, 0.5, 1.0,
 );
 
 struct Camera {
@@ -89,69 +112,291 @@ impl CameraUniform {
 }
 
 
-struct State {
-    surface: wgpu::Surface,
-    device: wgpu::Device,
-    queue: wgpu::Queue,
-    config: wgpu::SurfaceConfiguration,
+/// Plane 2 units high centred on the origin
+fn plane(aspect: f32) -> [Vertex; 4] {
+    [
+        Vertex { position: [aspect, 1., 0.], tex_coords: [1., 0.] }, //A
+        Vertex { position: [-aspect, 1., 0.], tex_coords: [0., 0.] }, //B
+        Vertex { position: [-aspect, -1., 0.], tex_coords: [0., 1.] }, //C
+        Vertex { position: [aspect, -1., 0.], tex_coords: [1., 1.] }, //D
+    ]
+}
+
+fn create_texture_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    texture: &texture::Texture,
+    label: &str,
+) -> wgpu::BindGroup {
+    device.create_bind_group(
+        &wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&texture.sampler),
+                }
+            ],
+            label: Some(label),
+        }
+    )
+}
+
+/// Where the frames of the wallpaper texture come from
+enum WallpaperSource {
+    /// The original image is kept when it is pre-scaled, to scale it again
+    /// when the window is resized
+    Still { original: Option<image::DynamicImage> },
+    Animation(animation::Animation),
+    Video(Box<video::Video>),
+}
+
+/// Dimensions of a still wallpaper downscaled to the height of the window,
+/// None if it isn't bigger than the window
+fn prescaled_dimensions(dimensions: (u32, u32), size: winit::dpi::PhysicalSize<u32>) -> Option<(u32, u32)> {
+    if size.height == 0 || size.height >= dimensions.1 {
+        return None;
+    }
+    let width = (dimensions.0 as u64 * size.height as u64 / dimensions.1 as u64).max(1) as u32;
+    Some((width, size.height))
+}
+
+/// Lanczos downscale on the CPU, sharper than the mipmaps
+fn prescale(image: &image::DynamicImage, size: winit::dpi::PhysicalSize<u32>) -> Option<image::DynamicImage> {
+    prescaled_dimensions(image.dimensions(), size)
+        .map(|(width, height)| image.resize_exact(width, height, image::imageops::FilterType::Lanczos3))
+}
+
+/// Decodes a still image, or the first frame of an animation or a video.
+fn load_wallpaper(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    mipmap_generator: &texture::MipmapGenerator,
+    path: &Path,
+    app_config: &config::Config,
     size: winit::dpi::PhysicalSize<u32>,
+) -> Result<(texture::Texture, WallpaperSource)> {
+    if video::is_video(path) {
+        let video = video::Video::open(
+            device, queue, path, texture::Texture::FORMAT, app_config.playback_rate, app_config.loop_video
+        )?;
+        let texture = texture::Texture::new(
+            device, video.dimensions(), texture::Texture::FORMAT, true, Some("wallpaper-texture")
+        );
+        return Ok((texture, WallpaperSource::Video(Box::new(video))));
+    }
+
+    let mut animation = animation::Animation::open(path, app_config.sequence_frame_rate)
+        .with_context(|| format!("Failed to open wallpaper {}", path.display()))?;
+    let image = match &mut animation {
+        Some(animation) => image::DynamicImage::ImageRgba8(animation.first_frame()?.image),
+        None => decode::open(path).context("Failed to load wallpaper")?,
+    };
+    let (texture, source) = match animation {
+        Some(animation) => (
+            texture::Texture::from_image(device, queue, mipmap_generator, &image, Some("wallpaper-texture"))?,
+            WallpaperSource::Animation(animation),
+        ),
+        None if app_config.prescale => {
+            let scaled = prescale(&image, size);
+            let texture = texture::Texture::from_image(
+                device, queue, mipmap_generator, scaled.as_ref().unwrap_or(&image), Some("wallpaper-texture")
+            )?;
+            (texture, WallpaperSource::Still { original: Some(image) })
+        },
+        None => (
+            texture::Texture::from_image(device, queue, mipmap_generator, &image, Some("wallpaper-texture"))?,
+            WallpaperSource::Still { original: None },
+        ),
+    };
+    Ok((texture, source))
+}
+
+/// The slideshow of the config and the wallpaper shown first
+struct FirstWallpaper {
+    slideshow: Option<slideshow::Slideshow>,
+    path: PathBuf,
+    texture: texture::Texture,
+    source: WallpaperSource,
+}
+
+fn first_wallpaper(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    mipmap_generator: &texture::MipmapGenerator,
+    app_config: &config::Config,
+    size: winit::dpi::PhysicalSize<u32>,
+) -> Result<FirstWallpaper> {
+    let slideshow = app_config.slideshow.as_ref().and_then(|description| {
+        match slideshow::Slideshow::new(description) {
+            Ok(slideshow) => Some(slideshow),
+            Err(error) => {
+                log::error!("{:#}, showing the wallpaper instead of the slideshow", error);
+                None
+            },
+        }
+    });
+    let path = slideshow.as_ref()
+        .map_or(app_config.wallpaper.as_path(), |slideshow| slideshow.current())
+        .to_path_buf();
+    let (texture, source) = load_wallpaper(device, queue, mipmap_generator, &path, app_config, size)?;
+    Ok(FirstWallpaper { slideshow, path, texture, source })
+}
+
+/// Tonemapping and exposure only apply to high dynamic range wallpapers
+fn tonemap_settings(app_config: &config::Config, texture: &texture::Texture) -> (globals::Tonemap, f32) {
+    if texture.is_high_dynamic_range() {
+        (app_config.tonemap, app_config.exposure)
+    } else {
+        (globals::Tonemap::None, 0.)
+    }
+}
+
+fn create_render_pipeline(
+    device: &wgpu::Device,
+    render_pipeline_layout: &wgpu::PipelineLayout,
+    shader: &wgpu::ShaderModule,
+    format: wgpu::TextureFormat,
+) -> wgpu::RenderPipeline {
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("Render Pipeline"),
+        layout: Some(render_pipeline_layout),
+        vertex: wgpu::VertexState {
+            module: shader,
+            entry_point: "vs_main",
+            buffers: &[
+                Vertex::desc(),
+            ],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: shader,
+            entry_point: "fs_main",
+            targets: &[Some(wgpu::ColorTargetState {
+                format,
+                blend: Some(wgpu::BlendState::REPLACE),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: wgpu::FrontFace::Ccw,
+            cull_mode: Some(wgpu::Face::Back),
+            // Setting this to anything other than Fill requires Features::NON_FILL_POLYGON_MODE
+            polygon_mode: wgpu::PolygonMode::Fill,
+            // Requires Features::DEPTH_CLIP_CONTROL
+            unclipped_depth: false,
+            // Requires Features::CONSERVATIVE_RASTERIZATION
+            conservative: false,
+        },
+        depth_stencil: None,
+        multisample: wgpu::MultisampleState {
+            count: 1,
+            mask: !0,
+            alpha_to_coverage_enabled: false,
+        },
+        multiview: None,
+    })
+}
+
+/// Where the frames are drawn
+enum Target {
     // The window must be declared after the surface so
     // it gets dropped after it as the surface contains
     // unsafe references to the window's resources.
-    window: Window,
+    Window { surface: wgpu::Surface, window: Window },
+    /// Headless rendering, the frames are read back with `State::snapshot`
+    Offscreen(wgpu::Texture),
+}
+
+/// Format of the headless target, read back as is in an `RgbaImage`
+const OFFSCREEN_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+
+fn create_offscreen_texture(device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("offscreen-texture"),
+        size: wgpu::Extent3d {
+            width: config.width,
+            height: config.height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: config.format,
+        usage: config.usage,
+        view_formats: &[],
+    })
+}
+
+struct State {
+    target: Target,
+    // shared with the state replacing this one when the config is reloaded
+    device: Arc<wgpu::Device>,
+    queue: Arc<wgpu::Queue>,
+    config: wgpu::SurfaceConfiguration,
+    size: winit::dpi::PhysicalSize<u32>,
 
     vertex_buffer: wgpu::Buffer,
     num_vertices: u32,
     index_buffer: wgpu::Buffer,
     num_indices: u32,
     render_pipeline: wgpu::RenderPipeline,
+    render_pipeline_layout: wgpu::PipelineLayout,
+    texture_bind_group_layout: wgpu::BindGroupLayout,
     wallpaper_bind_group: wgpu::BindGroup,
     wallpaper_texture: texture::Texture,
+    // The frames of animations are written alternately in the wallpaper
+    // texture and this one, so a frame is never written while it is shown
+    wallpaper_back: Option<(texture::Texture, wgpu::BindGroup)>,
+    wallpaper_source: WallpaperSource,
+    /// File of the wallpaper shown
+    wallpaper_path: PathBuf,
+    app_config: config::Config,
+    slideshow: Option<slideshow::Slideshow>,
+    transition_renderer: Option<transition::TransitionRenderer>,
+    // previous wallpaper and when the transition from it started
+    transition_from: Option<(texture::Texture, Instant)>,
+    // transitions are drawn to the size of the window
+    fullscreen_vertex_buffer: wgpu::Buffer,
+    // for the frames of animations and videos
+    mipmap_generator: Arc<texture::MipmapGenerator>,
     camera: Camera,
+    camera_motion: camera::CameraMotion,
+    camera_buffer: wgpu::Buffer,
     camera_bind_group: wgpu::BindGroup,
+    camera_bind_group_layout: wgpu::BindGroupLayout,
     vertices: [Vertex; 4],
+    globals: globals::Globals,
+    render_graph: Option<render_graph::RenderGraph>,
+    layers: Option<layers::Compositor>,
+    visualisers: Option<visualiser::VisualiserRenderer>,
+    /// Only when a layer shows what is playing
+    player_monitor: Option<mpris::PlayerMonitor>,
 }
 
 impl State {
     // Creating some of the wgpu types requires async code
-    async fn new(window: Window) -> Self {
+    async fn new(window: Window, app_config: &config::Config) -> Result<Self> {
         let size = window.inner_size();
 
         // The instance is a handle to our GPU
-        // Backends::all => Vulkan + Metal + DX12 + Browser WebGPU
-        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
-            backends: wgpu::Backends::all(),
-            ..Default::default()
-        });
+        let instance = app_config.gpu.create_instance();
         
         // # Safety
         //
         // The surface needs to live as long as the window that created it.
         // State owns the window, so this should be safe.
-        let surface = unsafe { instance.create_surface(&window) }.unwrap();
+        let surface = unsafe { instance.create_surface(&window) }
+            .context("Failed to create the surface of the window")?;
 
-        let adapter = instance.request_adapter(
-            &wgpu::RequestAdapterOptions {
-                power_preference: wgpu::PowerPreference::HighPerformance,
-                compatible_surface: Some(&surface),
-                force_fallback_adapter: false,
-            },
-        ).await.unwrap();
-
-        let (device, queue) = adapter.request_device(
-            &wgpu::DeviceDescriptor {
-                features: wgpu::Features::empty(),
-                // WebGL doesn't support all of wgpu's features, so if
-                // we're building for the web, we'll have to disable some.
-                limits: if cfg!(target_arch = "wasm32") {
-                    wgpu::Limits::downlevel_webgl2_defaults()
-                } else {
-                    wgpu::Limits::default()
-                },
-                label: None,
-            },
-            None, // Trace path
-        ).await.unwrap();
+        let adapter = app_config.gpu.select_adapter(&instance, Some(&surface), false).await?;
+        log::info!("Rendering with {}", gpu::describe(&adapter.get_info()));
+        let (device, queue) = gpu::request_device(&adapter).await?;
 
         let surface_caps = surface.get_capabilities(&adapter);
         // Shader code in this tutorial assumes an sRGB surface texture. Using a different
@@ -161,22 +406,75 @@ impl State {
             .copied()
             .filter(|f| f.is_srgb())
             .next()
-            .unwrap_or(surface_caps.formats[0]);
+            .or(surface_caps.formats.first().copied())
+            .context("The window can't be drawn to by the adapter")?;
         let config = wgpu::SurfaceConfiguration {
             usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
             format: surface_format,
             width: size.width,
             height: size.height,
-            present_mode: surface_caps.present_modes[0],
+            present_mode: app_config.gpu.present_mode(&surface_caps.present_modes),
             alpha_mode: surface_caps.alpha_modes[0],
             view_formats: vec![],
         };
         surface.configure(&device, &config);
-        
-        let wallpaper_bytes = include_bytes!("/etc/nixos/resources/wallpapers/space.jpg");
-        let wallpaper_texture = texture::Texture::from_bytes(
-            &device, &queue, wallpaper_bytes, "wallpaper-texture"
-        ).unwrap();
+
+        let mipmap_generator = Arc::new(texture::MipmapGenerator::new(&device));
+        let first_wallpaper = first_wallpaper(&device, &queue, &mipmap_generator, app_config, size)?;
+        let target = Target::Window { surface, window };
+        Ok(Self::with_target(
+            Arc::new(device), Arc::new(queue), mipmap_generator, config, target, first_wallpaper, app_config
+        ))
+    }
+
+    /// Renders in a texture instead of a window, on a software adapter when
+    /// there is one so the frames don't depend on the GPU.
+    async fn new_headless(app_config: &config::Config, size: winit::dpi::PhysicalSize<u32>) -> Result<Self> {
+        if size.width == 0 || size.height == 0 {
+            bail!("Can't render to a {}x{} target", size.width, size.height);
+        }
+        let instance = app_config.gpu.create_instance();
+        let adapter = app_config.gpu.select_adapter(&instance, None, true).await?;
+        log::info!("Rendering offscreen with {}", gpu::describe(&adapter.get_info()));
+        let (device, queue) = gpu::request_device(&adapter).await?;
+
+        let config = wgpu::SurfaceConfiguration {
+            // the frames are copied out of the texture
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            format: OFFSCREEN_FORMAT,
+            width: size.width,
+            height: size.height,
+            present_mode: wgpu::PresentMode::Fifo,
+            alpha_mode: wgpu::CompositeAlphaMode::Opaque,
+            view_formats: vec![],
+        };
+        let texture = create_offscreen_texture(&device, &config);
+        let mipmap_generator = Arc::new(texture::MipmapGenerator::new(&device));
+        let first_wallpaper = first_wallpaper(&device, &queue, &mipmap_generator, app_config, size)?;
+        let target = Target::Offscreen(texture);
+        Ok(Self::with_target(
+            Arc::new(device), Arc::new(queue), mipmap_generator, config, target, first_wallpaper, app_config
+        ))
+    }
+
+    /// Everything but the target is the same with or without a window
+    fn with_target(
+        device: Arc<wgpu::Device>,
+        queue: Arc<wgpu::Queue>,
+        mipmap_generator: Arc<texture::MipmapGenerator>,
+        config: wgpu::SurfaceConfiguration,
+        target: Target,
+        first_wallpaper: FirstWallpaper,
+        app_config: &config::Config,
+    ) -> Self {
+        let size = winit::dpi::PhysicalSize::new(config.width, config.height);
+
+        let FirstWallpaper {
+            slideshow,
+            path: wallpaper_path,
+            texture: wallpaper_texture,
+            source: wallpaper_source,
+        } = first_wallpaper;
 
         let texture_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
@@ -202,41 +500,28 @@ impl State {
                 ],
                 label: Some("texture_bind_group_layout"),
             });
-        let wallpaper_bind_group = device.create_bind_group(
-            &wgpu::BindGroupDescriptor {
-                layout: &texture_bind_group_layout,
-                entries: &[
-                    wgpu::BindGroupEntry {
-                        binding: 0,
-                        resource: wgpu::BindingResource::TextureView(&wallpaper_texture.view),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 1,
-                        resource: wgpu::BindingResource::Sampler(&wallpaper_texture.sampler),
-                }
-                ],
-                label: Some("wallpaper_bind_group"),
-            }
+        let wallpaper_bind_group = create_texture_bind_group(
+            &device, &texture_bind_group_layout, &wallpaper_texture, "wallpaper_bind_group"
         );
+        let transition_renderer = app_config.slideshow.as_ref()
+            .filter(|_| slideshow.is_some())
+            .map(|description| transition::TransitionRenderer::new(
+                &device, &texture_bind_group_layout, description.transition, description.transition_duration, size
+            ));
 
-        // setting up plane with the right size to keep the aspect ratio of the image
-        let vertices = [
-            Vertex { position: [1., 1., 0.], tex_coords: [1., 0.] }, //A
-            Vertex { position: [-1., 1., 0.], tex_coords: [0., 0.] }, //B
-            Vertex { position: [-1., -1., 0.], tex_coords: [0., 1.] }, //C
-            Vertex { position: [1., -1., 0.], tex_coords: [1., 1.] }, //D
-        ];
+        // the plane gets the aspect ratio of the image in resize
+        let vertices = plane(1.);
 
         let camera = Camera {
-            // position the camera 1 unit up and 2 units back
+            // far enough back that the wallpaper plane fills the view
             // +z is out of the screen
-            eye: (0.0, 0.0, 1.0).into(),
+            eye: (0.0, 0.0, camera::plane_distance()).into(),
             // have it look at the origin
             target: (0.0, 0.0, 0.0).into(),
             // which way is "up"
             up: cgmath::Vector3::unit_y(),
             aspect: config.width as f32 / config.height as f32,
-            fovy: 45.0,
+            fovy: camera::FOVY,
             znear: 0.1,
             zfar: 100.0,
         };
@@ -275,56 +560,70 @@ impl State {
             label: Some("camera_bind_group"),
         });
 
-        let shader = device.create_shader_module(wgpu::include_wgsl!("shader.wgsl"));
+        let mut globals = globals::Globals::new(&device, &queue);
+        let (tonemap, exposure) = tonemap_settings(app_config, &wallpaper_texture);
+        globals.set_tonemap(tonemap, exposure);
+        for (slot, path) in app_config.textures.iter().enumerate() {
+            if let Err(error) = globals.load_channel(&device, &queue, &mipmap_generator, slot, path) {
+                log::error!("{:#}", error);
+            }
+        }
+        if app_config.parameters.len() > globals::NB_PARAMETERS {
+            log::error!("Only the first {} parameters are passed to the shaders", globals::NB_PARAMETERS);
+        }
+        for (index, (_, value)) in app_config.parameters.iter().take(globals::NB_PARAMETERS).enumerate() {
+            globals.set_parameter(index, *value);
+        }
+
         let render_pipeline_layout =
             device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                 label: Some("Render Pipeline Layout"),
                 bind_group_layouts: &[
                     &texture_bind_group_layout,
                     &camera_bind_group_layout,
+                    &globals.bind_group_layout,
+                    &globals.channels_bind_group_layout,
                 ],
                 push_constant_ranges: &[],
             });
-
-        let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            label: Some("Render Pipeline"),
-            layout: Some(&render_pipeline_layout),
-            vertex: wgpu::VertexState {
-                module: &shader,
-                entry_point: "vs_main",
-                buffers: &[
-                    Vertex::desc(),
-                ],
-            },
-            fragment: Some(wgpu::FragmentState {
-                module: &shader,
-                entry_point: "fs_main",
-                targets: &[Some(wgpu::ColorTargetState {
-                    format: config.format,
-                    blend: Some(wgpu::BlendState::REPLACE),
-                    write_mask: wgpu::ColorWrites::ALL,
-                })],
-            }),
-            primitive: wgpu::PrimitiveState {
-                topology: wgpu::PrimitiveTopology::TriangleList,
-                strip_index_format: None,
-                front_face: wgpu::FrontFace::Ccw,
-                cull_mode: Some(wgpu::Face::Back),
-                // Setting this to anything other than Fill requires Features::NON_FILL_POLYGON_MODE
-                polygon_mode: wgpu::PolygonMode::Fill,
-                // Requires Features::DEPTH_CLIP_CONTROL
-                unclipped_depth: false,
-                // Requires Features::CONSERVATIVE_RASTERIZATION
-                conservative: false,
-            },
-            depth_stencil: None,
-            multisample: wgpu::MultisampleState {
-                count: 1,
-                mask: !0,
-                alpha_to_coverage_enabled: false,
-            },
-            multiview: None,
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Default Shader"),
+            source: wgpu::ShaderSource::Wgsl((globals::GLOBALS_SHADER.to_string() + DEFAULT_SHADER).into()),
         });
+        // bind groups 1 to 3 of the passes and the shader layers
+        let shared_layouts = [
+            &camera_bind_group_layout,
+            &globals.bind_group_layout,
+            &globals.channels_bind_group_layout,
+        ];
+        let render_graph = if app_config.passes.is_empty() {
+            None
+        } else {
+            match render_graph::RenderGraph::new(
+                &device, &app_config.passes, &shared_layouts, config.format, size
+            ) {
+                Ok(graph) => Some(graph),
+                Err(error) => {
+                    log::error!("{:#}, drawing the wallpaper without the render graph", error);
+                    None
+                },
+            }
+        };
+        // with a render graph, the wallpaper is drawn in an offscreen target
+        let scene_format = if render_graph.is_some() {
+            render_graph::TARGET_FORMAT
+        } else {
+            config.format
+        };
+        let render_pipeline = create_render_pipeline(
+            &device, &render_pipeline_layout, &shader, scene_format
+        );
+        let layers = (!app_config.layers.is_empty()).then(|| layers::Compositor::new(
+            &device, &queue, &mipmap_generator, &app_config.layers, &shared_layouts, scene_format, size
+        ));
+        let visualisers = (!app_config.visualisers.is_empty()).then(|| visualiser::VisualiserRenderer::new(
+            &device, &app_config.visualisers, scene_format, size
+        ));
 
         let vertex_buffer = device.create_buffer_init(
             &wgpu::util::BufferInitDescriptor {
@@ -334,6 +633,13 @@ impl State {
             }
         );
         let num_vertices = vertices.len() as u32;
+        let fullscreen_vertex_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Fullscreen Vertex Buffer"),
+                contents: bytemuck::cast_slice(&[vertices]),
+                usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            }
+        );
 
         let index_buffer = device.create_buffer_init(
             &wgpu::util::BufferInitDescriptor {
@@ -344,28 +650,53 @@ impl State {
         );
         let num_indices = INDICES.len() as u32;
 
-        Self {
-            window,
-            surface,
+        let mut state = Self {
+            target,
             device,
             queue,
             config,
             size,
             render_pipeline,
+            render_pipeline_layout,
+            texture_bind_group_layout,
             vertex_buffer,
             num_vertices,
             index_buffer,
             num_indices,
             wallpaper_bind_group,
             wallpaper_texture,
+            wallpaper_back: None,
+            wallpaper_source,
+            wallpaper_path,
+            app_config: app_config.clone(),
+            slideshow,
+            transition_renderer,
+            transition_from: None,
+            fullscreen_vertex_buffer,
+            mipmap_generator,
             camera,
+            camera_motion: camera::CameraMotion::new(&app_config.camera),
+            camera_buffer,
             camera_bind_group,
+            camera_bind_group_layout,
             vertices,
-        }
+            globals,
+            render_graph,
+            layers,
+            visualisers,
+            player_monitor: app_config.layers.iter().any(|layer| layer.uses_now_playing())
+                .then(mpris::PlayerMonitor::new),
+        };
+        // the plane depends on the aspect ratio of the image
+        state.resize(size);
+        state
     }
 
-    pub fn window(&self) -> &Window {
-        &self.window
+    pub fn window(&self) -> Option<&Window> {
+        match &self.target {
+            Target::Window { window, .. } => Some(window),
+            Target::Offscreen(_) => None,
+        }
     }
 
     fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
@@ -373,37 +704,374 @@ impl State {
             self.size = new_size;
             self.config.width = new_size.width;
             self.config.height = new_size.height;
-            self.surface.configure(&self.device, &self.config);
-
-            let wiw = new_size.height as f32 * self.wallpaper_texture.dimensions.0 as f32 / self.wallpaper_texture.dimensions.1 as f32;
-            let offset = (wiw - new_size.width as f32) / 2. / new_size.width as f32 * 2.;
-            self.vertices[0].position = [1. + offset, 1., 0.];
-            self.vertices[1].position = [-1. - offset, 1., 0.];
-            self.vertices[2].position = [-1. - offset, -1., 0.];
-            self.vertices[3].position = [1. + offset, -1., 0.];
+            match &mut self.target {
+                Target::Window { surface, .. } => surface.configure(&self.device, &self.config),
+                Target::Offscreen(texture) => *texture = create_offscreen_texture(&self.device, &self.config),
+            }
+            if let Some(graph) = &mut self.render_graph {
+                graph.resize(&self.device, new_size);
+            }
+            if let Some(renderer) = &mut self.transition_renderer {
+                renderer.resize(&self.device, &self.texture_bind_group_layout, new_size);
+            }
+            if let Some(layers) = &mut self.layers {
+                layers.resize(&self.device, &self.queue, new_size);
+            }
+            if let Some(visualisers) = &mut self.visualisers {
+                visualisers.resize(new_size);
+            }
+            if let WallpaperSource::Still { original: Some(original) } = &self.wallpaper_source {
+                let dimensions = prescaled_dimensions(original.dimensions(), new_size)
+                    .unwrap_or(original.dimensions());
+                if dimensions != self.wallpaper_texture.dimensions {
+                    let scaled = prescale(original, new_size);
+                    match texture::Texture::from_image(
+                        &self.device, &self.queue, &self.mipmap_generator, scaled.as_ref().unwrap_or(original), Some("wallpaper-texture")
+                    ) {
+                        Ok(texture) => {
+                            self.wallpaper_bind_group = create_texture_bind_group(
+                                &self.device, &self.texture_bind_group_layout, &texture, "wallpaper_bind_group"
+                            );
+                            self.wallpaper_texture = texture;
+                        },
+                        Err(error) => log::error!("Failed to scale the wallpaper: {:#}", error),
+                    }
+                }
+            }
+
+            // the plane is 2 units high, the camera fits its height to the window
+            let (width, height) = self.wallpaper_texture.dimensions;
+            self.vertices = plane(width as f32 / height as f32);
             self.queue.write_buffer(&self.vertex_buffer, 0, bytemuck::cast_slice(&[self.vertices]));
+            let window_aspect = new_size.width as f32 / new_size.height as f32;
+            self.queue.write_buffer(&self.fullscreen_vertex_buffer, 0, bytemuck::cast_slice(&[plane(window_aspect)]));
+            self.camera.aspect = window_aspect;
+            self.write_camera();
         }
     }
 
+    fn write_camera(&self) {
+        let mut camera_uniform = CameraUniform::new();
+        camera_uniform.update_view_proj(&self.camera);
+        self.queue.write_buffer(&self.camera_buffer, 0, bytemuck::cast_slice(&[camera_uniform]));
+    }
+
+    /// Moves the camera for this frame, `transient` being the output of the DSP patch
+    fn update_camera(&mut self, transient: f32) {
+        let (pan, zoom) = self.camera_motion.update(transient);
+        self.camera.eye = (pan[0], pan[1], camera::plane_distance() / zoom).into();
+        self.camera.target = (pan[0], pan[1], 0.).into();
+        self.write_camera();
+    }
+
+    /// Replaces the wallpaper, the current one is kept if the file can't be decoded.
+    fn reload_wallpaper(&mut self, path: &Path) -> Result<()> {
+        let (wallpaper_texture, wallpaper_source) = load_wallpaper(
+            &self.device, &self.queue, &self.mipmap_generator, path, &self.app_config, self.size
+        )?;
+        self.set_wallpaper(wallpaper_texture, wallpaper_source);
+        self.wallpaper_path = path.to_path_buf();
+        Ok(())
+    }
+
+    /// Goes to the next wallpaper of the slideshow with a transition.
+    fn next_slide(&mut self, path: &Path) -> Result<()> {
+        let (wallpaper_texture, wallpaper_source) = load_wallpaper(
+            &self.device, &self.queue, &self.mipmap_generator, path, &self.app_config, self.size
+        )?;
+        let previous = self.set_wallpaper(wallpaper_texture, wallpaper_source);
+        self.transition_from = Some((previous, Instant::now()));
+        self.wallpaper_path = path.to_path_buf();
+        Ok(())
+    }
+
+    /// Returns the previous wallpaper texture
+    fn set_wallpaper(
+        &mut self,
+        wallpaper_texture: texture::Texture,
+        wallpaper_source: WallpaperSource,
+    ) -> texture::Texture {
+        self.wallpaper_bind_group = create_texture_bind_group(
+            &self.device, &self.texture_bind_group_layout, &wallpaper_texture, "wallpaper_bind_group"
+        );
+        let previous = std::mem::replace(&mut self.wallpaper_texture, wallpaper_texture);
+        self.wallpaper_back = None;
+        self.wallpaper_source = wallpaper_source;
+        let (tonemap, exposure) = tonemap_settings(&self.app_config, &self.wallpaper_texture);
+        self.globals.set_tonemap(tonemap, exposure);
+        // the plane depends on the aspect ratio of the image
+        self.resize(self.size);
+        previous
+    }
+
+    /// Replaces the shader, the current one is kept if the new one doesn't compile.
+    fn reload_shader(&mut self, path: &Path) -> Result<()> {
+        let source = globals::GLOBALS_SHADER.to_string() + &fs::read_to_string(path)
+            .with_context(|| format!("Failed to read shader {}", path.display()))?;
+
+        // Without an error scope, wgpu panics on invalid shaders
+        self.device.push_error_scope(wgpu::ErrorFilter::Validation);
+        let shader = self.device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: path.to_str(),
+            source: wgpu::ShaderSource::Wgsl(source.into()),
+        });
+        let render_pipeline = create_render_pipeline(
+            &self.device, &self.render_pipeline_layout, &shader, self.scene_format()
+        );
+        if let Some(error) = pollster::block_on(self.device.pop_error_scope()) {
+            bail!("Failed to compile shader {}: {}", path.display(), error);
+        }
+        self.render_pipeline = render_pipeline;
+        Ok(())
+    }
+
+    fn reload_pass_shader(&mut self, path: &Path) -> Result<()> {
+        let shared_layouts = [
+            &self.camera_bind_group_layout,
+            &self.globals.bind_group_layout,
+            &self.globals.channels_bind_group_layout,
+        ];
+        match &mut self.render_graph {
+            Some(graph) => graph.reload_shader(&self.device, path, &shared_layouts),
+            None => Ok(()),
+        }
+    }
+
+    fn reload_layer(&mut self, path: &Path) -> Result<()> {
+        let shared_layouts = [
+            &self.camera_bind_group_layout,
+            &self.globals.bind_group_layout,
+            &self.globals.channels_bind_group_layout,
+        ];
+        match &mut self.layers {
+            Some(layers) => layers.reload(&self.device, &self.queue, &self.mipmap_generator, path, &shared_layouts),
+            None => Ok(()),
+        }
+    }
+
+    fn scene_format(&self) -> wgpu::TextureFormat {
+        match self.render_graph {
+            Some(_) => render_graph::TARGET_FORMAT,
+            None => self.config.format,
+        }
+    }
+
+    /// Changes a value of `globals.parameters` by its name in the config
+    fn set_shader_parameter(&mut self, name: &str, value: f32) -> Result<()> {
+        let index = self.app_config.parameters.iter()
+            .take(globals::NB_PARAMETERS)
+            .position(|(parameter, _)| parameter == name)
+            .with_context(|| format!("No shader parameter named {}", name))?;
+        self.globals.set_parameter(index, value);
+        Ok(())
+    }
+
+    /// Skips to the next or the previous wallpaper of the slideshow
+    fn skip_slide(&mut self, forward: bool) -> Result<()> {
+        let slideshow = self.slideshow.as_mut().context("There is no slideshow")?;
+        let path = if forward { slideshow.next() } else { slideshow.previous() }.to_path_buf();
+        self.next_slide(&path)
+    }
+
+    fn reload_channel(&mut self, slot: usize, path: &Path) -> Result<()> {
+        self.globals.load_channel(&self.device, &self.queue, &self.mipmap_generator, slot, path)
+    }
+
     fn input(&mut self, event: &WindowEvent) -> bool {
+        if let WindowEvent::CursorMoved { position, .. } = event {
+            self.globals.set_mouse(position.x as f32, position.y as f32);
+            self.camera_motion.set_mouse(position.x as f32, position.y as f32, self.size);
+        }
         false
     }
 
-    fn update(&mut self) {
+    /// No need to decode frames nobody sees
+    fn set_paused(&mut self, paused: bool) {
+        if let WallpaperSource::Video(video) = &mut self.wallpaper_source {
+            video.set_paused(paused);
+        }
+    }
+
+    /// Custom shaders, passes and visualisers may use the time or the audio,
+    /// so they are drawn continuously
+    fn is_animated(&self) -> bool {
+        self.app_config.shader.is_some()
+            || self.render_graph.is_some()
+            || self.visualisers.is_some()
+            || self.layers.as_ref().is_some_and(|layers| layers.is_animated())
+            || self.camera_motion.is_animated()
+            || self.transition_from.is_some()
+            || !matches!(self.wallpaper_source, WallpaperSource::Still { .. })
+    }
+
+    /// Advances the wallpaper, true if the frame has to be drawn again
+    fn update(&mut self, audio: Option<&audio::AudioFeatures>) -> bool {
+        self.globals.update(&self.queue, self.size, audio);
+        let mut changed = self.app_config.shader.is_some()
+            || self.render_graph.is_some()
+            || self.transition_from.is_some();
+        changed |= self.camera_motion.is_animated();
+        self.update_camera(audio.map_or(0., |audio| audio.transient as f32));
+        if let Some(layers) = &mut self.layers {
+            let now_playing = self.player_monitor.as_ref().and_then(|monitor| monitor.now_playing());
+            changed |= layers.update(&self.device, &self.queue, &self.mipmap_generator, audio, now_playing.as_ref());
+        }
+        if let Some(visualisers) = &mut self.visualisers {
+            visualisers.update(&self.queue, audio);
+            changed = true;
+        }
+        let frame = match &mut self.wallpaper_source {
+            WallpaperSource::Still { .. } => None,
+            WallpaperSource::Animation(animation) => animation.poll(Instant::now()),
+            WallpaperSource::Video(video) => {
+                changed |= video.update(&self.device, &self.queue);
+                None
+            },
+        };
+        if let Some(frame) = frame {
+            self.show_frame(&frame.image);
+            changed = true;
+        }
+        if let Some(path) = self.slideshow.as_mut().and_then(|slideshow| slideshow.poll()) {
+            changed = true;
+            if let Err(error) = self.next_slide(&path) {
+                log::error!("{:#}, skipping it", error);
+            }
+        }
+        changed
+    }
+
+    fn show_frame(&mut self, image: &image::RgbaImage) {
+        let dimensions = image.dimensions();
+        let (texture, bind_group) = match self.wallpaper_back.take() {
+            Some((texture, bind_group)) if texture.dimensions == dimensions => (texture, bind_group),
+            // frames of image sequences don't have to share the same size
+            _ => {
+                let texture = texture::Texture::new(
+                    &self.device, dimensions, texture::Texture::FORMAT, true, Some("wallpaper-texture")
+                );
+                let bind_group = create_texture_bind_group(
+                    &self.device, &self.texture_bind_group_layout, &texture, "wallpaper_bind_group"
+                );
+                (texture, bind_group)
+            },
+        };
+        texture.write(&self.queue, image);
+        let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Mipmap Encoder"),
+        });
+        self.mipmap_generator.generate(&self.device, &mut encoder, &texture);
+        self.queue.submit(std::iter::once(encoder.finish()));
+        let texture = std::mem::replace(&mut self.wallpaper_texture, texture);
+        let bind_group = std::mem::replace(&mut self.wallpaper_bind_group, bind_group);
+        let resized = texture.dimensions != dimensions;
+        self.wallpaper_back = Some((texture, bind_group));
+        if resized {
+            self.resize(self.size);
+        }
     }
 
     fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
-        let output = self.surface.get_current_texture()?;
-        let view = output.texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let output = match &self.target {
+            Target::Window { surface, .. } => Some(surface.get_current_texture()?),
+            Target::Offscreen(_) => None,
+        };
+        let view = match (&output, &self.target) {
+            (Some(output), _) => output.texture.create_view(&wgpu::TextureViewDescriptor::default()),
+            (None, Target::Offscreen(texture)) => texture.create_view(&wgpu::TextureViewDescriptor::default()),
+            (None, Target::Window { .. }) => unreachable!(),
+        };
+        self.draw(&view);
+        if let Some(output) = output {
+            output.present();
+        }
+        Ok(())
+    }
+
+    /// Renders a frame and reads it back, in a texture of its own with a window.
+    fn snapshot(&mut self) -> Result<image::RgbaImage> {
+        if let Target::Offscreen(_) = &self.target {
+            self.render()?;
+            let Target::Offscreen(texture) = &self.target else { unreachable!() };
+            return texture::read_back(&self.device, &self.queue, texture);
+        }
+        let config = wgpu::SurfaceConfiguration {
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            ..self.config.clone()
+        };
+        let texture = create_offscreen_texture(&self.device, &config);
+        self.draw(&texture.create_view(&wgpu::TextureViewDescriptor::default()));
+        let mut image = texture::read_back(&self.device, &self.queue, &texture)?;
+        if matches!(config.format, wgpu::TextureFormat::Bgra8Unorm | wgpu::TextureFormat::Bgra8UnormSrgb) {
+            for pixel in image.pixels_mut() {
+                pixel.0.swap(0, 2);
+            }
+        }
+        Ok(image)
+    }
+
+    /// Rebuilds everything but the window and the device, the current state
+    /// is kept if the wallpaper of `app_config` can't be loaded.
+    fn reload_config(&mut self, app_config: &config::Config) -> Result<()> {
+        let first_wallpaper = first_wallpaper(
+            &self.device, &self.queue, &self.mipmap_generator, app_config, self.size
+        )?;
+        let placeholder = wgpu::SurfaceConfiguration { width: 1, height: 1, ..self.config.clone() };
+        let placeholder = Target::Offscreen(create_offscreen_texture(&self.device, &placeholder));
+        let target = std::mem::replace(&mut self.target, placeholder);
+        *self = Self::with_target(
+            self.device.clone(), self.queue.clone(), self.mipmap_generator.clone(),
+            self.config.clone(), target, first_wallpaper, app_config
+        );
+        load_user_shader(self, app_config);
+        Ok(())
+    }
+
+    fn draw(&mut self, view: &wgpu::TextureView) {
         let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
             label: Some("Render Encoder"),
         });
 
+        if let WallpaperSource::Video(video) = &mut self.wallpaper_source {
+            if video.convert(&mut encoder, &self.wallpaper_texture.level_view(0)) {
+                self.mipmap_generator.generate(&self.device, &mut encoder, &self.wallpaper_texture);
+            }
+        }
+
+        // during transitions, the blend of both wallpapers is drawn instead
+        // of the wallpaper, over the whole window
+        let transition = match (&self.transition_renderer, &self.transition_from) {
+            (Some(renderer), Some((from, start))) => renderer.progress(*start).map(|progress| {
+                renderer.render(&self.device, &self.queue, &mut encoder, from, &self.wallpaper_texture, progress);
+                renderer
+            }),
+            _ => None,
+        };
+        if transition.is_none() {
+            self.transition_from = None;
+        }
+        if let Some(layers) = &self.layers {
+            let shared_bind_groups = [
+                &self.camera_bind_group,
+                &self.globals.bind_group,
+                &self.globals.channels_bind_group,
+            ];
+            layers.prepare(&mut encoder, &shared_bind_groups);
+        }
+        let (wallpaper_bind_group, vertex_buffer) = match transition {
+            Some(renderer) => (&renderer.target_bind_group, &self.fullscreen_vertex_buffer),
+            None => (&self.wallpaper_bind_group, &self.vertex_buffer),
+        };
+
         {
+            let scene_view = match &self.render_graph {
+                Some(graph) => graph.scene_view(),
+                None => view,
+            };
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: Some("Render Pass"),
                 color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                    view: &view,
+                    view: scene_view,
                     resolve_target: None,
                     ops: wgpu::Operations {
                         load: wgpu::LoadOp::Clear(wgpu::Color {
@@ -421,32 +1089,222 @@ impl State {
             });
 
             render_pass.set_pipeline(&self.render_pipeline);
-            render_pass.set_bind_group(0, &self.wallpaper_bind_group, &[]);
+            render_pass.set_bind_group(0, wallpaper_bind_group, &[]);
             render_pass.set_bind_group(1, &self.camera_bind_group, &[]);
-            render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+            render_pass.set_bind_group(2, &self.globals.bind_group, &[]);
+            render_pass.set_bind_group(3, &self.globals.channels_bind_group, &[]);
+            render_pass.set_vertex_buffer(0, vertex_buffer.slice(..));
             render_pass.set_index_buffer(self.index_buffer.slice(..), wgpu::IndexFormat::Uint16);
             render_pass.draw_indexed(0..self.num_indices, 0, 0..1);
+            if let Some(layers) = &self.layers {
+                layers.draw(&mut render_pass, &self.camera_bind_group);
+            }
+            if let Some(visualisers) = &self.visualisers {
+                visualisers.draw(&mut render_pass);
+            }
+        }
+
+        if let Some(graph) = &mut self.render_graph {
+            let shared_bind_groups = [
+                &self.camera_bind_group,
+                &self.globals.bind_group,
+                &self.globals.channels_bind_group,
+            ];
+            graph.render(&self.device, &mut encoder, view, &shared_bind_groups);
         }
 
         // submit will accept anything that implements IntoIter
         self.queue.submit(std::iter::once(encoder.finish()));
-        output.present();
+    }
+}
 
-        Ok(())
+fn load_config() -> config::Config {
+    config::Config::load().unwrap_or_else(|error| {
+        log::error!("{:#}, using the default configuration", error);
+        config::Config::default()
+    })
+}
+
+fn load_user_shader(state: &mut State, app_config: &config::Config) {
+    if let Some(shader) = &app_config.shader {
+        if let Err(error) = state.reload_shader(shader) {
+            log::error!("{:#}, using the default shader", error);
+        }
     }
 }
 
-pub async fn run() {
-    audio::process_audio();
+/// Renders a frame of the configured wallpaper without a window and saves it,
+/// the format is guessed from the extension of `path`.
+pub async fn screenshot(path: &Path, width: u32, height: u32) -> Result<()> {
     env_logger::init();
-    let event_loop = EventLoop::new();
-    let window = WindowBuilder::new().build(&event_loop).unwrap();
+    let app_config = load_config();
+    let mut state = State::new_headless(&app_config, winit::dpi::PhysicalSize::new(width, height)).await?;
+    load_user_shader(&mut state, &app_config);
+    state.update(None);
+    let image = state.snapshot()?;
+    image.save(path)
+        .with_context(|| format!("Failed to save the screenshot to {}", path.display()))
+}
+
+/// Watches the files of `app_config` to reload them on changes, if inotify works
+fn watch_files(app_config: &config::Config) -> Option<watcher::FileWatcher> {
+    let mut watcher = match watcher::FileWatcher::new() {
+        Ok(watcher) => watcher,
+        Err(error) => {
+            log::error!("{:#}, files won't be reloaded on changes", error);
+            return None;
+        },
+    };
+    let watched = [Some(&app_config.wallpaper), app_config.shader.as_ref(), app_config.patch.as_ref()];
+    let pass_shaders = app_config.passes.iter().map(|pass| pass.shader.as_path());
+    let layer_files = app_config.layers.iter().flat_map(|layer| layer.files());
+    let watched = watched.into_iter().flatten().chain(&app_config.textures).map(PathBuf::as_path);
+    for path in watched.chain(pass_shaders).chain(layer_files) {
+        if let Err(error) = watcher.watch(path) {
+            log::error!("{:#}, it won't be reloaded on changes", error);
+        }
+    }
+    Some(watcher)
+}
 
-    let mut state = State::new(window).await;
+/// Runs a command of the control socket, but reloading the config
+fn control(
+    command: &ipc::Command,
+    state: &mut State,
+    scheduler: &mut scheduler::FrameScheduler,
+    audio_capture: Option<&mut audio::AudioCapture>,
+) -> Result<Option<ipc::Status>> {
+    match command {
+        ipc::Command::SetWallpaper(path) => state.reload_wallpaper(path)?,
+        ipc::Command::NextSlide => state.skip_slide(true)?,
+        ipc::Command::PreviousSlide => state.skip_slide(false)?,
+        ipc::Command::Pause | ipc::Command::Resume => {
+            let paused = *command == ipc::Command::Pause;
+            scheduler.set_paused(paused);
+            if let Some(capture) = audio_capture {
+                capture.set_paused(paused);
+            }
+        },
+        ipc::Command::SetShaderParameter { name, value } => state.set_shader_parameter(name, *value)?,
+        ipc::Command::SetDspParameter { node, parameter, value } => audio_capture
+            .context("Audio isn't captured")?
+            .set_parameter(node, parameter, *value)?,
+        ipc::Command::SavePatch(path) => audio_capture
+            .context("Audio isn't captured")?
+            .save_patch(path)?,
+        ipc::Command::Status => return Ok(Some(ipc::Status {
+            wallpaper: state.wallpaper_path.clone(),
+            width: state.size.width,
+            height: state.size.height,
+            fps: scheduler.fps(Instant::now()),
+            paused: scheduler.paused(),
+            slideshow: state.slideshow.is_some(),
+            audio: audio_capture.is_some(),
+            audio_latency: audio_capture.map(|capture| capture.features().latency.as_secs_f64()),
+        })),
+        ipc::Command::Screenshot(path) => state.snapshot()?.save(path)
+            .with_context(|| format!("Failed to save the screenshot to {}", path.display()))?,
+        ipc::Command::ReloadConfig => bail!("The config is reloaded by the event loop"),
+    }
+    Ok(None)
+}
+
+/// Sends a command to the running instance, `words` being the arguments of
+/// `wallfuck ctl`, and returns its JSON response.
+pub fn ctl(words: &[String]) -> Result<String> {
+    // the instance skips empty lines without answering
+    if words.iter().all(|word| word.trim().is_empty()) {
+        bail!("wallfuck ctl needs a command, one of:\n{}", ipc::COMMANDS);
+    }
+    let mut command = words.join(" ");
+    // the instance may not run in the same directory
+    if let [verb, path @ ..] = words {
+        if ["wallpaper", "screenshot", "patch"].contains(&verb.as_str()) && !path.is_empty() {
+            let path = std::env::current_dir()?.join(path.join(" "));
+            command = format!("{} {}", verb, path.display());
+        }
+    }
+    let response = ipc::send(&ipc::socket_path(), &command)?;
+    if let Some(error) = response.error {
+        bail!("{}", error);
+    }
+    Ok(serde_json::to_string_pretty(&response)?)
+}
+
+fn capture_audio(config: &config::Config) -> Option<audio::AudioCapture> {
+    let fragment = config.audio_fragment
+        .filter(|fragment| *fragment > 0.)
+        .map(Duration::from_secs_f32);
+    audio::process_audio(config.patch.as_deref(), &config.audio_source, config.audio_backend, fragment)
+}
+
+/// The audio matching a frame shown at `presentation_time`, `av_offset`
+/// seconds before it
+fn audio_time(presentation_time: Instant, av_offset: f32) -> Instant {
+    let offset = Duration::from_secs_f32(av_offset.abs());
+    if av_offset >= 0. {
+        presentation_time.checked_sub(offset).unwrap_or(presentation_time)
+    } else {
+        presentation_time + offset
+    }
+}
+
+pub async fn run() -> Result<()> {
+    env_logger::init();
+    let mut app_config = load_config();
+    let mut audio_capture = capture_audio(&app_config);
+    let event_loop = EventLoopBuilder::<ipc::Request>::with_user_event().build();
+    let window = WindowBuilder::new().build(&event_loop)
+        .context("Failed to create the window")?;
+    let main_window_id = window.id();
+
+    let mut state = State::new(window, &app_config).await?;
+    load_user_shader(&mut state, &app_config);
+
+    let mut watcher = watch_files(&app_config);
+    let mut scheduler = scheduler::FrameScheduler::new(app_config.max_fps, app_config.battery_max_fps);
+    let mut visible = true;
+
+    let proxy = event_loop.create_proxy();
+    let mut server = match ipc::Server::start(&ipc::socket_path(), move |request| proxy.send_event(request).is_ok()) {
+        Ok(server) => Some(server),
+        Err(error) => {
+            log::error!("{:#}, wallfuck ctl won't work", error);
+            None
+        },
+    };
 
     event_loop.run(move |event, _, control_flow| match event {
-        Event::RedrawRequested(window_id) if window_id == state.window().id() => {
-            state.update();
+        Event::UserEvent(request) => {
+            let result = match request.command {
+                ipc::Command::ReloadConfig => config::Config::load().and_then(|new_config| {
+                    state.reload_config(&new_config)?;
+                    let audio_changed = new_config.patch != app_config.patch
+                        || new_config.audio_source != app_config.audio_source
+                        || new_config.audio_backend != app_config.audio_backend
+                        || new_config.audio_fragment != app_config.audio_fragment;
+                    if audio_changed {
+                        // the previous capture stops first
+                        drop(audio_capture.take());
+                        audio_capture = capture_audio(&new_config);
+                    }
+                    watcher = watch_files(&new_config);
+                    scheduler.set_max_fps(new_config.max_fps, new_config.battery_max_fps);
+                    app_config = new_config;
+                    Ok(None)
+                }),
+                ref command => control(command, &mut state, &mut scheduler, audio_capture.as_mut()),
+            };
+            if let Err(error) = &result {
+                log::error!("{:#}", error);
+            }
+            request.reply(result);
+            scheduler.invalidate();
+        }
+        // removes the socket, the process exits without dropping the closure
+        Event::LoopDestroyed => drop(server.take()),
+        Event::RedrawRequested(window_id) if window_id == main_window_id => {
+            scheduler.rendered(Instant::now());
             match state.render() {
                 Ok(_) => {}
                 // Reconfigure the surface if lost
@@ -458,22 +1316,64 @@ pub async fn run() {
             }
         }
         Event::MainEventsCleared => {
-            // RedrawRequested will only trigger once unless we manually
-            // request it.
-            state.window().request_redraw();
+            for path in watcher.iter_mut().flat_map(watcher::FileWatcher::changed) {
+                let result = if path == app_config.wallpaper {
+                    state.reload_wallpaper(&path)
+                } else if Some(&path) == app_config.shader.as_ref() {
+                    state.reload_shader(&path)
+                } else if let Some(slot) = app_config.textures.iter().position(|texture| *texture == path) {
+                    state.reload_channel(slot, &path)
+                } else if app_config.passes.iter().any(|pass| pass.shader == path) {
+                    state.reload_pass_shader(&path)
+                } else if app_config.layers.iter().any(|layer| layer.files().any(|file| file == path)) {
+                    state.reload_layer(&path)
+                } else {
+                    match audio_capture.as_mut() {
+                        Some(capture) => capture.load_patch(&path),
+                        None => Ok(()),
+                    }
+                };
+                match result {
+                    Ok(_) => log::info!("Reloaded {}", path.display()),
+                    Err(error) => log::error!("{:#}", error),
+                }
+                scheduler.invalidate();
+            }
+
+            if scheduler.visible() != visible {
+                visible = scheduler.visible();
+                state.set_paused(!visible);
+            }
+            let now = Instant::now();
+            if scheduler.frame_due(now) {
+                let heard_at = audio_time(scheduler.presentation_time(now), app_config.av_offset);
+                let audio_features = audio_capture.as_ref().map(|capture| capture.features_at(heard_at));
+                let changed = state.update(audio_features.as_ref());
+                // RedrawRequested will only trigger once unless we manually
+                // request it.
+                if changed | scheduler.take_dirty() {
+                    if let Some(window) = state.window() {
+                        window.request_redraw();
+                    }
+                }
+            }
+            *control_flow = ControlFlow::WaitUntil(scheduler.next_wakeup(now, state.is_animated()));
         }
         Event::WindowEvent {
             ref event,
             window_id,
-        } if window_id == state.window().id() => if !state.input(event) {
+        } if window_id == main_window_id => if !state.input(event) {
             match event {
                 WindowEvent::Resized(physical_size) => {
                     state.resize(*physical_size);
+                    scheduler.invalidate();
                 }
                 WindowEvent::ScaleFactorChanged { new_inner_size, .. } => {
                     // new_inner_size is &&mut so we have to dereference it twice
                     state.resize(**new_inner_size);
+                    scheduler.invalidate();
                 }
+                WindowEvent::Occluded(occluded) => scheduler.set_occluded(*occluded),
                 WindowEvent::CloseRequested
                 | WindowEvent::KeyboardInput {
                     input:
@@ -491,3 +1391,88 @@ pub async fn run() {
     });
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ctl_without_command() {
+        let error = ctl(&[]).unwrap_err().to_string();
+        assert!(error.starts_with("wallfuck ctl needs a command") && error.contains("screenshot <path>"));
+        assert!(ctl(&[" ".to_string()]).is_err());
+    }
+
+    /// Renders `wallpaper` in a `width`x`height` target, through `shader` if any
+    fn render_headless(wallpaper: &image::RgbaImage, width: u32, height: u32, shader: Option<&str>) -> image::RgbaImage {
+        let name = format!("wallfuck-headless-{}-{:?}", std::process::id(), std::thread::current().id());
+        let path = std::env::temp_dir().join(format!("{}.png", name));
+        wallpaper.save(&path).unwrap();
+        let shader_path = shader.map(|source| {
+            let shader_path = std::env::temp_dir().join(format!("{}.wgsl", name));
+            fs::write(&shader_path, source).unwrap();
+            shader_path
+        });
+        let app_config = config::Config { wallpaper: path.clone(), shader: shader_path.clone(), ..Default::default() };
+        let state = pollster::block_on(State::new_headless(&app_config, winit::dpi::PhysicalSize::new(width, height)));
+        fs::remove_file(&path).unwrap();
+        let mut state = state.unwrap();
+        if let Some(shader_path) = shader_path {
+            state.reload_shader(&shader_path).unwrap();
+            fs::remove_file(&shader_path).unwrap();
+        }
+        state.update(None);
+        let snapshot = state.snapshot().unwrap();
+        assert_eq!(snapshot.dimensions(), (width, height));
+        snapshot
+    }
+
+    fn assert_close(snapshot: &image::RgbaImage, x: u32, y: u32, expected: [u8; 4]) {
+        let pixel = snapshot.get_pixel(x, y).0;
+        assert!(pixel.iter().zip(expected).all(|(a, b)| a.abs_diff(b) <= 2),
+            "pixel ({}, {}) is {:?}, expected {:?}", x, y, pixel, expected);
+    }
+
+    /// Columns of 4 pixels, red, green, blue and white from the left
+    fn stripes(height: u32) -> image::RgbaImage {
+        let colours = [[255, 0, 0, 255], [0, 255, 0, 255], [0, 0, 255, 255], [255, 255, 255, 255]];
+        image::RgbaImage::from_fn(16, height, |x, _| image::Rgba(colours[x as usize / 4]))
+    }
+
+    #[test]
+    #[ignore = "needs a GPU or software adapter"]
+    fn headless_wallpaper_fills_the_height() {
+        let red = image::RgbaImage::from_pixel(1, 1, image::Rgba([255, 0, 0, 255]));
+        let snapshot = render_headless(&red, 8, 4, None);
+        // a square wallpaper in a 2:1 window, with black bars on both sides
+        assert_eq!(snapshot.get_pixel(4, 2), &image::Rgba([255, 0, 0, 255]));
+        assert_eq!(snapshot.get_pixel(0, 2), &image::Rgba([0, 0, 0, 255]));
+        assert_eq!(snapshot.get_pixel(7, 2), &image::Rgba([0, 0, 0, 255]));
+    }
+
+    #[test]
+    #[ignore = "needs a GPU or software adapter"]
+    fn headless_wide_wallpaper_is_cropped() {
+        // 4:1 in a 2:1 window, only the green and blue stripes in the middle are left
+        let snapshot = render_headless(&stripes(4), 8, 4, None);
+        for y in 0..4 {
+            assert_close(&snapshot, 1, y, [0, 255, 0, 255]);
+            assert_close(&snapshot, 6, y, [0, 0, 255, 255]);
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a GPU or software adapter"]
+    fn headless_custom_shader() {
+        let shader = DEFAULT_SHADER.replace(
+            "return vec4<f32>(tonemap(color.rgb), color.a);",
+            "return vec4<f32>(1.0 - color.rgb, color.a);",
+        );
+        assert_ne!(shader, DEFAULT_SHADER);
+        let red = image::RgbaImage::from_pixel(1, 1, image::Rgba([255, 0, 0, 255]));
+        let snapshot = render_headless(&red, 8, 4, Some(&shader));
+        assert_eq!(snapshot.get_pixel(4, 2), &image::Rgba([0, 255, 255, 255]));
+        // the bars aren't drawn by the shader
+        assert_eq!(snapshot.get_pixel(0, 2), &image::Rgba([0, 0, 0, 255]));
+    }
+}
@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use anyhow::Result;
+use zbus::blocking::Connection;
+use zbus::blocking::fdo::DBusProxy;
+use zbus::proxy::CacheProperties;
+use zbus::zvariant::OwnedValue;
+
+/// Bus names of the players start with this
+const PLAYER_PREFIX: &str = "org.mpris.MediaPlayer2.";
+const PLAYER_PATH: &str = "/org/mpris/MediaPlayer2";
+const PLAYER_INTERFACE: &str = "org.mpris.MediaPlayer2.Player";
+/// How often the players are asked what they play
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum PlaybackStatus {
+    Playing,
+    Paused,
+    #[default]
+    Stopped,
+}
+
+impl PlaybackStatus {
+    fn parse(status: &str) -> Self {
+        match status {
+            "Playing" => PlaybackStatus::Playing,
+            "Paused" => PlaybackStatus::Paused,
+            _ => PlaybackStatus::Stopped,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PlaybackStatus::Playing => "Playing",
+            PlaybackStatus::Paused => "Paused",
+            PlaybackStatus::Stopped => "Stopped",
+        }
+    }
+}
+
+/// What a player plays, from its MPRIS metadata.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NowPlaying {
+    pub title: String,
+    /// All the artists, comma separated
+    pub artist: String,
+    pub album: String,
+    /// Usually a file:// URL
+    pub art_url: Option<String>,
+    pub status: PlaybackStatus,
+}
+
+impl NowPlaying {
+    /// Local file of the album art, None for remote URLs
+    pub fn art_path(&self) -> Option<PathBuf> {
+        let path = self.art_url.as_deref()?.strip_prefix("file://")?;
+        Some(PathBuf::from(percent_decode(path)))
+    }
+
+    /// Replaces `{title}`, `{artist}`, `{album}` and `{status}` in `text`
+    pub fn substitute(&self, text: &str) -> String {
+        text.replace("{title}", &self.title)
+            .replace("{artist}", &self.artist)
+            .replace("{album}", &self.album)
+            .replace("{status}", self.status.as_str())
+    }
+}
+
+/// Whether `text` shows what is playing
+pub fn has_placeholders(text: &str) -> bool {
+    ["{title}", "{artist}", "{album}", "{status}"].iter().any(|placeholder| text.contains(placeholder))
+}
+
+/// Decodes the %XX escapes of a URL path, invalid ones are kept as they are
+fn percent_decode(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escape = (bytes[i] == b'%')
+            .then(|| path.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escape {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            },
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            },
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// The player to show: the first one playing, else the first one paused
+fn active(players: Vec<NowPlaying>) -> Option<NowPlaying> {
+    players.into_iter().min_by_key(|player| player.status as u8)
+}
+
+fn player(connection: &Connection, name: &str) -> Result<NowPlaying> {
+    let proxy: zbus::blocking::Proxy = zbus::blocking::proxy::Builder::new(connection)
+        .destination(name)?
+        .path(PLAYER_PATH)?
+        .interface(PLAYER_INTERFACE)?
+        .cache_properties(CacheProperties::No)
+        .build()?;
+    let status: String = proxy.get_property("PlaybackStatus")?;
+    let metadata: HashMap<String, OwnedValue> = proxy.get_property("Metadata")?;
+    let text = |key: &str| metadata.get(key).and_then(|value| <&str>::try_from(value).ok()).map(str::to_string);
+    let artists = metadata.get("xesam:artist")
+        .and_then(|value| value.try_clone().ok())
+        .and_then(|value| Vec::<String>::try_from(value).ok())
+        .unwrap_or_default();
+    Ok(NowPlaying {
+        title: text("xesam:title").unwrap_or_default(),
+        artist: artists.join(", "),
+        album: text("xesam:album").unwrap_or_default(),
+        art_url: text("mpris:artUrl").filter(|url| !url.is_empty()),
+        status: PlaybackStatus::parse(&status),
+    })
+}
+
+fn query(connection: &Connection) -> Result<Option<NowPlaying>> {
+    let names = DBusProxy::new(connection)?.list_names()?;
+    let players = names.iter()
+        .filter(|name| name.starts_with(PLAYER_PREFIX))
+        // players may quit while they are asked
+        .filter_map(|name| player(connection, name).ok())
+        .collect();
+    Ok(active(players))
+}
+
+/// Asks the MPRIS players of the session bus what they play on a thread, so
+/// the render loop never waits on D-Bus.
+pub struct PlayerMonitor {
+    now_playing: Arc<Mutex<Option<NowPlaying>>>,
+}
+
+impl PlayerMonitor {
+    pub fn new() -> Self {
+        let now_playing = Arc::new(Mutex::new(None));
+        let shared = now_playing.clone();
+        thread::spawn(move || {
+            let mut connection = None;
+            // stops once the monitor is dropped
+            while Arc::strong_count(&shared) > 1 {
+                if connection.is_none() {
+                    connection = match Connection::session() {
+                        Ok(connection) => Some(connection),
+                        Err(error) => {
+                            log::warn!("Failed to connect to the session bus: {}", error);
+                            None
+                        },
+                    };
+                }
+                if let Some(bus) = &connection {
+                    match query(bus) {
+                        Ok(playing) => *shared.lock().unwrap() = playing,
+                        Err(error) => {
+                            log::warn!("Failed to list the MPRIS players: {:#}", error);
+                            connection = None;
+                        },
+                    }
+                }
+                thread::sleep(POLL_INTERVAL);
+            }
+        });
+        Self { now_playing }
+    }
+
+    /// What the active player plays, None without a player
+    pub fn now_playing(&self) -> Option<NowPlaying> {
+        self.now_playing.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zbus::zvariant::Value;
+
+    #[test]
+    fn now_playing_text() {
+        let playing = NowPlaying {
+            title: "Song".to_string(),
+            artist: "A, B".to_string(),
+            art_url: Some("file:///music/Some%20Album/cover.jpg".to_string()),
+            status: PlaybackStatus::Playing,
+            ..Default::default()
+        };
+        assert_eq!(playing.substitute("{artist} - {title} ({status})"), "A, B - Song (Playing)");
+        assert!(has_placeholders("%H:%M {title}") && !has_placeholders("%H:%M {}"));
+        assert_eq!(playing.art_path(), Some(PathBuf::from("/music/Some Album/cover.jpg")));
+        assert_eq!(percent_decode("100%"), "100%");
+
+        let remote = NowPlaying { art_url: Some("https://example.com/a.jpg".to_string()), ..Default::default() };
+        assert_eq!(remote.art_path(), None);
+        let paused = NowPlaying { status: PlaybackStatus::Paused, ..Default::default() };
+        assert_eq!(active(vec![paused.clone(), NowPlaying::default(), playing.clone()]), Some(playing));
+        assert_eq!(active(vec![NowPlaying::default(), paused.clone()]), Some(paused));
+    }
+
+    struct MockPlayer;
+
+    #[zbus::interface(name = "org.mpris.MediaPlayer2.Player")]
+    impl MockPlayer {
+        #[zbus(property)]
+        fn playback_status(&self) -> String {
+            "Paused".to_string()
+        }
+
+        #[zbus(property)]
+        fn metadata(&self) -> HashMap<String, OwnedValue> {
+            let value = |value: Value| OwnedValue::try_from(value).unwrap();
+            HashMap::from([
+                ("xesam:title".to_string(), value(Value::from("Title"))),
+                ("xesam:artist".to_string(), value(Value::from(vec!["First", "Second"]))),
+                ("mpris:artUrl".to_string(), value(Value::from("file:///tmp/art.png"))),
+            ])
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a session bus, run it under dbus-run-session"]
+    fn mock_player() {
+        let name = "org.mpris.MediaPlayer2.wallfuck_test";
+        let _service = zbus::blocking::connection::Builder::session().unwrap()
+            .name(name).unwrap()
+            .serve_at(PLAYER_PATH, MockPlayer).unwrap()
+            .build().unwrap();
+        let connection = Connection::session().unwrap();
+        let playing = player(&connection, name).unwrap();
+        assert_eq!(playing, NowPlaying {
+            title: "Title".to_string(),
+            artist: "First, Second".to_string(),
+            album: String::new(),
+            art_url: Some("file:///tmp/art.png".to_string()),
+            status: PlaybackStatus::Paused,
+        });
+        assert!(query(&connection).unwrap().is_some());
+    }
+}
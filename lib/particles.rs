@@ -0,0 +1,576 @@
+use std::path::PathBuf;
+use std::time::Instant;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::audio::{AudioFeatures, SAMPLE_RATE};
+use crate::audio::spectrum::SpectrumAnalyser;
+use crate::decode;
+use crate::texture::{MipmapGenerator, Texture};
+
+const PARTICLES_SHADER: &str = include_str!("shaders/particles.wgsl");
+const MAX_EMITTERS: usize = 8;
+const MAX_FORCES: usize = 8;
+const MAX_KEYS: usize = 8;
+const WORKGROUP_SIZE: u32 = 64;
+const FFT_SIZE: usize = 2048;
+/// Longer frames are simulated as this, so particles don't jump after a pause
+const MAX_TIME_DELTA: f32 = 0.1;
+
+/// Audio feature a parameter can follow
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum AudioInput {
+    /// RMS of the latest samples
+    Level,
+    /// Output of the DSP patch
+    Transient,
+    /// Mean of the spectrum (0 to 1 on a dB scale) between two frequencies in Hz
+    Band { low: f32, high: f32 },
+}
+
+/// Adds `amount` times the audio input to a parameter.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AudioBinding {
+    pub input: AudioInput,
+    pub amount: f32,
+}
+
+/// Values of the audio inputs for a frame
+struct AudioValues<'a> {
+    level: f32,
+    transient: f32,
+    /// None without audio
+    spectrum: Option<&'a [f64]>,
+    /// Hz between two bins of the spectrum
+    bin_width: f32,
+}
+
+impl AudioValues<'_> {
+    /// `base` moved by the binding
+    fn apply(&self, base: f32, binding: Option<&AudioBinding>) -> f32 {
+        let Some(binding) = binding else {
+            return base;
+        };
+        let input = match binding.input {
+            AudioInput::Level => self.level,
+            AudioInput::Transient => self.transient,
+            AudioInput::Band { low, high } => self.spectrum.map_or(0., |spectrum| band(spectrum, self.bin_width, low, high)),
+        };
+        base + binding.amount * input
+    }
+}
+
+/// Mean magnitude of the bins between `low` and `high` Hz
+fn band(spectrum: &[f64], bin_width: f32, low: f32, high: f32) -> f32 {
+    let first = ((low / bin_width) as usize).min(spectrum.len() - 1);
+    let last = ((high / bin_width).ceil() as usize).clamp(first + 1, spectrum.len());
+    let bins = &spectrum[first..last];
+    (bins.iter().sum::<f64>() / bins.len() as f64) as f32
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Emitter {
+    /// From (0, 0) at the top left of the window to (1, 1)
+    pub position: [f32; 2],
+    /// Size of the area particles appear in, in heights of the window
+    pub spread: [f32; 2],
+    /// Particles per second
+    pub rate: f32,
+    pub rate_audio: Option<AudioBinding>,
+    /// Heights of the window per second, y going down
+    pub velocity: [f32; 2],
+    /// Random speed added in any direction
+    pub velocity_spread: f32,
+    /// Seconds
+    pub lifetime: f32,
+}
+impl Default for Emitter {
+    fn default() -> Self {
+        Self {
+            position: [0.5, 0.5],
+            spread: [0., 0.],
+            rate: 100.,
+            rate_audio: None,
+            velocity: [0., 0.],
+            velocity_spread: 0.2,
+            lifetime: 2.,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ForceKind {
+    /// Constant acceleration, `direction` being normalised
+    Gravity { direction: [f32; 2] },
+    /// Swirling flow, `scale` being the number of swirls per height
+    CurlNoise { scale: f32 },
+    /// Pulls towards the position (from 0 to 1 like the emitters), pushes
+    /// away with a negative strength
+    Attractor { position: [f32; 2] },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Force {
+    pub kind: ForceKind,
+    /// Heights of the window per second squared
+    pub strength: f32,
+    #[serde(default)]
+    pub strength_audio: Option<AudioBinding>,
+}
+
+/// A particle system as written in the config.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ParticleSystemDescription {
+    /// The oldest particles are replaced when there are this many
+    pub max_particles: u32,
+    pub emitters: Vec<Emitter>,
+    pub forces: Vec<Force>,
+    /// RGBA from 0 to 1, evenly spread over the lifetime of a particle
+    pub colors: Vec<[f32; 4]>,
+    /// Diameters in heights of the window, evenly spread over the lifetime
+    pub sizes: Vec<f32>,
+    /// Image whose tiles are given randomly to the particles, round sprites
+    /// without one
+    pub atlas: Option<PathBuf>,
+    /// Columns and rows of the atlas
+    pub atlas_tiles: [u32; 2],
+}
+impl Default for ParticleSystemDescription {
+    fn default() -> Self {
+        Self {
+            max_particles: 10000,
+            emitters: vec![Emitter::default()],
+            forces: Vec::new(),
+            colors: vec![[1., 1., 1., 1.], [1., 1., 1., 0.]],
+            sizes: vec![0.01],
+            atlas: None,
+            atlas_tiles: [1, 1],
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, bytemuck::Pod, bytemuck::Zeroable)]
+struct GpuEmitter {
+    position: [f32; 2],
+    spread: [f32; 2],
+    velocity: [f32; 2],
+    velocity_spread: f32,
+    lifetime: f32,
+    first: u32,
+    count: u32,
+    _padding: [u32; 2],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, bytemuck::Pod, bytemuck::Zeroable)]
+struct GpuForce {
+    vector: [f32; 2],
+    kind: u32,
+    strength: f32,
+    scale: f32,
+    _padding: [f32; 3],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct SystemUniform {
+    emitters: [GpuEmitter; MAX_EMITTERS],
+    forces: [GpuForce; MAX_FORCES],
+    colors: [[f32; 4]; MAX_KEYS],
+    sizes: [f32; MAX_KEYS],
+    time: f32,
+    time_delta: f32,
+    aspect: f32,
+    max_particles: u32,
+    nb_emitters: u32,
+    nb_forces: u32,
+    nb_colors: u32,
+    nb_sizes: u32,
+    atlas: [u32; 2],
+    frame: u32,
+    _padding: u32,
+}
+
+/// Size of a particle in the buffer, see `Particle` in the shader
+const PARTICLE_SIZE: u64 = 32;
+
+/// Slots of the particles to spawn for each emitter: `pending` holds the
+/// fractions of particles carried over from the previous frames and `next`
+/// the slot after the latest particle, the slots wrapping around.
+fn schedule(pending: &mut [f32], rates: &[f32], time_delta: f32, next: &mut u32, max_particles: u32) -> Vec<(u32, u32)> {
+    pending.iter_mut().zip(rates).map(|(pending, rate)| {
+        *pending += rate.max(0.) * time_delta;
+        let count = (pending.floor() as u32).min(max_particles);
+        *pending -= pending.floor();
+        let first = *next;
+        *next = (*next + count) % max_particles;
+        (first, count)
+    }).collect()
+}
+
+/// Particles simulated by a compute shader and drawn as sprites.
+pub struct ParticleSystem {
+    description: ParticleSystemDescription,
+    uniform: SystemUniform,
+    uniform_buffer: wgpu::Buffer,
+    particle_buffer: wgpu::Buffer,
+    compute_pipeline: wgpu::ComputePipeline,
+    compute_bind_group: wgpu::BindGroup,
+    render_pipeline: wgpu::RenderPipeline,
+    render_bind_group: wgpu::BindGroup,
+    analyser: SpectrumAnalyser,
+    pending: Vec<f32>,
+    next: u32,
+    start: Instant,
+    last_update: Instant,
+}
+
+impl ParticleSystem {
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        mipmap_generator: &MipmapGenerator,
+        description: &ParticleSystemDescription,
+        format: wgpu::TextureFormat,
+    ) -> Result<Self> {
+        let mut description = description.clone();
+        description.max_particles = description.max_particles.clamp(1, 1 << 20);
+        for (name, len, max) in [
+            ("emitters", description.emitters.len(), MAX_EMITTERS),
+            ("forces", description.forces.len(), MAX_FORCES),
+            ("colors", description.colors.len(), MAX_KEYS),
+            ("sizes", description.sizes.len(), MAX_KEYS),
+        ] {
+            if len > max {
+                log::warn!("Only the first {} {} of the particle system are used", max, name);
+            }
+        }
+        description.emitters.truncate(MAX_EMITTERS);
+        description.forces.truncate(MAX_FORCES);
+        description.colors.truncate(MAX_KEYS);
+        description.sizes.truncate(MAX_KEYS);
+        if description.colors.is_empty() {
+            description.colors.push([1.; 4]);
+        }
+        if description.sizes.is_empty() {
+            description.sizes.push(0.01);
+        }
+
+        let atlas = match &description.atlas {
+            Some(path) => {
+                let image = decode::open(path)?;
+                Texture::from_image(device, queue, mipmap_generator, &image, path.to_str())
+                    .with_context(|| format!("Failed to load the atlas {}", path.display()))?
+            },
+            None => {
+                let white = Texture::new(device, (1, 1), Texture::FORMAT, false, Some("particle-atlas"));
+                white.write(queue, &image::RgbaImage::from_pixel(1, 1, image::Rgba([255; 4])));
+                white
+            },
+        };
+
+        let mut uniform = SystemUniform {
+            emitters: [GpuEmitter::default(); MAX_EMITTERS],
+            forces: [GpuForce::default(); MAX_FORCES],
+            colors: [[0.; 4]; MAX_KEYS],
+            sizes: [0.; MAX_KEYS],
+            time: 0.,
+            time_delta: 0.,
+            aspect: 1.,
+            max_particles: description.max_particles,
+            nb_emitters: description.emitters.len() as u32,
+            nb_forces: description.forces.len() as u32,
+            nb_colors: description.colors.len() as u32,
+            nb_sizes: description.sizes.len() as u32,
+            atlas: if description.atlas.is_some() { description.atlas_tiles.map(|tiles| tiles.max(1)) } else { [0, 0] },
+            frame: 0,
+            _padding: 0,
+        };
+        uniform.colors[..description.colors.len()].copy_from_slice(&description.colors);
+        uniform.sizes[..description.sizes.len()].copy_from_slice(&description.sizes);
+
+        let uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Particle System Buffer"),
+            size: std::mem::size_of::<SystemUniform>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        // zeroed, so every particle starts dead
+        let particle_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Particle Buffer"),
+            size: description.max_particles as u64 * PARTICLE_SIZE,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::VERTEX,
+            mapped_at_creation: false,
+        });
+
+        let uniform_entry = wgpu::BindGroupLayoutEntry {
+            binding: 0,
+            visibility: wgpu::ShaderStages::COMPUTE | wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let compute_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                uniform_entry,
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("particle_compute_bind_group_layout"),
+        });
+        let render_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                uniform_entry,
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+            label: Some("particle_render_bind_group_layout"),
+        });
+        let compute_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &compute_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: particle_buffer.as_entire_binding(),
+                },
+            ],
+            label: Some("particle_compute_bind_group"),
+        });
+        let render_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &render_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&atlas.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::Sampler(&atlas.sampler),
+                },
+            ],
+            label: Some("particle_render_bind_group"),
+        });
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Particle Shader"),
+            source: wgpu::ShaderSource::Wgsl(PARTICLES_SHADER.into()),
+        });
+        let compute_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Particle Compute Pipeline Layout"),
+            bind_group_layouts: &[&compute_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let compute_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("Particle Compute Pipeline"),
+            layout: Some(&compute_pipeline_layout),
+            module: &shader,
+            entry_point: "cs_main",
+        });
+        let render_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Particle Render Pipeline Layout"),
+            bind_group_layouts: &[&render_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Particle Render Pipeline"),
+            layout: Some(&render_pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                // the particles are the instances
+                buffers: &[wgpu::VertexBufferLayout {
+                    array_stride: PARTICLE_SIZE,
+                    step_mode: wgpu::VertexStepMode::Instance,
+                    attributes: &[
+                        // the velocity is skipped
+                        wgpu::VertexAttribute { offset: 0, shader_location: 0, format: wgpu::VertexFormat::Float32x2 },
+                        wgpu::VertexAttribute { offset: 16, shader_location: 1, format: wgpu::VertexFormat::Float32 },
+                        wgpu::VertexAttribute { offset: 20, shader_location: 2, format: wgpu::VertexFormat::Float32 },
+                        wgpu::VertexAttribute { offset: 24, shader_location: 3, format: wgpu::VertexFormat::Uint32 },
+                        wgpu::VertexAttribute { offset: 28, shader_location: 4, format: wgpu::VertexFormat::Uint32 },
+                    ],
+                }],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format,
+                    blend: Some(wgpu::BlendState::PREMULTIPLIED_ALPHA_BLENDING),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        });
+
+        let now = Instant::now();
+        Ok(Self {
+            pending: vec![0.; description.emitters.len()],
+            description,
+            uniform,
+            uniform_buffer,
+            particle_buffer,
+            compute_pipeline,
+            compute_bind_group,
+            render_pipeline,
+            render_bind_group,
+            analyser: SpectrumAnalyser::new(FFT_SIZE, SAMPLE_RATE.into(), 0.),
+            next: 0,
+            start: now,
+            last_update: now,
+        })
+    }
+
+    /// Spawns the particles of this frame and moves the forces with the audio
+    pub fn update(&mut self, queue: &wgpu::Queue, size: winit::dpi::PhysicalSize<u32>, audio: Option<&AudioFeatures>) {
+        let now = Instant::now();
+        let time_delta = (now - self.last_update).as_secs_f32().min(MAX_TIME_DELTA);
+        self.last_update = now;
+
+        if let Some(audio) = audio {
+            self.analyser.set_sample_rate(audio.sample_rate.into());
+        }
+        let bin_width = self.analyser.bin_width() as f32;
+        let spectrum = audio.map(|audio| {
+            let samples: Vec<f64> = audio.samples.iter().copied().collect();
+            self.analyser.process(&samples)
+        });
+        let values = AudioValues {
+            level: audio.map_or(0., |audio| audio.level as f32),
+            transient: audio.map_or(0., |audio| audio.transient as f32),
+            spectrum,
+            bin_width,
+        };
+
+        let aspect = size.width as f32 / size.height as f32;
+        let rates: Vec<f32> = self.description.emitters.iter()
+            .map(|emitter| values.apply(emitter.rate, emitter.rate_audio.as_ref()))
+            .collect();
+        let slots = schedule(&mut self.pending, &rates, time_delta, &mut self.next, self.description.max_particles);
+        for ((gpu, emitter), (first, count)) in self.uniform.emitters.iter_mut().zip(&self.description.emitters).zip(slots) {
+            *gpu = GpuEmitter {
+                position: [emitter.position[0] * aspect, emitter.position[1]],
+                spread: emitter.spread,
+                velocity: emitter.velocity,
+                velocity_spread: emitter.velocity_spread,
+                lifetime: emitter.lifetime.max(0.01),
+                first,
+                count,
+                _padding: [0; 2],
+            };
+        }
+        for (gpu, force) in self.uniform.forces.iter_mut().zip(&self.description.forces) {
+            let strength = values.apply(force.strength, force.strength_audio.as_ref());
+            *gpu = match force.kind {
+                ForceKind::Gravity { direction } => {
+                    let length = direction[0].hypot(direction[1]).max(f32::EPSILON);
+                    GpuForce { vector: direction.map(|x| x / length), kind: 0, strength, ..Default::default() }
+                },
+                ForceKind::CurlNoise { scale } => GpuForce { kind: 1, strength, scale, ..Default::default() },
+                ForceKind::Attractor { position } => GpuForce {
+                    vector: [position[0] * aspect, position[1]],
+                    kind: 2,
+                    strength,
+                    ..Default::default()
+                },
+            };
+        }
+        self.uniform.time = (now - self.start).as_secs_f32();
+        self.uniform.time_delta = time_delta;
+        self.uniform.aspect = aspect;
+        self.uniform.frame = self.uniform.frame.wrapping_add(1);
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[self.uniform]));
+    }
+
+    /// Runs the simulation, before the particles are drawn
+    pub fn simulate(&self, encoder: &mut wgpu::CommandEncoder) {
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("Particle Pass"),
+            timestamp_writes: None,
+        });
+        compute_pass.set_pipeline(&self.compute_pipeline);
+        compute_pass.set_bind_group(0, &self.compute_bind_group, &[]);
+        compute_pass.dispatch_workgroups(self.description.max_particles.div_ceil(WORKGROUP_SIZE), 1, 1);
+    }
+
+    pub fn draw<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>) {
+        render_pass.set_pipeline(&self.render_pipeline);
+        render_pass.set_bind_group(0, &self.render_bind_group, &[]);
+        render_pass.set_vertex_buffer(0, self.particle_buffer.slice(..));
+        render_pass.draw(0..6, 0..self.description.max_particles);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn emission_wraps_around() {
+        let mut pending = vec![0., 0.5];
+        let mut next = 8;
+        // 2.5 and 3 particles for the second emitter with what was left
+        let slots = schedule(&mut pending, &[25., 25.], 0.1, &mut next, 10);
+        assert_eq!(slots, vec![(8, 2), (0, 3)]);
+        assert_eq!(next, 3);
+        assert_eq!(pending, vec![0.5, 0.]);
+        // never more than the buffer holds
+        let slots = schedule(&mut pending, &[1000., -5.], 1., &mut next, 10);
+        assert_eq!(slots, vec![(3, 10), (3, 0)]);
+    }
+
+    #[test]
+    fn audio_bindings() {
+        let spectrum: Vec<f64> = (0..FFT_SIZE / 2).map(|bin| if bin < 10 { 1. } else { 0. }).collect();
+        let bin_width = SAMPLE_RATE as f32 / FFT_SIZE as f32;
+        let values = AudioValues { level: 0.5, transient: 1., spectrum: Some(&spectrum), bin_width };
+        let binding = |input| AudioBinding { input, amount: 2. };
+        assert_eq!(values.apply(1., None), 1.);
+        assert_eq!(values.apply(1., Some(&binding(AudioInput::Level))), 2.);
+        assert_eq!(values.apply(1., Some(&binding(AudioInput::Transient))), 3.);
+        // the bins are 21.5 Hz wide, the first 10 are full
+        let low = binding(AudioInput::Band { low: 0., high: 200. });
+        assert_eq!(values.apply(0., Some(&low)), 2.);
+        let high = binding(AudioInput::Band { low: 1000., high: 2000. });
+        assert_eq!(values.apply(0., Some(&high)), 0.);
+    }
+}
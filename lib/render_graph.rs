@@ -0,0 +1,364 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::globals;
+
+const PASS_PRELUDE: &str = include_str!("shaders/pass_prelude.wgsl");
+/// Target the wallpaper is drawn into before the passes run
+pub const SCENE: &str = "scene";
+/// Float targets so feedback effects don't lose precision from frame to frame
+pub const TARGET_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum PassOutput {
+    /// Offscreen texture, `scale` is relative to the size of the window
+    Target {
+        name: String,
+        #[serde(default = "default_scale")]
+        scale: f32,
+    },
+    Screen,
+}
+fn default_scale() -> f32 {
+    1.
+}
+
+/// A pass of the render graph as written in the config.
+///
+/// The inputs are target names. Reading a target before it is written in
+/// the frame (or from the pass writing it) gives its content from the
+/// previous frame.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PassDescription {
+    pub shader: PathBuf,
+    #[serde(default)]
+    pub inputs: Vec<String>,
+    pub output: PassOutput,
+}
+
+//==============================================================================
+struct Target {
+    name: String,
+    scale: f32,
+    // ping-pong so a pass can read the previous content of its own output,
+    // only the views are used, this keeps the textures alive with them
+    _textures: [wgpu::Texture; 2],
+    views: [wgpu::TextureView; 2],
+    front: usize,
+}
+impl Target {
+    fn new(
+        device: &wgpu::Device,
+        name: &str,
+        scale: f32,
+        size: winit::dpi::PhysicalSize<u32>,
+    ) -> Self {
+        let size = wgpu::Extent3d {
+            width: ((size.width as f32 * scale).round() as u32).max(1),
+            height: ((size.height as f32 * scale).round() as u32).max(1),
+            depth_or_array_layers: 1,
+        };
+        let textures = [0, 1].map(|_| device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(name),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: TARGET_FORMAT,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::RENDER_ATTACHMENT,
+            view_formats: &[],
+        }));
+        let views = [0, 1].map(|i| textures[i].create_view(&wgpu::TextureViewDescriptor::default()));
+        Self {
+            name: name.to_string(),
+            scale,
+            _textures: textures,
+            views,
+            front: 0,
+        }
+    }
+    /// Latest content
+    fn front_view(&self) -> &wgpu::TextureView {
+        &self.views[self.front]
+    }
+    /// Where the next write goes
+    fn back_view(&self) -> &wgpu::TextureView {
+        &self.views[1 - self.front]
+    }
+    fn swap(&mut self) {
+        self.front = 1 - self.front;
+    }
+}
+
+struct Pass {
+    shader: PathBuf,
+    inputs: Vec<usize>,
+    /// None for the screen
+    output: Option<usize>,
+    input_bind_group_layout: wgpu::BindGroupLayout,
+    pipeline: wgpu::RenderPipeline,
+}
+
+/// Offscreen passes run after the wallpaper is drawn in the scene target.
+pub struct RenderGraph {
+    targets: Vec<Target>,
+    passes: Vec<Pass>,
+    sampler: wgpu::Sampler,
+    surface_format: wgpu::TextureFormat,
+}
+
+impl RenderGraph {
+    /// `shared_layouts` are the layouts of the bind groups 1 to 3 (camera,
+    /// globals and channels), the bind group 0 holds the inputs of the pass.
+    pub fn new(
+        device: &wgpu::Device,
+        descriptions: &[PassDescription],
+        shared_layouts: &[&wgpu::BindGroupLayout; 3],
+        surface_format: wgpu::TextureFormat,
+        size: winit::dpi::PhysicalSize<u32>,
+    ) -> Result<Self> {
+        let mut targets = vec![Target::new(device, SCENE, 1., size)];
+        for description in descriptions {
+            if let PassOutput::Target { name, scale } = &description.output {
+                if *scale <= 0. {
+                    bail!("Target \"{}\" needs a positive scale", name);
+                }
+                if let Some(target) = targets.iter().find(|target| target.name == *name) {
+                    if target.scale != *scale {
+                        bail!("Target \"{}\" is written with different scales", name);
+                    }
+                } else {
+                    targets.push(Target::new(device, name, *scale, size));
+                }
+            }
+        }
+        if !descriptions.iter().any(|description| matches!(description.output, PassOutput::Screen)) {
+            bail!("No pass of the render graph outputs to the screen");
+        }
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        let mut passes = Vec::with_capacity(descriptions.len());
+        for (i, description) in descriptions.iter().enumerate() {
+            let target_index = |name: &str| targets.iter().position(|target| target.name == name);
+            let inputs = description.inputs.iter()
+                .map(|input| target_index(input).with_context(|| format!(
+                    "Unknown input \"{}\" in pass #{} ({})", input, i, description.shader.display()
+                )))
+                .collect::<Result<Vec<_>>>()?;
+            let output = match &description.output {
+                PassOutput::Target { name, .. } => target_index(name),
+                PassOutput::Screen => None,
+            };
+
+            let entries: Vec<_> = (0..inputs.len() as u32).flat_map(|i| [
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2 * i,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2 * i + 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ]).collect();
+            let input_bind_group_layout = device.create_bind_group_layout(
+                &wgpu::BindGroupLayoutDescriptor {
+                    entries: &entries,
+                    label: Some("pass_input_bind_group_layout"),
+                }
+            );
+            let format = if output.is_some() { TARGET_FORMAT } else { surface_format };
+            let pipeline = create_pass_pipeline(
+                device, &description.shader, inputs.len(),
+                &input_bind_group_layout, shared_layouts, format,
+            )?;
+
+            passes.push(Pass {
+                shader: description.shader.clone(),
+                inputs,
+                output,
+                input_bind_group_layout,
+                pipeline,
+            });
+        }
+
+        Ok(Self {
+            targets,
+            passes,
+            sampler,
+            surface_format,
+        })
+    }
+
+    /// Recompiles the passes using this shader, they are left untouched if it doesn't compile.
+    pub fn reload_shader(
+        &mut self,
+        device: &wgpu::Device,
+        path: &Path,
+        shared_layouts: &[&wgpu::BindGroupLayout; 3],
+    ) -> Result<()> {
+        for pass in self.passes.iter_mut().filter(|pass| pass.shader == path) {
+            let format = if pass.output.is_some() { TARGET_FORMAT } else { self.surface_format };
+            pass.pipeline = create_pass_pipeline(
+                device, path, pass.inputs.len(),
+                &pass.input_bind_group_layout, shared_layouts, format,
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn resize(&mut self, device: &wgpu::Device, size: winit::dpi::PhysicalSize<u32>) {
+        for target in self.targets.iter_mut() {
+            *target = Target::new(device, &target.name, target.scale, size);
+        }
+    }
+
+    /// Where the wallpaper has to be drawn before calling `render`
+    pub fn scene_view(&self) -> &wgpu::TextureView {
+        self.targets[0].back_view()
+    }
+
+    /// `shared_bind_groups` match the `shared_layouts` given to `new`.
+    pub fn render(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        screen_view: &wgpu::TextureView,
+        shared_bind_groups: &[&wgpu::BindGroup; 3],
+    ) {
+        // the scene has just been drawn
+        self.targets[0].swap();
+
+        for pass in &self.passes {
+            let entries: Vec<_> = pass.inputs.iter().enumerate().flat_map(|(i, &input)| [
+                wgpu::BindGroupEntry {
+                    binding: 2 * i as u32,
+                    resource: wgpu::BindingResource::TextureView(self.targets[input].front_view()),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2 * i as u32 + 1,
+                    resource: wgpu::BindingResource::Sampler(&self.sampler),
+                },
+            ]).collect();
+            let input_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                layout: &pass.input_bind_group_layout,
+                entries: &entries,
+                label: Some("pass_input_bind_group"),
+            });
+            let view = match pass.output {
+                Some(output) => self.targets[output].back_view(),
+                None => screen_view,
+            };
+
+            {
+                let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                    label: Some("Graph Pass"),
+                    color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                        view,
+                        resolve_target: None,
+                        ops: wgpu::Operations {
+                            load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                            store: wgpu::StoreOp::Store,
+                        },
+                    })],
+                    depth_stencil_attachment: None,
+                    occlusion_query_set: None,
+                    timestamp_writes: None,
+                });
+                render_pass.set_pipeline(&pass.pipeline);
+                render_pass.set_bind_group(0, &input_bind_group, &[]);
+                for (i, bind_group) in shared_bind_groups.iter().enumerate() {
+                    render_pass.set_bind_group(i as u32 + 1, bind_group, &[]);
+                }
+                render_pass.draw(0..3, 0..1);
+            }
+
+            if let Some(output) = pass.output {
+                self.targets[output].swap();
+            }
+        }
+    }
+}
+
+/// Pipeline of a pass shader, prepended with the prelude and the declarations
+/// of its inputs
+pub fn create_pass_pipeline(
+    device: &wgpu::Device,
+    path: &Path,
+    nb_inputs: usize,
+    input_bind_group_layout: &wgpu::BindGroupLayout,
+    shared_layouts: &[&wgpu::BindGroupLayout; 3],
+    format: wgpu::TextureFormat,
+) -> Result<wgpu::RenderPipeline> {
+    let mut source = globals::GLOBALS_SHADER.to_string() + PASS_PRELUDE;
+    for i in 0..nb_inputs {
+        source += &format!(
+            "@group(0) @binding({})\nvar t_input{}: texture_2d<f32>;\n\
+             @group(0) @binding({})\nvar s_input{}: sampler;\n",
+            2 * i, i, 2 * i + 1, i
+        );
+    }
+    source += &fs::read_to_string(path)
+        .with_context(|| format!("Failed to read shader {}", path.display()))?;
+
+    // Without an error scope, wgpu panics on invalid shaders
+    device.push_error_scope(wgpu::ErrorFilter::Validation);
+    let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: path.to_str(),
+        source: wgpu::ShaderSource::Wgsl(source.into()),
+    });
+    let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("Pass Pipeline Layout"),
+        bind_group_layouts: &[
+            input_bind_group_layout,
+            shared_layouts[0],
+            shared_layouts[1],
+            shared_layouts[2],
+        ],
+        push_constant_ranges: &[],
+    });
+    let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("Pass Pipeline"),
+        layout: Some(&layout),
+        vertex: wgpu::VertexState {
+            module: &shader,
+            entry_point: "vs_main",
+            buffers: &[],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: &shader,
+            entry_point: "fs_main",
+            targets: &[Some(wgpu::ColorTargetState {
+                format,
+                blend: Some(wgpu::BlendState::REPLACE),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+        }),
+        primitive: wgpu::PrimitiveState::default(),
+        depth_stencil: None,
+        multisample: wgpu::MultisampleState::default(),
+        multiview: None,
+    });
+    if let Some(error) = pollster::block_on(device.pop_error_scope()) {
+        bail!("Failed to compile shader {}: {}", path.display(), error);
+    }
+    Ok(pipeline)
+}
@@ -0,0 +1,285 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use anyhow::Result;
+use zbus::blocking::{Connection, Proxy};
+use zbus::zvariant::OwnedObjectPath;
+
+/// How often the loop wakes up when nothing moves, to pick up file changes
+/// and slideshow ticks
+const IDLE_INTERVAL: Duration = Duration::from_millis(250);
+/// How often the battery is checked
+const POWER_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+const POWER_SUPPLY_PATH: &str = "/sys/class/power_supply";
+
+/// Whether the computer runs on a battery, from the power supplies in sysfs:
+/// there is a battery and no mains supply is online.
+fn is_on_battery(power_supplies: &Path) -> bool {
+    let Ok(entries) = fs::read_dir(power_supplies) else {
+        return false;
+    };
+    let mut has_battery = false;
+    for entry in entries.flatten() {
+        let read = |file| fs::read_to_string(entry.path().join(file)).unwrap_or_default();
+        match read("type").trim() {
+            "Battery" => has_battery = true,
+            "Mains" | "USB" if read("online").trim() == "1" => return false,
+            _ => (),
+        }
+    }
+    has_battery
+}
+
+const LOGIN_SERVICE: &str = "org.freedesktop.login1";
+
+/// Follows the LockedHint of our logind session from its PropertiesChanged
+/// signals, until `locked` is the last reference to the flag
+fn watch_session_lock(locked: &Arc<AtomicBool>) -> Result<()> {
+    let connection = Connection::system()?;
+    let manager = Proxy::new(&connection, LOGIN_SERVICE, "/org/freedesktop/login1", "org.freedesktop.login1.Manager")?;
+    let session: OwnedObjectPath = match std::env::var("XDG_SESSION_ID") {
+        Ok(id) => manager.call("GetSession", &(id,))?,
+        Err(_) => manager.call("GetSessionByPID", &(std::process::id(),))?,
+    };
+    let session = Proxy::new(&connection, LOGIN_SERVICE, session, "org.freedesktop.login1.Session")?;
+    locked.store(session.get_property("LockedHint")?, Ordering::Relaxed);
+    for change in session.receive_property_changed::<bool>("LockedHint") {
+        if Arc::strong_count(locked) == 1 {
+            break;
+        }
+        locked.store(change.get()?, Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+/// Checks the battery and follows the session lock on threads, so the render
+/// loop never waits on sysfs or D-Bus.
+struct PowerMonitor {
+    on_battery: Arc<AtomicBool>,
+    locked: Arc<AtomicBool>,
+}
+
+impl PowerMonitor {
+    fn new() -> Self {
+        let on_battery = Arc::new(AtomicBool::new(false));
+        let locked = Arc::new(AtomicBool::new(false));
+        let (battery_flag, locked_flag) = (on_battery.clone(), locked.clone());
+        thread::spawn(move || {
+            // stops once the monitor is dropped
+            while Arc::strong_count(&battery_flag) > 1 {
+                battery_flag.store(is_on_battery(Path::new(POWER_SUPPLY_PATH)), Ordering::Relaxed);
+                thread::sleep(POWER_CHECK_INTERVAL);
+            }
+        });
+        thread::spawn(move || {
+            // never locked without logind
+            if let Err(error) = watch_session_lock(&locked_flag) {
+                log::info!("Not following the session lock: {}", error);
+            }
+        });
+        Self { on_battery, locked }
+    }
+}
+
+fn frame_interval(fps: f32) -> Duration {
+    Duration::from_secs_f64(1. / fps.max(0.1) as f64)
+}
+
+/// Decides when frames are drawn: only when something changed, never faster
+/// than the FPS cap, and not at all while the wallpaper can't be seen.
+pub struct FrameScheduler {
+    frame_interval: Duration,
+    battery_frame_interval: Duration,
+    last_frame: Option<Instant>,
+    /// Something changed outside of the wallpaper: resize, reload
+    dirty: bool,
+    occluded: bool,
+    /// Paused from the control socket
+    paused: bool,
+    /// Frames drawn in the last second, to measure the FPS
+    frames: VecDeque<Instant>,
+    power: Option<PowerMonitor>,
+}
+
+impl FrameScheduler {
+    pub fn new(max_fps: f32, battery_max_fps: f32) -> Self {
+        Self {
+            frame_interval: frame_interval(max_fps),
+            battery_frame_interval: frame_interval(battery_max_fps),
+            last_frame: None,
+            dirty: true,
+            occluded: false,
+            paused: false,
+            frames: VecDeque::new(),
+            power: Some(PowerMonitor::new()),
+        }
+    }
+
+    /// Applies the caps of a new config
+    pub fn set_max_fps(&mut self, max_fps: f32, battery_max_fps: f32) {
+        self.frame_interval = frame_interval(max_fps);
+        self.battery_frame_interval = frame_interval(battery_max_fps);
+    }
+
+    /// The next frame has to be drawn even if the wallpaper didn't change.
+    pub fn invalidate(&mut self) {
+        self.dirty = true;
+    }
+
+    pub fn set_occluded(&mut self, occluded: bool) {
+        self.occluded = occluded;
+        // the content of the window may be lost while it is hidden
+        self.dirty |= !occluded;
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        self.dirty |= !paused;
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    fn flag(&self, flag: impl Fn(&PowerMonitor) -> &AtomicBool) -> bool {
+        self.power.as_ref().is_some_and(|power| flag(power).load(Ordering::Relaxed))
+    }
+
+    pub fn visible(&self) -> bool {
+        !self.occluded && !self.paused && !self.flag(|power| &power.locked)
+    }
+
+    fn interval(&self) -> Duration {
+        if self.flag(|power| &power.on_battery) {
+            self.frame_interval.max(self.battery_frame_interval)
+        } else {
+            self.frame_interval
+        }
+    }
+
+    /// Whether the wallpaper can be updated now
+    pub fn frame_due(&self, now: Instant) -> bool {
+        self.visible() && self.last_frame.map_or(true, |last_frame| now >= last_frame + self.interval())
+    }
+
+    /// When a frame updated at `now` is expected on screen: it is drawn and
+    /// waits for the next vertical blank, about a frame later.
+    pub fn presentation_time(&self, now: Instant) -> Instant {
+        now + self.interval()
+    }
+
+    /// True once after `invalidate`
+    pub fn take_dirty(&mut self) -> bool {
+        std::mem::take(&mut self.dirty)
+    }
+
+    pub fn rendered(&mut self, now: Instant) {
+        self.last_frame = Some(now);
+        self.frames.push_back(now);
+        self.forget_frames(now);
+    }
+
+    fn forget_frames(&mut self, now: Instant) {
+        while self.frames.front().is_some_and(|&frame| now.saturating_duration_since(frame) >= Duration::from_secs(1)) {
+            self.frames.pop_front();
+        }
+    }
+
+    /// Frames drawn in the last second
+    pub fn fps(&mut self, now: Instant) -> usize {
+        self.forget_frames(now);
+        self.frames.len()
+    }
+
+    /// When the loop has to wake up again, `animated` being whether the
+    /// wallpaper moves on its own
+    pub fn next_wakeup(&self, now: Instant, animated: bool) -> Instant {
+        if self.visible() && (animated || self.dirty) {
+            match self.last_frame {
+                Some(last_frame) => (last_frame + self.interval()).max(now),
+                None => now,
+            }
+        } else {
+            now + IDLE_INTERVAL
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scheduler(max_fps: f32) -> FrameScheduler {
+        FrameScheduler {
+            frame_interval: frame_interval(max_fps),
+            battery_frame_interval: frame_interval(max_fps),
+            last_frame: None,
+            dirty: true,
+            occluded: false,
+            paused: false,
+            frames: VecDeque::new(),
+            power: None,
+        }
+    }
+
+    #[test]
+    fn fps_cap_and_idle() {
+        let mut scheduler = scheduler(10.);
+        let start = Instant::now();
+        assert!(scheduler.frame_due(start));
+        assert!(scheduler.take_dirty());
+        scheduler.rendered(start);
+        assert!(!scheduler.frame_due(start + Duration::from_millis(50)));
+        assert!(scheduler.frame_due(start + Duration::from_millis(100)));
+        assert_eq!(scheduler.next_wakeup(start, true), start + Duration::from_millis(100));
+        assert_eq!(scheduler.presentation_time(start), start + Duration::from_millis(100));
+        // nothing moves, only the idle polling
+        assert_eq!(scheduler.next_wakeup(start, false), start + IDLE_INTERVAL);
+
+        scheduler.set_occluded(true);
+        assert!(!scheduler.frame_due(start + Duration::from_secs(1)));
+        assert_eq!(scheduler.next_wakeup(start, true), start + IDLE_INTERVAL);
+        scheduler.set_occluded(false);
+        assert!(scheduler.take_dirty());
+
+        scheduler.set_paused(true);
+        assert!(!scheduler.frame_due(start + Duration::from_secs(1)));
+        scheduler.set_paused(false);
+        assert!(scheduler.frame_due(start + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn frames_per_second() {
+        let mut scheduler = scheduler(60.);
+        let start = Instant::now();
+        for i in 0..30 {
+            scheduler.rendered(start + Duration::from_millis(i * 50));
+        }
+        // the frames of the last second, from 0.5 s to 1.45 s
+        assert_eq!(scheduler.fps(start + Duration::from_millis(1450)), 20);
+        assert_eq!(scheduler.fps(start + Duration::from_secs(5)), 0);
+    }
+
+    #[test]
+    fn battery_detection() {
+        let root = std::env::temp_dir().join(format!("wallfuck-power-{}", std::process::id()));
+        let supply = |name: &str, kind: &str, online: &str| {
+            let path = root.join(name);
+            fs::create_dir_all(&path).unwrap();
+            fs::write(path.join("type"), kind).unwrap();
+            fs::write(path.join("online"), online).unwrap();
+        };
+        supply("BAT0", "Battery\n", "");
+        supply("AC", "Mains\n", "1\n");
+        assert!(!is_on_battery(&root));
+        supply("AC", "Mains\n", "0\n");
+        assert!(is_on_battery(&root));
+        fs::remove_dir_all(&root).unwrap();
+        // desktops without power supplies
+        assert!(!is_on_battery(&root));
+    }
+}
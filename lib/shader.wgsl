@@ -22,7 +22,7 @@ fn vs_main(
 ) -> VertexOutput {
     var out: VertexOutput;
     out.tex_coords = model.tex_coords;
-    out.clip_position = vec4<f32>(model.position, 1.0);
+    out.clip_position = camera.view_proj * vec4<f32>(model.position, 1.0);
     return out;
 }
 
@@ -30,7 +30,39 @@ fn vs_main(
 var t_diffuse: texture_2d<f32>;
 @group(0) @binding(1)
 var s_diffuse: sampler;
+
+const TONEMAP_NONE: u32 = 0u;
+const TONEMAP_REINHARD: u32 = 1u;
+const TONEMAP_ACES: u32 = 2u;
+
+// Narkowicz's fit of the ACES filmic curve
+fn aces(color: vec3<f32>) -> vec3<f32> {
+    return clamp(
+        (color * (2.51 * color + 0.03)) / (color * (2.43 * color + 0.59) + 0.14),
+        vec3<f32>(0.0),
+        vec3<f32>(1.0),
+    );
+}
+
+// High dynamic range wallpapers are linear and can go over 1, the others
+// are left untouched as tonemap is 0 for them
+fn tonemap(color: vec3<f32>) -> vec3<f32> {
+    let exposed = color * exp2(globals.exposure);
+    switch globals.tonemap {
+        case TONEMAP_REINHARD: {
+            return exposed / (exposed + 1.0);
+        }
+        case TONEMAP_ACES: {
+            return aces(exposed);
+        }
+        case TONEMAP_NONE, default: {
+            return exposed;
+        }
+    }
+}
+
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-    return textureSample(t_diffuse, s_diffuse, in.tex_coords);
+    let color = textureSample(t_diffuse, s_diffuse, in.tex_coords);
+    return vec4<f32>(tonemap(color.rgb), color.a);
 }
@@ -0,0 +1,40 @@
+// Prepended to the wallpaper, pass and layer shaders, the bind groups 2 and 3
+// shared by all of them.
+
+struct Globals {
+    resolution: vec2<f32>,
+    mouse: vec2<f32>,
+    time: f32,
+    time_delta: f32,
+    frame: u32,
+    audio_level: f32,
+    audio_transient: f32,
+    date: vec4<f32>,
+    tonemap: u32,
+    exposure: f32,
+    parameters: array<vec4<f32>, 4>,
+}
+@group(2) @binding(0)
+var<uniform> globals: Globals;
+@group(2) @binding(1)
+var t_spectrum: texture_2d<f32>;
+@group(2) @binding(2)
+var s_spectrum: sampler;
+
+@group(3) @binding(0)
+var t_channel0: texture_2d<f32>;
+@group(3) @binding(1)
+var s_channel0: sampler;
+@group(3) @binding(2)
+var t_channel1: texture_2d<f32>;
+@group(3) @binding(3)
+var s_channel1: sampler;
+@group(3) @binding(4)
+var t_channel2: texture_2d<f32>;
+@group(3) @binding(5)
+var s_channel2: sampler;
+@group(3) @binding(6)
+var t_channel3: texture_2d<f32>;
+@group(3) @binding(7)
+var s_channel3: sampler;
+
@@ -0,0 +1,66 @@
+// Composites a layer over the scene: its content is drawn on a transformed
+// quad seen from the camera, faded by the opacity and the mask. The colour
+// is output with premultiplied alpha so every blend mode is a fixed blend
+// state.
+
+struct LayerUniform {
+    // half axes of the quad and its centre, in world units
+    x_axis: vec2<f32>,
+    y_axis: vec2<f32>,
+    centre: vec2<f32>,
+    opacity: f32,
+    // 1 when the content already has its alpha multiplied in
+    premultiplied: u32,
+    // distance in front of the wallpaper plane
+    depth: f32,
+}
+
+@group(0) @binding(0)
+var<uniform> layer: LayerUniform;
+@group(0) @binding(1)
+var t_content: texture_2d<f32>;
+@group(0) @binding(2)
+var s_content: sampler;
+// stretched over the layer, its red channel scales the alpha
+@group(0) @binding(3)
+var t_mask: texture_2d<f32>;
+@group(0) @binding(4)
+var s_mask: sampler;
+
+struct CameraUniform {
+    view_proj: mat4x4<f32>,
+}
+@group(1) @binding(0)
+var<uniform> camera: CameraUniform;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+};
+
+// Two triangles covering the quad, no vertex buffer needed
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    var corners = array<vec2<f32>, 6>(
+        vec2<f32>(0.0, 0.0), vec2<f32>(0.0, 1.0), vec2<f32>(1.0, 1.0),
+        vec2<f32>(0.0, 0.0), vec2<f32>(1.0, 1.0), vec2<f32>(1.0, 0.0),
+    );
+    let uv = corners[index];
+    let local = vec2<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0);
+    var out: VertexOutput;
+    out.uv = uv;
+    let position = layer.centre + layer.x_axis * local.x + layer.y_axis * local.y;
+    out.clip_position = camera.view_proj * vec4<f32>(position, layer.depth, 1.0);
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let color = textureSample(t_content, s_content, in.uv);
+    let fade = layer.opacity * textureSample(t_mask, s_mask, in.uv).r;
+    if layer.premultiplied == 1u {
+        return color * fade;
+    }
+    let alpha = color.a * fade;
+    return vec4<f32>(color.rgb * alpha, alpha);
+}
@@ -0,0 +1,239 @@
+// Particle system: cs_main spawns and moves the particles, vs_main and
+// fs_main draw them as sprites. Positions are in heights of the window from
+// the top left corner, x going up to the aspect ratio.
+
+const MAX_EMITTERS: u32 = 8u;
+const MAX_FORCES: u32 = 8u;
+const MAX_KEYS: u32 = 8u;
+
+const GRAVITY: u32 = 0u;
+const CURL_NOISE: u32 = 1u;
+const ATTRACTOR: u32 = 2u;
+
+struct Emitter {
+    position: vec2<f32>,
+    spread: vec2<f32>,
+    velocity: vec2<f32>,
+    velocity_spread: f32,
+    lifetime: f32,
+    // slots of the particles spawned this frame, wrapping around
+    first: u32,
+    count: u32,
+    _padding0: u32,
+    _padding1: u32,
+}
+
+struct Force {
+    // direction of the gravity or position of the attractor
+    vector: vec2<f32>,
+    kind: u32,
+    strength: f32,
+    scale: f32,
+    _padding0: f32,
+    _padding1: f32,
+    _padding2: f32,
+}
+
+struct System {
+    emitters: array<Emitter, MAX_EMITTERS>,
+    forces: array<Force, MAX_FORCES>,
+    // keys evenly spread over the lifetime of a particle
+    colors: array<vec4<f32>, MAX_KEYS>,
+    sizes: array<vec4<f32>, 2>,
+    time: f32,
+    time_delta: f32,
+    aspect: f32,
+    max_particles: u32,
+    nb_emitters: u32,
+    nb_forces: u32,
+    nb_colors: u32,
+    nb_sizes: u32,
+    // columns and rows of the atlas, 0 for a round sprite
+    atlas: vec2<u32>,
+    frame: u32,
+    _padding: u32,
+}
+
+struct Particle {
+    position: vec2<f32>,
+    velocity: vec2<f32>,
+    age: f32,
+    lifetime: f32,
+    seed: u32,
+    alive: u32,
+}
+
+@group(0) @binding(0)
+var<uniform> system: System;
+@group(0) @binding(1)
+var<storage, read_write> particles: array<Particle>;
+
+// PCG hash
+fn hash(value: u32) -> u32 {
+    let state = value * 747796405u + 2891336453u;
+    let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+fn random(seed: ptr<function, u32>) -> f32 {
+    *seed = hash(*seed);
+    return f32(*seed) / 4294967295.0;
+}
+
+fn value_noise(p: vec2<f32>) -> f32 {
+    let cell = floor(p);
+    let f = fract(p);
+    let u = f * f * (3.0 - 2.0 * f);
+    let seed = vec2<u32>(vec2<i32>(cell));
+    let a = f32(hash(seed.x + hash(seed.y))) / 4294967295.0;
+    let b = f32(hash(seed.x + 1u + hash(seed.y))) / 4294967295.0;
+    let c = f32(hash(seed.x + hash(seed.y + 1u))) / 4294967295.0;
+    let d = f32(hash(seed.x + 1u + hash(seed.y + 1u))) / 4294967295.0;
+    return mix(mix(a, b, u.x), mix(c, d, u.x), u.y);
+}
+
+// Divergence-free flow: the curl of a slowly moving noise potential
+fn curl(p: vec2<f32>) -> vec2<f32> {
+    let epsilon = 0.01;
+    let offset = vec2<f32>(system.time * 0.1, 0.0);
+    let dx = value_noise(p + offset + vec2<f32>(epsilon, 0.0)) - value_noise(p + offset - vec2<f32>(epsilon, 0.0));
+    let dy = value_noise(p + offset + vec2<f32>(0.0, epsilon)) - value_noise(p + offset - vec2<f32>(0.0, epsilon));
+    return vec2<f32>(dy, -dx) / (2.0 * epsilon);
+}
+
+fn acceleration(particle: Particle) -> vec2<f32> {
+    var total = vec2<f32>(0.0);
+    for (var i = 0u; i < system.nb_forces; i++) {
+        let force = system.forces[i];
+        switch force.kind {
+            case GRAVITY: {
+                total += force.vector * force.strength;
+            }
+            case CURL_NOISE: {
+                total += curl(particle.position * force.scale) * force.strength;
+            }
+            case ATTRACTOR: {
+                let offset = force.vector - particle.position;
+                let distance = max(length(offset), 0.05);
+                total += offset / distance * force.strength / distance;
+            }
+            default: {}
+        }
+    }
+    return total;
+}
+
+fn spawn(emitter: Emitter, index: u32) -> Particle {
+    var seed = hash(index ^ hash(system.frame));
+    var particle: Particle;
+    particle.position = emitter.position + (vec2<f32>(random(&seed), random(&seed)) - 0.5) * emitter.spread;
+    let angle = random(&seed) * 6.2831853;
+    particle.velocity = emitter.velocity + vec2<f32>(cos(angle), sin(angle)) * emitter.velocity_spread * random(&seed);
+    particle.age = 0.0;
+    particle.lifetime = emitter.lifetime;
+    particle.seed = hash(seed);
+    particle.alive = 1u;
+    return particle;
+}
+
+@compute @workgroup_size(64)
+fn cs_main(@builtin(global_invocation_id) id: vec3<u32>) {
+    let index = id.x;
+    if index >= system.max_particles {
+        return;
+    }
+    for (var i = 0u; i < system.nb_emitters; i++) {
+        let emitter = system.emitters[i];
+        if (index + system.max_particles - emitter.first) % system.max_particles < emitter.count {
+            particles[index] = spawn(emitter, index);
+            return;
+        }
+    }
+
+    var particle = particles[index];
+    if particle.alive == 0u {
+        return;
+    }
+    particle.age += system.time_delta;
+    if particle.age >= particle.lifetime {
+        particle.alive = 0u;
+    } else {
+        particle.velocity += acceleration(particle) * system.time_delta;
+        particle.position += particle.velocity * system.time_delta;
+    }
+    particles[index] = particle;
+}
+
+@group(0) @binding(2)
+var t_atlas: texture_2d<f32>;
+@group(0) @binding(3)
+var s_atlas: sampler;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+    @location(1) color: vec4<f32>,
+};
+
+// Linear interpolation between evenly spread keys
+fn color_at(t: f32) -> vec4<f32> {
+    let x = t * f32(system.nb_colors - 1u);
+    let key = min(u32(x), system.nb_colors - 1u);
+    return mix(system.colors[key], system.colors[min(key + 1u, system.nb_colors - 1u)], fract(x));
+}
+
+fn size_key(key: u32) -> f32 {
+    return system.sizes[key / 4u][key % 4u];
+}
+
+fn size_at(t: f32) -> f32 {
+    let x = t * f32(system.nb_sizes - 1u);
+    let key = min(u32(x), system.nb_sizes - 1u);
+    return mix(size_key(key), size_key(min(key + 1u, system.nb_sizes - 1u)), fract(x));
+}
+
+// A sprite per instance, the particle buffer being the instance buffer
+@vertex
+fn vs_main(
+    @builtin(vertex_index) vertex: u32,
+    @location(0) position: vec2<f32>,
+    @location(1) age: f32,
+    @location(2) lifetime: f32,
+    @location(3) seed: u32,
+    @location(4) alive: u32,
+) -> VertexOutput {
+    var corners = array<vec2<f32>, 6>(
+        vec2<f32>(0.0, 0.0), vec2<f32>(0.0, 1.0), vec2<f32>(1.0, 1.0),
+        vec2<f32>(0.0, 0.0), vec2<f32>(1.0, 1.0), vec2<f32>(1.0, 0.0),
+    );
+    let corner = corners[vertex];
+    let t = clamp(age / lifetime, 0.0, 1.0);
+    // dead particles collapse to nothing
+    let size = select(0.0, size_at(t), alive == 1u);
+    let centre = vec2<f32>(position.x / system.aspect * 2.0 - 1.0, 1.0 - position.y * 2.0);
+    let offset = (corner * 2.0 - 1.0) * vec2<f32>(size / system.aspect, -size);
+
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(centre + offset, 0.0, 1.0);
+    out.color = color_at(t);
+    if system.atlas.x == 0u {
+        out.uv = corner;
+    } else {
+        let tile = seed % (system.atlas.x * system.atlas.y);
+        let cell = vec2<f32>(f32(tile % system.atlas.x), f32(tile / system.atlas.x));
+        out.uv = (cell + corner) / vec2<f32>(system.atlas);
+    }
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    var color = in.color;
+    if system.atlas.x == 0u {
+        color.a *= 1.0 - smoothstep(0.5, 1.0, length(in.uv * 2.0 - 1.0));
+    } else {
+        color *= textureSample(t_atlas, s_atlas, in.uv);
+    }
+    // premultiplied, like the other layers drawn in a target
+    return vec4<f32>(color.rgb * color.a, color.a);
+}
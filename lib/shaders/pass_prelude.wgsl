@@ -0,0 +1,18 @@
+// Prepended to the shaders of the render graph passes after globals.wgsl, the
+// declarations of the inputs (t_input0, s_input0, ...) are generated after it.
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    // from 0 to 1, (0, 0) being the top left corner like texture coordinates
+    @location(0) uv: vec2<f32>,
+};
+
+// Full screen triangle, no vertex buffer needed
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    var out: VertexOutput;
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    out.uv = uv;
+    out.clip_position = vec4<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, 0.0, 1.0);
+    return out;
+}
@@ -0,0 +1,10 @@
+// Adds the blurred highlights of t_input1 on top of t_input0, the bloom
+// follows the transients of the audio.
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let scene = textureSample(t_input0, s_input0, in.uv);
+    let blur = textureSample(t_input1, s_input1, in.uv);
+    let highlights = max(blur - vec4<f32>(0.5), vec4<f32>(0.0));
+    return scene + highlights * (1.0 + 4.0 * globals.audio_transient);
+}
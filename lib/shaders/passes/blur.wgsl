@@ -0,0 +1,17 @@
+// Blur of t_input0, better used on a target with a scale below 1.
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let texel = 1.0 / vec2<f32>(textureDimensions(t_input0));
+    var color = vec4<f32>(0.0);
+    var total = 0.0;
+    for (var x = -2; x <= 2; x++) {
+        for (var y = -2; y <= 2; y++) {
+            let weight = exp(-f32(x * x + y * y) / 4.0);
+            let offset = vec2<f32>(f32(x), f32(y)) * texel * 2.0;
+            color += weight * textureSample(t_input0, s_input0, in.uv + offset);
+            total += weight;
+        }
+    }
+    return color / total;
+}
@@ -0,0 +1,10 @@
+// Feedback: t_input0 is the scene and t_input1 the previous frame of this
+// pass, which is zoomed in and faded so bright parts leave trails.
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let scene = textureSample(t_input0, s_input0, in.uv);
+    let previous_uv = (in.uv - vec2<f32>(0.5)) * 0.99 + vec2<f32>(0.5);
+    let previous = textureSample(t_input1, s_input1, previous_uv);
+    return max(scene, previous * (0.9 + 0.08 * globals.audio_level));
+}
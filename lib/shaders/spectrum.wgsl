@@ -0,0 +1,56 @@
+// Example of an audio-reactive wallpaper: the wallpaper pulses with the
+// transients and the spectrum is drawn as bars at the bottom.
+
+// Vertex shader
+
+struct CameraUniform {
+    view_proj: mat4x4<f32>,
+}
+@group(1) @binding(0)
+var<uniform> camera: CameraUniform;
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) tex_coords: vec2<f32>,
+}
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+};
+
+@vertex
+fn vs_main(
+    model: VertexInput,
+) -> VertexOutput {
+    var out: VertexOutput;
+    out.tex_coords = model.tex_coords;
+    out.clip_position = camera.view_proj * vec4<f32>(model.position, 1.0);
+    return out;
+}
+
+// Fragment shader
+
+@group(0) @binding(0)
+var t_diffuse: texture_2d<f32>;
+@group(0) @binding(1)
+var s_diffuse: sampler;
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    // screen coordinates from 0 to 1, (0, 0) being the bottom left corner
+    let uv = vec2<f32>(in.clip_position.x, globals.resolution.y - in.clip_position.y)
+        / globals.resolution;
+
+    var color = textureSample(t_diffuse, s_diffuse, in.tex_coords).rgb;
+    color *= 0.6 + 0.4 * globals.audio_transient;
+
+    // logarithmic frequency axis, the lowest bins are too wide to be interesting
+    let frequency = exp2(mix(-7.0, 0.0, uv.x));
+    let magnitude = textureSample(t_spectrum, s_spectrum, vec2<f32>(frequency, 0.25)).r;
+    if (uv.y < magnitude * 0.3) {
+        let hue = vec3<f32>(uv.x, 0.5 + 0.5 * sin(globals.time), 1.0 - uv.x);
+        color = mix(color, hue, 0.7);
+    }
+    return vec4<f32>(color, 1.0);
+}
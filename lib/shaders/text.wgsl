@@ -0,0 +1,33 @@
+// Text layer: a quad per glyph, its coverage read from the red channel of
+// the glyph atlas. The colour is output with premultiplied alpha like the
+// other layers drawn in a target.
+
+struct TextUniform {
+    color: vec4<f32>,
+}
+
+@group(0) @binding(0)
+var<uniform> text: TextUniform;
+@group(0) @binding(1)
+var t_atlas: texture_2d<f32>;
+@group(0) @binding(2)
+var s_atlas: sampler;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+};
+
+@vertex
+fn vs_main(@location(0) position: vec2<f32>, @location(1) uv: vec2<f32>) -> VertexOutput {
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(position, 0.0, 1.0);
+    out.uv = uv;
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let alpha = text.color.a * textureSample(t_atlas, s_atlas, in.uv).r;
+    return vec4<f32>(text.color.rgb * alpha, alpha);
+}
@@ -0,0 +1,85 @@
+// Blends the previous and the next wallpaper of a slideshow into a texture
+// the size of the window, which is then drawn like any wallpaper.
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+};
+
+// Full screen triangle, no vertex buffer needed
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    var out: VertexOutput;
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    out.uv = uv;
+    out.clip_position = vec4<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, 0.0, 1.0);
+    return out;
+}
+
+const CROSSFADE: u32 = 0u;
+const SLIDE: u32 = 1u;
+const WIPE: u32 = 2u;
+const DISSOLVE: u32 = 3u;
+
+struct TransitionUniform {
+    // from screen to texture coordinates, keeping the aspect ratio of the
+    // images like the wallpaper plane does
+    from_scale: vec2<f32>,
+    to_scale: vec2<f32>,
+    progress: f32,
+    kind: u32,
+}
+
+@group(0) @binding(0)
+var t_from: texture_2d<f32>;
+@group(0) @binding(1)
+var t_to: texture_2d<f32>;
+@group(0) @binding(2)
+var s_wallpaper: sampler;
+@group(0) @binding(3)
+var<uniform> transition: TransitionUniform;
+
+fn fit(uv: vec2<f32>, scale: vec2<f32>) -> vec2<f32> {
+    return (uv - 0.5) * scale + 0.5;
+}
+
+fn inside(uv: vec2<f32>) -> bool {
+    return all(uv >= vec2<f32>(0.0)) && all(uv <= vec2<f32>(1.0));
+}
+
+fn hash(p: vec2<f32>) -> f32 {
+    return fract(sin(dot(p, vec2<f32>(12.9898, 78.233))) * 43758.5453);
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let progress = smoothstep(0.0, 1.0, transition.progress);
+    var from_uv = fit(in.uv, transition.from_scale);
+    var to_uv = fit(in.uv, transition.to_scale);
+    if (transition.kind == SLIDE) {
+        // the next wallpaper comes from the right and pushes the previous one
+        from_uv = fit(in.uv + vec2<f32>(progress, 0.0), transition.from_scale);
+        to_uv = fit(in.uv - vec2<f32>(1.0 - progress, 0.0), transition.to_scale);
+    }
+    // sampled before any branching, texture sampling needs uniform control flow
+    let previous = select(vec4<f32>(0.0, 0.0, 0.0, 1.0), textureSample(t_from, s_wallpaper, from_uv), inside(from_uv));
+    let next = select(vec4<f32>(0.0, 0.0, 0.0, 1.0), textureSample(t_to, s_wallpaper, to_uv), inside(to_uv));
+
+    var factor = progress;
+    switch transition.kind {
+        case SLIDE: {
+            factor = select(0.0, 1.0, in.uv.x > 1.0 - progress);
+        }
+        case WIPE: {
+            // soft edge going from left to right
+            let width = 0.05;
+            let edge = progress * (1.0 + width);
+            factor = 1.0 - smoothstep(edge - width, edge, in.uv.x);
+        }
+        case DISSOLVE: {
+            factor = select(0.0, 1.0, hash(floor(in.clip_position.xy)) < progress);
+        }
+        default: {}
+    }
+    return mix(previous, next, factor);
+}
@@ -0,0 +1,91 @@
+// Audio visualisers drawn over the wallpaper, each one in its own rectangle.
+// The data texture holds a value from 0 to 1 per band (or per point of the
+// waveform), the spectrogram keeps a column per update.
+
+const BARS: u32 = 0u;
+const WAVEFORM: u32 = 1u;
+const RADIAL: u32 = 2u;
+const SPECTROGRAM: u32 = 3u;
+
+struct VisualiserUniform {
+    // left, top, width, height, from 0 to 1 with (0, 0) the top left corner
+    rect: vec4<f32>,
+    color: vec4<f32>,
+    // size of the rectangle in pixels
+    pixels: vec2<f32>,
+    // spectrogram column written last
+    head: u32,
+    kind: u32,
+    bands: u32,
+}
+
+@group(0) @binding(0)
+var<uniform> visualiser: VisualiserUniform;
+@group(0) @binding(1)
+var t_data: texture_2d<f32>;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    // from 0 to 1 in the rectangle, (0, 0) being the top left corner
+    @location(0) uv: vec2<f32>,
+};
+
+// Two triangles covering the rectangle, no vertex buffer needed
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    var corners = array<vec2<f32>, 6>(
+        vec2<f32>(0.0, 0.0), vec2<f32>(0.0, 1.0), vec2<f32>(1.0, 1.0),
+        vec2<f32>(0.0, 0.0), vec2<f32>(1.0, 1.0), vec2<f32>(1.0, 0.0),
+    );
+    let uv = corners[index];
+    let position = visualiser.rect.xy + uv * visualiser.rect.zw;
+    var out: VertexOutput;
+    out.uv = uv;
+    out.clip_position = vec4<f32>(position.x * 2.0 - 1.0, 1.0 - position.y * 2.0, 0.0, 1.0);
+    return out;
+}
+
+fn band_value(band: u32) -> f32 {
+    return textureLoad(t_data, vec2<u32>(min(band, visualiser.bands - 1u), 0u), 0).r;
+}
+
+// Bars with a gap between them, filled from the bottom
+fn bar(position: f32, height: f32) -> f32 {
+    let band = u32(position * f32(visualiser.bands));
+    let inside_bar = fract(position * f32(visualiser.bands)) < 0.8;
+    return select(0.0, 1.0, inside_bar && height < band_value(band));
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    var coverage = 0.0;
+    switch visualiser.kind {
+        case BARS: {
+            coverage = bar(in.uv.x, 1.0 - in.uv.y);
+        }
+        case WAVEFORM: {
+            // line of about 2 pixels between the points
+            let x = in.uv.x * f32(visualiser.bands - 1u);
+            let point = u32(x);
+            let value = mix(band_value(point), band_value(point + 1u), fract(x));
+            let distance = abs(1.0 - in.uv.y - value) * visualiser.pixels.y;
+            coverage = 1.0 - smoothstep(1.0, 2.0, distance);
+        }
+        case RADIAL: {
+            // bars around a circle fitting the rectangle, clockwise from the top
+            let p = (in.uv - 0.5) * visualiser.pixels / min(visualiser.pixels.x, visualiser.pixels.y) * 2.0;
+            let angle = fract(atan2(p.x, -p.y) / 6.2831853 + 1.0);
+            let height = (length(p) - 0.5) * 2.0;
+            coverage = select(0.0, bar(angle, height), height >= 0.0);
+        }
+        case SPECTROGRAM: {
+            // the newest column on the right, low frequencies at the bottom
+            let columns = textureDimensions(t_data).x;
+            let column = (u32(in.uv.x * f32(columns)) + visualiser.head + 1u) % columns;
+            let band = min(u32((1.0 - in.uv.y) * f32(visualiser.bands)), visualiser.bands - 1u);
+            coverage = textureLoad(t_data, vec2<u32>(column, band), 0).r;
+        }
+        default: {}
+    }
+    return vec4<f32>(visualiser.color.rgb, visualiser.color.a * coverage);
+}
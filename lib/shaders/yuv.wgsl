@@ -0,0 +1,57 @@
+// Converts the planes of a video frame to RGB, using BT.601 like JPEG and
+// most YUV4MPEG2 streams.
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+};
+
+// Full screen triangle, no vertex buffer needed
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    var out: VertexOutput;
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    out.uv = uv;
+    out.clip_position = vec4<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, 0.0, 1.0);
+    return out;
+}
+
+struct Conversion {
+    full_range: u32,
+}
+
+@group(0) @binding(0)
+var t_y: texture_2d<f32>;
+@group(0) @binding(1)
+var t_u: texture_2d<f32>;
+@group(0) @binding(2)
+var t_v: texture_2d<f32>;
+@group(0) @binding(3)
+var s_planes: sampler;
+@group(0) @binding(4)
+var<uniform> conversion: Conversion;
+
+// The target is sRGB so the hardware encodes what we output
+fn srgb_to_linear(color: vec3<f32>) -> vec3<f32> {
+    let low = color / 12.92;
+    let high = pow((color + 0.055) / 1.055, vec3<f32>(2.4));
+    return select(high, low, color <= vec3<f32>(0.04045));
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    var y = textureSample(t_y, s_planes, in.uv).r;
+    var u = textureSample(t_u, s_planes, in.uv).r - 128.0 / 255.0;
+    var v = textureSample(t_v, s_planes, in.uv).r - 128.0 / 255.0;
+    if (conversion.full_range == 0u) {
+        y = (y - 16.0 / 255.0) * 255.0 / 219.0;
+        u *= 255.0 / 224.0;
+        v *= 255.0 / 224.0;
+    }
+    let rgb = vec3<f32>(
+        y + 1.402 * v,
+        y - 0.344136 * u - 0.714136 * v,
+        y + 1.772 * u,
+    );
+    return vec4<f32>(srgb_to_linear(clamp(rgb, vec3<f32>(0.0), vec3<f32>(1.0))), 1.0);
+}
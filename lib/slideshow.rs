@@ -0,0 +1,243 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use anyhow::{bail, Context, Result};
+use rand::{thread_rng, Rng};
+use rand::rngs::ThreadRng;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+
+use crate::transition::Transition;
+use crate::{decode, video};
+
+/// Wallpapers remembered to go back to
+const HISTORY: usize = 64;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Order {
+    #[default]
+    Sequential,
+    /// Random, but never the same wallpaper twice in a row
+    Shuffle,
+    /// Random, every wallpaper is shown once before any of them comes back
+    ShuffleWithoutRepeat,
+}
+
+fn default_interval() -> f32 {
+    300.
+}
+fn default_transition_duration() -> f32 {
+    1.5
+}
+
+/// A slideshow as written in the config.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SlideshowDescription {
+    /// Directory of wallpapers, or playlist with a path per line (relative
+    /// to the playlist, lines starting with # are ignored like in M3U files)
+    pub source: PathBuf,
+    /// Seconds each wallpaper is shown
+    #[serde(default = "default_interval")]
+    pub interval: f32,
+    #[serde(default)]
+    pub order: Order,
+    #[serde(default)]
+    pub transition: Transition,
+    /// Seconds the transition lasts
+    #[serde(default = "default_transition_duration")]
+    pub transition_duration: f32,
+}
+
+fn list_wallpapers(source: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths: Vec<PathBuf> = if source.is_dir() {
+        let mut paths: Vec<PathBuf> = fs::read_dir(source)
+            .with_context(|| format!("Failed to list {}", source.display()))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            // anything a wallpaper can be
+            .filter(|path| decode::is_image(path) || video::is_video(path))
+            .collect();
+        paths.sort();
+        paths
+    } else {
+        let playlist = fs::read_to_string(source)
+            .with_context(|| format!("Failed to read playlist {}", source.display()))?;
+        let directory = source.parent().unwrap_or(Path::new(""));
+        playlist.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| directory.join(line))
+            .collect()
+    };
+    paths.dedup();
+    if paths.is_empty() {
+        bail!("No wallpaper found in {}", source.display());
+    }
+    Ok(paths)
+}
+
+/// Picks the wallpapers of a slideshow and when to change them.
+pub struct Slideshow {
+    paths: Vec<PathBuf>,
+    order: Order,
+    interval: Duration,
+    current: usize,
+    /// Indices left to show before shuffling again
+    bag: Vec<usize>,
+    /// Indices shown before the current one, the latest last
+    history: Vec<usize>,
+    changed_at: Instant,
+    rng: ThreadRng,
+}
+
+impl Slideshow {
+    pub fn new(description: &SlideshowDescription) -> Result<Self> {
+        if description.interval <= 0. {
+            bail!("The interval of the slideshow has to be positive");
+        }
+        let paths = list_wallpapers(&description.source)?;
+        Ok(Self::from_paths(paths, description.order, Duration::from_secs_f32(description.interval)))
+    }
+
+    fn from_paths(paths: Vec<PathBuf>, order: Order, interval: Duration) -> Self {
+        let mut slideshow = Self {
+            paths,
+            order,
+            interval,
+            current: 0,
+            bag: Vec::new(),
+            history: Vec::new(),
+            changed_at: Instant::now(),
+            rng: thread_rng(),
+        };
+        match order {
+            Order::Sequential => (),
+            Order::Shuffle => slideshow.current = slideshow.rng.gen_range(0..slideshow.paths.len()),
+            Order::ShuffleWithoutRepeat => slideshow.current = slideshow.draw_from_bag(),
+        }
+        slideshow
+    }
+
+    pub fn current(&self) -> &Path {
+        &self.paths[self.current]
+    }
+
+    /// The next wallpaper once the current one has been shown long enough.
+    pub fn poll(&mut self) -> Option<PathBuf> {
+        if self.changed_at.elapsed() < self.interval {
+            return None;
+        }
+        Some(self.next().to_path_buf())
+    }
+
+    /// Skips to the next wallpaper right away.
+    pub fn next(&mut self) -> &Path {
+        let count = self.paths.len();
+        if self.history.len() == HISTORY {
+            self.history.remove(0);
+        }
+        self.history.push(self.current);
+        self.current = match self.order {
+            Order::Sequential => (self.current + 1) % count,
+            Order::Shuffle if count > 1 => {
+                // drawing among the others so the wallpaper always changes
+                let index = self.rng.gen_range(0..count - 1);
+                if index >= self.current { index + 1 } else { index }
+            },
+            Order::Shuffle => 0,
+            Order::ShuffleWithoutRepeat => self.draw_from_bag(),
+        };
+        self.changed_at = Instant::now();
+        self.current()
+    }
+
+    /// Goes back to the wallpaper shown before, or the one before in the
+    /// list once the history is exhausted.
+    pub fn previous(&mut self) -> &Path {
+        let count = self.paths.len();
+        self.current = self.history.pop().unwrap_or((self.current + count - 1) % count);
+        self.changed_at = Instant::now();
+        self.current()
+    }
+
+    fn draw_from_bag(&mut self) -> usize {
+        if self.bag.is_empty() {
+            self.bag = (0..self.paths.len()).collect();
+            self.bag.shuffle(&mut self.rng);
+            // the last of a round can't be the first of the next one
+            if self.bag.len() > 1 && self.bag.last() == Some(&self.current) {
+                self.bag.swap(0, self.paths.len() - 1);
+            }
+        }
+        self.bag.pop().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slideshow(order: Order) -> Slideshow {
+        let paths = (0..5).map(|i| PathBuf::from(format!("{}.png", i))).collect();
+        Slideshow::from_paths(paths, order, Duration::from_secs(60))
+    }
+
+    #[test]
+    fn sequential_order_loops() {
+        let mut slideshow = slideshow(Order::Sequential);
+        assert_eq!(slideshow.current(), Path::new("0.png"));
+        let shown: Vec<PathBuf> = (0..6).map(|_| slideshow.next().to_path_buf()).collect();
+        assert_eq!(shown[0], Path::new("1.png"));
+        assert_eq!(shown[5], Path::new("1.png"));
+        assert!(slideshow.poll().is_none());
+    }
+
+    #[test]
+    fn shuffle_never_repeats_in_a_row() {
+        let mut slideshow = slideshow(Order::Shuffle);
+        for _ in 0..100 {
+            let previous = slideshow.current().to_path_buf();
+            assert_ne!(slideshow.next(), previous);
+        }
+    }
+
+    #[test]
+    fn shuffle_without_repeat_shows_everything() {
+        let mut slideshow = slideshow(Order::ShuffleWithoutRepeat);
+        let mut previous = slideshow.current().to_path_buf();
+        let mut round = vec![previous.clone()];
+        for _ in 0..50 {
+            let path = slideshow.next().to_path_buf();
+            assert_ne!(path, previous);
+            round.push(path.clone());
+            if round.len() == 5 {
+                round.sort();
+                round.dedup();
+                assert_eq!(round.len(), 5);
+                round.clear();
+            }
+            previous = path;
+        }
+    }
+
+    #[test]
+    fn previous_goes_back_through_the_history() {
+        let mut shuffled = slideshow(Order::Shuffle);
+        let first = shuffled.current().to_path_buf();
+        let second = shuffled.next().to_path_buf();
+        shuffled.next();
+        assert_eq!(shuffled.previous(), second);
+        assert_eq!(shuffled.previous(), first);
+
+        let mut sequential = slideshow(Order::Sequential);
+        assert_eq!(sequential.previous(), Path::new("4.png"));
+    }
+
+    #[test]
+    fn playlist() {
+        let path = std::env::temp_dir().join(format!("wallfuck-playlist-{}.m3u", std::process::id()));
+        fs::write(&path, "#EXTM3U\nsunset.jpg\n\n  /wallpapers/space.png \n").unwrap();
+        let paths = list_wallpapers(&path).unwrap();
+        assert_eq!(paths, vec![std::env::temp_dir().join("sunset.jpg"), PathBuf::from("/wallpapers/space.png")]);
+        fs::remove_file(&path).unwrap();
+    }
+}
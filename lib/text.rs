@@ -0,0 +1,412 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::format::{Item, StrftimeItems};
+use fontdue::layout::{CoordinateSystem, GlyphRasterConfig, Layout, TextStyle};
+use serde::{Deserialize, Serialize};
+
+use crate::mpris::NowPlaying;
+use crate::texture::Texture;
+
+const TEXT_SHADER: &str = include_str!("shaders/text.wgsl");
+/// Width and height of the glyph atlas
+const ATLAS_SIZE: u32 = 1024;
+/// Glyphs past this are left out
+const MAX_GLYPHS: usize = 512;
+
+/// Side of the text put on its position, the lines being aligned to the
+/// same side.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Align {
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    #[default]
+    Centre,
+    Right,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+}
+
+impl Align {
+    /// Fractions of the width and height of the text before its position
+    fn factors(self) -> [f32; 2] {
+        match self {
+            Align::TopLeft => [0., 0.],
+            Align::Top => [0.5, 0.],
+            Align::TopRight => [1., 0.],
+            Align::Left => [0., 0.5],
+            Align::Centre => [0.5, 0.5],
+            Align::Right => [1., 0.5],
+            Align::BottomLeft => [0., 1.],
+            Align::Bottom => [0.5, 1.],
+            Align::BottomRight => [1., 1.],
+        }
+    }
+}
+
+/// A text as written in the config.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TextDescription {
+    /// TTF or OTF file
+    pub font: PathBuf,
+    /// Formatted with the strftime codes of the local time, `%%` for a `%`,
+    /// and `{title}`, `{artist}`, `{album}` or `{status}` of the active player
+    #[serde(default = "default_text")]
+    pub text: String,
+    /// Height of a line as a fraction of the window
+    #[serde(default = "default_size")]
+    pub size: f32,
+    /// RGBA from 0 to 1
+    #[serde(default = "default_color")]
+    pub color: [f32; 4],
+    /// From (0, 0) at the top left of the window to (1, 1)
+    #[serde(default = "default_position")]
+    pub position: [f32; 2],
+    #[serde(default)]
+    pub align: Align,
+}
+fn default_text() -> String {
+    "%H:%M".to_string()
+}
+fn default_size() -> f32 {
+    0.1
+}
+fn default_color() -> [f32; 4] {
+    [1., 1., 1., 1.]
+}
+fn default_position() -> [f32; 2] {
+    [0.5, 0.5]
+}
+
+/// Top left corner of a block of text of `block` pixels, `position` being a
+/// fraction of the window
+fn origin(align: Align, position: [f32; 2], block: [f32; 2], window: [f32; 2]) -> [f32; 2] {
+    let factors = align.factors();
+    // whole pixels, so the glyphs are sampled texel for texel
+    [0, 1].map(|i| (position[i] * window[i] - block[i] * factors[i]).round())
+}
+
+/// Rows of glyphs packed from the top left of the atlas
+#[derive(Default)]
+struct Shelves {
+    cursor: [u32; 2],
+    row_height: u32,
+}
+
+impl Shelves {
+    /// Top left corner of a free rectangle, with a texel between glyphs so
+    /// they don't bleed into each other
+    fn allocate(&mut self, width: u32, height: u32) -> Option<[u32; 2]> {
+        if self.cursor[0] + width > ATLAS_SIZE {
+            self.cursor = [0, self.cursor[1] + self.row_height + 1];
+            self.row_height = 0;
+        }
+        if self.cursor[0] + width > ATLAS_SIZE || self.cursor[1] + height > ATLAS_SIZE {
+            return None;
+        }
+        let corner = self.cursor;
+        self.cursor[0] += width + 1;
+        self.row_height = self.row_height.max(height);
+        Some(corner)
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct TextVertex {
+    position: [f32; 2],
+    uv: [f32; 2],
+}
+
+/// Draws a text over a transparent target, laid out again only when the
+/// formatted text or the size of the window changes.
+pub struct TextRenderer {
+    description: TextDescription,
+    font: fontdue::Font,
+    atlas: Texture,
+    shelves: Shelves,
+    /// Corner and size of the glyphs in the atlas
+    glyphs: HashMap<GlyphRasterConfig, [u32; 4]>,
+    pipeline: wgpu::RenderPipeline,
+    vertex_buffer: wgpu::Buffer,
+    nb_vertices: u32,
+    bind_group: wgpu::BindGroup,
+    text: String,
+    size: winit::dpi::PhysicalSize<u32>,
+}
+
+impl TextRenderer {
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        description: &TextDescription,
+        format: wgpu::TextureFormat,
+    ) -> Result<Self> {
+        if StrftimeItems::new(&description.text).any(|item| item == Item::Error) {
+            bail!("Invalid format in the text {:?}", description.text);
+        }
+        let data = std::fs::read(&description.font)
+            .with_context(|| format!("Failed to read {}", description.font.display()))?;
+        let font = fontdue::Font::from_bytes(data, fontdue::FontSettings::default())
+            .map_err(|error| anyhow!("Failed to load {}: {}", description.font.display(), error))?;
+
+        let atlas = Texture::new(device, (ATLAS_SIZE, ATLAS_SIZE), wgpu::TextureFormat::R8Unorm, false, Some("glyph-atlas"));
+        let uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Text Buffer"),
+            size: std::mem::size_of::<[f32; 4]>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        queue.write_buffer(&uniform_buffer, 0, bytemuck::cast_slice(&description.color));
+        let vertex_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Text Vertex Buffer"),
+            size: (MAX_GLYPHS * 6 * std::mem::size_of::<TextVertex>()) as u64,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+            label: Some("text_bind_group_layout"),
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&atlas.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(&atlas.sampler),
+                },
+            ],
+            label: Some("text_bind_group"),
+        });
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Text Shader"),
+            source: wgpu::ShaderSource::Wgsl(TEXT_SHADER.into()),
+        });
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Text Pipeline Layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Text Pipeline"),
+            layout: Some(&layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[wgpu::VertexBufferLayout {
+                    array_stride: std::mem::size_of::<TextVertex>() as wgpu::BufferAddress,
+                    step_mode: wgpu::VertexStepMode::Vertex,
+                    attributes: &wgpu::vertex_attr_array![0 => Float32x2, 1 => Float32x2],
+                }],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format,
+                    blend: Some(wgpu::BlendState::PREMULTIPLIED_ALPHA_BLENDING),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        });
+
+        Ok(Self {
+            description: description.clone(),
+            font,
+            atlas,
+            shelves: Shelves::default(),
+            glyphs: HashMap::new(),
+            pipeline,
+            vertex_buffer,
+            nb_vertices: 0,
+            bind_group,
+            text: String::new(),
+            size: winit::dpi::PhysicalSize::new(0, 0),
+        })
+    }
+
+    /// Formats the text again, true if it has to be drawn again. The
+    /// placeholders are left empty without a player.
+    pub fn update(
+        &mut self,
+        queue: &wgpu::Queue,
+        size: winit::dpi::PhysicalSize<u32>,
+        now_playing: Option<&NowPlaying>,
+    ) -> bool {
+        let items = StrftimeItems::new(&self.description.text);
+        let text = chrono::Local::now().format_with_items(items).to_string();
+        // after the time, so a % in a title is kept as it is
+        let text = match now_playing {
+            Some(playing) => playing.substitute(&text),
+            None => NowPlaying::default().substitute(&text),
+        };
+        if text == self.text && size == self.size {
+            return false;
+        }
+        self.text = text;
+        self.size = size;
+        self.layout(queue);
+        true
+    }
+
+    fn layout(&mut self, queue: &wgpu::Queue) {
+        let window = [self.size.width as f32, self.size.height as f32];
+        let px = (self.description.size * window[1]).max(1.);
+        let mut layout = Layout::new(CoordinateSystem::PositiveYDown);
+        layout.append(&[&self.font], &TextStyle::new(&self.text, px, 0));
+        let glyphs = layout.glyphs();
+        let glyphs = &glyphs[..glyphs.len().min(MAX_GLYPHS)];
+
+        if !self.rasterise(queue, glyphs) {
+            // full of glyphs of older texts or sizes, start over
+            self.glyphs.clear();
+            self.shelves = Shelves::default();
+            if !self.rasterise(queue, glyphs) {
+                log::warn!("The glyphs of {:?} don't fit in the atlas", self.text);
+            }
+        }
+
+        let line_width = |start: usize, end: usize| glyphs[start.min(glyphs.len())..end.min(glyphs.len())]
+            .iter()
+            .filter(|glyph| !glyph.char_data.is_whitespace())
+            .map(|glyph| glyph.x + glyph.width as f32)
+            .fold(0., f32::max);
+        let lines: Vec<(usize, usize, f32)> = layout.lines().into_iter().flatten()
+            .map(|line| (line.glyph_start, line.glyph_end + 1, line_width(line.glyph_start, line.glyph_end + 1)))
+            .collect();
+        let block = [lines.iter().map(|line| line.2).fold(0., f32::max), layout.height()];
+        let align = self.description.align;
+        let [left, top] = origin(align, self.description.position, block, window);
+
+        let mut vertices = Vec::with_capacity(glyphs.len() * 6);
+        for &(start, end, width) in &lines {
+            let offset = left + ((block[0] - width) * align.factors()[0]).round();
+            for glyph in glyphs.iter().take(end).skip(start) {
+                let Some(&[u, v, width, height]) = self.glyphs.get(&glyph.key) else {
+                    continue;
+                };
+                let x = offset + glyph.x;
+                let y = top + glyph.y;
+                let to_clip = |x: f32, y: f32| [x / window[0] * 2. - 1., 1. - y / window[1] * 2.];
+                let to_uv = |u: u32, v: u32| [u as f32 / ATLAS_SIZE as f32, v as f32 / ATLAS_SIZE as f32];
+                let corner = |dx: u32, dy: u32| TextVertex {
+                    position: to_clip(x + dx as f32, y + dy as f32),
+                    uv: to_uv(u + dx, v + dy),
+                };
+                let corners = [corner(0, 0), corner(0, height), corner(width, height), corner(width, 0)];
+                vertices.extend([0, 1, 2, 0, 2, 3].map(|i| corners[i]));
+            }
+        }
+        queue.write_buffer(&self.vertex_buffer, 0, bytemuck::cast_slice(&vertices));
+        self.nb_vertices = vertices.len() as u32;
+    }
+
+    /// Adds the missing glyphs to the atlas, false if it is full
+    fn rasterise(&mut self, queue: &wgpu::Queue, glyphs: &[fontdue::layout::GlyphPosition]) -> bool {
+        for glyph in glyphs {
+            if glyph.width == 0 || glyph.height == 0 || self.glyphs.contains_key(&glyph.key) {
+                continue;
+            }
+            let (metrics, coverage) = self.font.rasterize_config(glyph.key);
+            let (width, height) = (metrics.width as u32, metrics.height as u32);
+            let Some([u, v]) = self.shelves.allocate(width, height) else {
+                return false;
+            };
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    aspect: wgpu::TextureAspect::All,
+                    texture: &self.atlas.texture,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d { x: u, y: v, z: 0 },
+                },
+                &coverage,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(width),
+                    rows_per_image: Some(height),
+                },
+                wgpu::Extent3d { width, height, depth_or_array_layers: 1 },
+            );
+            self.glyphs.insert(glyph.key, [u, v, width, height]);
+        }
+        true
+    }
+
+    pub fn draw<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>) {
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, &self.bind_group, &[]);
+        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+        render_pass.draw(0..self.nb_vertices, 0..1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_placement() {
+        let window = [200., 100.];
+        // centred on the middle of the window
+        assert_eq!(origin(Align::Centre, [0.5, 0.5], [50., 20.], window), [75., 40.]);
+        // hanging from the top right corner
+        assert_eq!(origin(Align::TopRight, [1., 0.], [50., 20.], window), [150., 0.]);
+        assert_eq!(origin(Align::BottomLeft, [0.1, 0.9], [50., 20.], window), [20., 70.]);
+
+        let description: TextDescription = ron::from_str("(font: \"a.ttf\", align: Bottom)").unwrap();
+        assert_eq!(description.text, "%H:%M");
+        assert_eq!(description.align.factors(), [0.5, 1.]);
+    }
+
+    #[test]
+    fn atlas_shelves() {
+        let mut shelves = Shelves::default();
+        assert_eq!(shelves.allocate(1010, 10), Some([0, 0]));
+        assert_eq!(shelves.allocate(20, 5), Some([0, 11]));
+        assert_eq!(shelves.allocate(20, 5), Some([21, 11]));
+        assert_eq!(shelves.allocate(10, ATLAS_SIZE), None);
+    }
+}
@@ -1,33 +1,71 @@
 use image::GenericImageView;
 use anyhow::*;
 
+use crate::decode;
+
+const BLIT_SHADER: &str = include_str!("shaders/blit.wgsl");
+
 pub struct Texture {
     pub dimensions: (u32, u32),
+    pub format: wgpu::TextureFormat,
     pub texture: wgpu::Texture,
     pub view: wgpu::TextureView,
     pub sampler: wgpu::Sampler,
 }
 
 impl Texture {
+    pub const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+    /// Linear colors of 16-bit and float images, which can go over 1
+    pub const HDR_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
     pub fn from_bytes(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
-        bytes: &[u8], 
+        mipmap_generator: &MipmapGenerator,
+        bytes: &[u8],
         label: &str
     ) -> Result<Self> {
-        let img = image::load_from_memory(bytes)?;
-        Self::from_image(device, queue, &img, Some(label))
+        let img = decode::decode(bytes)?;
+        Self::from_image(device, queue, mipmap_generator, &img, Some(label))
     }
 
+    /// Texture with the whole mip chain generated, in `HDR_FORMAT` for high
+    /// dynamic range images
     pub fn from_image(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
+        mipmap_generator: &MipmapGenerator,
         img: &image::DynamicImage,
         label: Option<&str>
     ) -> Result<Self> {
-        let rgba = img.to_rgba8();
-        let dimensions = img.dimensions();
+        let texture = if decode::is_high_dynamic_range(img) {
+            let texture = Self::new(device, img.dimensions(), Self::HDR_FORMAT, true, label);
+            texture.write_linear(queue, &decode::to_linear(img));
+            texture
+        } else {
+            let texture = Self::new(device, img.dimensions(), Self::FORMAT, true, label);
+            texture.write(queue, &img.to_rgba8());
+            texture
+        };
 
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Mipmap Encoder"),
+        });
+        mipmap_generator.generate(device, &mut encoder, &texture);
+        queue.submit(std::iter::once(encoder.finish()));
+        Ok(texture)
+    }
+
+    /// Uninitialized texture to be filled with `write` (`FORMAT`) or
+    /// `write_linear` (`HDR_FORMAT`), the mip levels are then generated with
+    /// a `MipmapGenerator`
+    pub fn new(
+        device: &wgpu::Device,
+        dimensions: (u32, u32),
+        format: wgpu::TextureFormat,
+        mipmapped: bool,
+        label: Option<&str>
+    ) -> Self {
         let size = wgpu::Extent3d {
             width: dimensions.0,
             height: dimensions.1,
@@ -37,45 +75,247 @@ impl Texture {
             &wgpu::TextureDescriptor {
                 label,
                 size,
-                mip_level_count: 1,
+                mip_level_count: if mipmapped { size.max_mips(wgpu::TextureDimension::D2) } else { 1 },
                 sample_count: 1,
                 dimension: wgpu::TextureDimension::D2,
-                format: wgpu::TextureFormat::Rgba8UnormSrgb,
-                usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+                format,
+                // mip levels and video frames are drawn in the texture
+                usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST
+                    | wgpu::TextureUsages::RENDER_ATTACHMENT,
                 view_formats: &[],
             }
         );
 
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        // Trilinear filtering with anisotropy so big images downscaled to
+        // the screen don't shimmer
+        let sampler = device.create_sampler(
+            &wgpu::SamplerDescriptor {
+                address_mode_u: wgpu::AddressMode::ClampToEdge,
+                address_mode_v: wgpu::AddressMode::ClampToEdge,
+                address_mode_w: wgpu::AddressMode::ClampToEdge,
+                mag_filter: wgpu::FilterMode::Linear,
+                min_filter: wgpu::FilterMode::Linear,
+                mipmap_filter: wgpu::FilterMode::Linear,
+                anisotropy_clamp: 16,
+                ..Default::default()
+            }
+        );
+
+        Self { dimensions, format, texture, view, sampler }
+    }
+
+    /// View of a single mip level, to render in it
+    pub fn level_view(&self, level: u32) -> wgpu::TextureView {
+        self.texture.create_view(&wgpu::TextureViewDescriptor {
+            base_mip_level: level,
+            mip_level_count: Some(1),
+            ..Default::default()
+        })
+    }
+
+    pub fn is_high_dynamic_range(&self) -> bool {
+        self.format == Self::HDR_FORMAT
+    }
+
+    /// Replaces the content of the first mip level, the image needs the same dimensions.
+    pub fn write(&self, queue: &wgpu::Queue, rgba: &image::RgbaImage) {
+        self.write_bytes(queue, rgba, 4);
+    }
+
+    /// Same as `write` for `HDR_FORMAT` textures
+    pub fn write_linear(&self, queue: &wgpu::Queue, rgba: &image::Rgba32FImage) {
+        let half: Vec<u16> = rgba.iter().map(|&value| half::f16::from_f32(value).to_bits()).collect();
+        self.write_bytes(queue, bytemuck::cast_slice(&half), 8);
+    }
+
+    fn write_bytes(&self, queue: &wgpu::Queue, bytes: &[u8], bytes_per_pixel: u32) {
         queue.write_texture(
             wgpu::ImageCopyTexture {
                 aspect: wgpu::TextureAspect::All,
-                texture: &texture,
+                texture: &self.texture,
                 mip_level: 0,
                 origin: wgpu::Origin3d::ZERO,
             },
-            &rgba,
+            bytes,
             wgpu::ImageDataLayout {
                 offset: 0,
-                bytes_per_row: Some(4 * dimensions.0),
-                rows_per_image: Some(dimensions.1),
+                bytes_per_row: Some(bytes_per_pixel * self.dimensions.0),
+                rows_per_image: Some(self.dimensions.1),
+            },
+            wgpu::Extent3d {
+                width: self.dimensions.0,
+                height: self.dimensions.1,
+                depth_or_array_layers: 1,
             },
-            size,
         );
+    }
+}
 
-        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
-        let sampler = device.create_sampler(
-            &wgpu::SamplerDescriptor {
-                address_mode_u: wgpu::AddressMode::ClampToEdge,
-                address_mode_v: wgpu::AddressMode::ClampToEdge,
-                address_mode_w: wgpu::AddressMode::ClampToEdge,
-                mag_filter: wgpu::FilterMode::Linear,
-                min_filter: wgpu::FilterMode::Nearest,
-                mipmap_filter: wgpu::FilterMode::Nearest,
-                ..Default::default()
-            }
-        );
-        
-        Ok(Self { dimensions, texture, view, sampler })
+/// Fills the mip chain of a texture from its first level, each level being
+/// drawn from the previous one with a bilinear blit.
+pub struct MipmapGenerator {
+    pipeline: wgpu::RenderPipeline,
+    hdr_pipeline: wgpu::RenderPipeline,
+    bind_group_layout: wgpu::BindGroupLayout,
+    sampler: wgpu::Sampler,
+}
+
+impl MipmapGenerator {
+    pub fn new(device: &wgpu::Device) -> Self {
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+            label: Some("blit_bind_group_layout"),
+        });
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Blit Shader"),
+            source: wgpu::ShaderSource::Wgsl(BLIT_SHADER.into()),
+        });
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Blit Pipeline Layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let create_pipeline = |format| device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Blit Pipeline"),
+            layout: Some(&layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format,
+                    blend: Some(wgpu::BlendState::REPLACE),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        });
+        let pipeline = create_pipeline(Texture::FORMAT);
+        let hdr_pipeline = create_pipeline(Texture::HDR_FORMAT);
+        // sampling a level at the centre of the texels of the next one
+        // averages four texels
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+        Self { pipeline, hdr_pipeline, bind_group_layout, sampler }
+    }
+
+    pub fn generate(&self, device: &wgpu::Device, encoder: &mut wgpu::CommandEncoder, texture: &Texture) {
+        let pipeline = if texture.is_high_dynamic_range() { &self.hdr_pipeline } else { &self.pipeline };
+        for level in 1..texture.texture.mip_level_count() {
+            let source = texture.level_view(level - 1);
+            let destination = texture.level_view(level);
+            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                layout: &self.bind_group_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&source),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Sampler(&self.sampler),
+                    },
+                ],
+                label: Some("blit_bind_group"),
+            });
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Mipmap Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &destination,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                occlusion_query_set: None,
+                timestamp_writes: None,
+            });
+            render_pass.set_pipeline(pipeline);
+            render_pass.set_bind_group(0, &bind_group, &[]);
+            render_pass.draw(0..3, 0..1);
+        }
     }
 }
 
+/// Copies an `Rgba8UnormSrgb` texture back to the CPU, waiting for the GPU.
+pub fn read_back(device: &wgpu::Device, queue: &wgpu::Queue, texture: &wgpu::Texture) -> Result<image::RgbaImage> {
+    let (width, height) = (texture.width(), texture.height());
+    // rows of the buffer have to be aligned on 256 bytes
+    let row_size = 4 * width;
+    let padded_row_size = row_size.div_ceil(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT) * wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+    let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Read Back Buffer"),
+        size: (padded_row_size * height) as wgpu::BufferAddress,
+        usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+        mapped_at_creation: false,
+    });
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("Read Back Encoder"),
+    });
+    encoder.copy_texture_to_buffer(
+        wgpu::ImageCopyTexture {
+            aspect: wgpu::TextureAspect::All,
+            texture,
+            mip_level: 0,
+            origin: wgpu::Origin3d::ZERO,
+        },
+        wgpu::ImageCopyBuffer {
+            buffer: &buffer,
+            layout: wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(padded_row_size),
+                rows_per_image: Some(height),
+            },
+        },
+        texture.size(),
+    );
+    queue.submit(std::iter::once(encoder.finish()));
+
+    let slice = buffer.slice(..);
+    let (sender, receiver) = std::sync::mpsc::channel();
+    slice.map_async(wgpu::MapMode::Read, move |result| {
+        let _ = sender.send(result);
+    });
+    device.poll(wgpu::Maintain::Wait);
+    receiver.recv()?.context("Failed to map the read back buffer")?;
+    let pixels: Vec<u8> = slice.get_mapped_range()
+        .chunks(padded_row_size as usize)
+        .flat_map(|row| &row[..row_size as usize])
+        .copied()
+        .collect();
+    buffer.unmap();
+    image::RgbaImage::from_raw(width, height, pixels).context("Read back buffer too small")
+}
@@ -0,0 +1,239 @@
+use std::time::Instant;
+use serde::{Deserialize, Serialize};
+use wgpu::util::DeviceExt;
+
+use crate::texture;
+
+const TRANSITION_SHADER: &str = include_str!("shaders/transition.wgsl");
+
+/// Effect used to go from a wallpaper to the next one, the values match the
+/// constants of the shader.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Transition {
+    #[default]
+    Crossfade,
+    Slide,
+    Wipe,
+    Dissolve,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct TransitionUniform {
+    from_scale: [f32; 2],
+    to_scale: [f32; 2],
+    progress: f32,
+    kind: u32,
+    _padding: [f32; 2],
+}
+
+/// Same fit as the wallpaper plane: the image fills the height of the window
+fn fit_scale(dimensions: (u32, u32), size: winit::dpi::PhysicalSize<u32>) -> [f32; 2] {
+    let window_ratio = size.width as f32 / size.height as f32;
+    let image_ratio = dimensions.0 as f32 / dimensions.1 as f32;
+    [window_ratio / image_ratio, 1.]
+}
+
+/// Blends two wallpapers into a texture the size of the window, bound like a
+/// wallpaper so the shaders don't know about transitions.
+pub struct TransitionRenderer {
+    transition: Transition,
+    duration: f32,
+    pipeline: wgpu::RenderPipeline,
+    bind_group_layout: wgpu::BindGroupLayout,
+    uniform_buffer: wgpu::Buffer,
+    sampler: wgpu::Sampler,
+    size: winit::dpi::PhysicalSize<u32>,
+    target: texture::Texture,
+    pub target_bind_group: wgpu::BindGroup,
+}
+
+impl TransitionRenderer {
+    /// `texture_bind_group_layout` is the layout of the wallpaper bind group.
+    pub fn new(
+        device: &wgpu::Device,
+        texture_bind_group_layout: &wgpu::BindGroupLayout,
+        transition: Transition,
+        duration: f32,
+        size: winit::dpi::PhysicalSize<u32>,
+    ) -> Self {
+        let texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                multisampled: false,
+                view_dimension: wgpu::TextureViewDimension::D2,
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+            },
+            count: None,
+        };
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                texture_entry(0),
+                texture_entry(1),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("transition_bind_group_layout"),
+        });
+        let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Transition Buffer"),
+            contents: bytemuck::cast_slice(&[TransitionUniform {
+                from_scale: [1., 1.],
+                to_scale: [1., 1.],
+                progress: 0.,
+                kind: transition as u32,
+                _padding: [0.; 2],
+            }]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Transition Shader"),
+            source: wgpu::ShaderSource::Wgsl(TRANSITION_SHADER.into()),
+        });
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Transition Pipeline Layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Transition Pipeline"),
+            layout: Some(&layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: texture::Texture::HDR_FORMAT,
+                    blend: Some(wgpu::BlendState::REPLACE),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        });
+
+        // drawn at the size of the window, no need for mipmaps, and in floats
+        // to keep the range of high dynamic range wallpapers
+        let target = texture::Texture::new(
+            device, (size.width, size.height), texture::Texture::HDR_FORMAT, false, Some("transition-texture")
+        );
+        let target_bind_group = crate::create_texture_bind_group(
+            device, texture_bind_group_layout, &target, "transition_target_bind_group"
+        );
+        Self {
+            transition,
+            duration,
+            pipeline,
+            bind_group_layout,
+            uniform_buffer,
+            sampler,
+            size,
+            target,
+            target_bind_group,
+        }
+    }
+
+    pub fn resize(
+        &mut self,
+        device: &wgpu::Device,
+        texture_bind_group_layout: &wgpu::BindGroupLayout,
+        size: winit::dpi::PhysicalSize<u32>,
+    ) {
+        self.size = size;
+        self.target = texture::Texture::new(
+            device, (size.width, size.height), texture::Texture::HDR_FORMAT, false, Some("transition-texture")
+        );
+        self.target_bind_group = crate::create_texture_bind_group(
+            device, texture_bind_group_layout, &self.target, "transition_target_bind_group"
+        );
+    }
+
+    /// From 0 to 1, None once the transition started at `start` is over
+    pub fn progress(&self, start: Instant) -> Option<f32> {
+        let progress = if self.duration > 0. {
+            start.elapsed().as_secs_f32() / self.duration
+        } else {
+            1.
+        };
+        (progress < 1.).then_some(progress)
+    }
+
+    pub fn render(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        from: &texture::Texture,
+        to: &texture::Texture,
+        progress: f32,
+    ) {
+        let uniform = TransitionUniform {
+            from_scale: fit_scale(from.dimensions, self.size),
+            to_scale: fit_scale(to.dimensions, self.size),
+            progress,
+            kind: self.transition as u32,
+            _padding: [0.; 2],
+        };
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[uniform]));
+        // the textures change with every slide, and every frame for animations
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry { binding: 0, resource: wgpu::BindingResource::TextureView(&from.view) },
+                wgpu::BindGroupEntry { binding: 1, resource: wgpu::BindingResource::TextureView(&to.view) },
+                wgpu::BindGroupEntry { binding: 2, resource: wgpu::BindingResource::Sampler(&self.sampler) },
+                wgpu::BindGroupEntry { binding: 3, resource: self.uniform_buffer.as_entire_binding() },
+            ],
+            label: Some("transition_bind_group"),
+        });
+
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Transition Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: &self.target.view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            occlusion_query_set: None,
+            timestamp_writes: None,
+        });
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, &bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
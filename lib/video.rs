@@ -0,0 +1,363 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+use anyhow::{bail, Context, Result};
+use wgpu::util::DeviceExt;
+
+mod avi;
+mod y4m;
+use avi::AviDecoder;
+use y4m::Y4mDecoder;
+
+const YUV_SHADER: &str = include_str!("shaders/yuv.wgsl");
+/// Number of decoded frames waiting to be shown
+const NB_BUFFERED_FRAMES: usize = 3;
+
+#[derive(Clone)]
+pub struct Plane {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+impl Plane {
+    /// Chroma plane of greyscale videos
+    fn neutral() -> Self {
+        Self { width: 1, height: 1, data: vec![128] }
+    }
+}
+
+/// Frame in planar Y'CbCr, the chroma planes may be subsampled.
+#[derive(Clone)]
+pub struct YuvFrame {
+    pub planes: [Plane; 3],
+    /// Samples use the whole 0-255 range instead of 16-235 (16-240 for chroma)
+    pub full_range: bool,
+}
+
+pub trait VideoDecoder: Send {
+    fn dimensions(&self) -> (u32, u32);
+    fn frame_rate(&self) -> f64;
+    /// None at the end of the video
+    fn next_frame(&mut self) -> Result<Option<YuvFrame>>;
+    fn rewind(&mut self) -> Result<()>;
+}
+
+pub fn is_video(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| ["y4m", "avi"].contains(&extension.to_lowercase().as_str()))
+}
+
+fn open_decoder(path: &Path) -> Result<Box<dyn VideoDecoder>> {
+    let file = BufReader::new(File::open(path)
+        .with_context(|| format!("Failed to open video {}", path.display()))?);
+    let is_y4m = path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("y4m"));
+    Ok(if is_y4m {
+        Box::new(Y4mDecoder::new(file)?)
+    } else {
+        Box::new(AviDecoder::new(file)?)
+    })
+}
+
+/// Runs on the decoder thread until the video is dropped, ends or fails.
+fn decode(mut decoder: Box<dyn VideoDecoder>, looping: bool, sender: SyncSender<Result<YuvFrame>>) {
+    let mut nb_frames = 0;
+    loop {
+        let result = match decoder.next_frame() {
+            Ok(Some(frame)) => {
+                nb_frames += 1;
+                // sending only fails once the video is dropped
+                if sender.send(Ok(frame)).is_err() {
+                    return;
+                }
+                Ok(())
+            },
+            // a video without frames would spin forever
+            Ok(None) if looping && nb_frames > 0 => {
+                nb_frames = 0;
+                decoder.rewind()
+            },
+            Ok(None) => return,
+            Err(error) => Err(error),
+        };
+        if let Err(error) = result {
+            let _ = sender.send(Err(error));
+            return;
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct ConversionUniform {
+    full_range: u32,
+    _padding: [u32; 3],
+}
+
+/// Video wallpaper, frames are decoded on a separate thread and converted to
+/// RGB on the GPU, in the wallpaper texture.
+pub struct Video {
+    frames: Receiver<Result<YuvFrame>>,
+    dimensions: (u32, u32),
+    frame_duration: Duration,
+    shown_at: Instant,
+    paused: bool,
+    /// A frame has been uploaded but not converted yet
+    pending: bool,
+
+    planes: Vec<wgpu::Texture>,
+    sampler: wgpu::Sampler,
+    uniform_buffer: wgpu::Buffer,
+    bind_group_layout: wgpu::BindGroupLayout,
+    // created with the textures of the planes
+    bind_group: Option<wgpu::BindGroup>,
+    pipeline: wgpu::RenderPipeline,
+}
+
+impl Video {
+    /// `target_format` is the one of the texture the frames are converted in.
+    pub fn open(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        path: &Path,
+        target_format: wgpu::TextureFormat,
+        playback_rate: f32,
+        looping: bool,
+    ) -> Result<Self> {
+        if playback_rate <= 0. {
+            bail!("The playback rate has to be positive");
+        }
+        let decoder = open_decoder(path)
+            .with_context(|| format!("Failed to open video {}", path.display()))?;
+        let dimensions = decoder.dimensions();
+        let frame_duration = Duration::from_secs_f64(1. / (decoder.frame_rate() * playback_rate as f64));
+
+        let (sender, frames) = mpsc::sync_channel(NB_BUFFERED_FRAMES);
+        thread::Builder::new()
+            .name("video decoder".to_string())
+            .spawn(move || decode(decoder, looping, sender))?;
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            // upsamples the chroma
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+        let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("YUV Conversion Buffer"),
+            contents: bytemuck::cast_slice(&[ConversionUniform { full_range: 0, _padding: [0; 3] }]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        let plane_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                multisampled: false,
+                view_dimension: wgpu::TextureViewDimension::D2,
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+            },
+            count: None,
+        };
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                plane_entry(0),
+                plane_entry(1),
+                plane_entry(2),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("yuv_bind_group_layout"),
+        });
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("YUV Shader"),
+            source: wgpu::ShaderSource::Wgsl(YUV_SHADER.into()),
+        });
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("YUV Pipeline Layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("YUV Pipeline"),
+            layout: Some(&layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: target_format,
+                    blend: Some(wgpu::BlendState::REPLACE),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        });
+
+        let mut video = Self {
+            frames,
+            dimensions,
+            frame_duration,
+            shown_at: Instant::now(),
+            paused: false,
+            pending: false,
+            planes: Vec::new(),
+            sampler,
+            uniform_buffer,
+            bind_group_layout,
+            bind_group: None,
+            pipeline,
+        };
+        // the first frame is shown right away
+        let frame = video.frames.recv().context("The video has no frame")??;
+        video.upload(device, queue, &frame);
+        Ok(video)
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        self.dimensions
+    }
+
+    /// Paused videos don't decode nor upload anything, e.g. while the window is hidden.
+    pub fn set_paused(&mut self, paused: bool) {
+        if self.paused && !paused {
+            self.shown_at = Instant::now();
+        }
+        self.paused = paused;
+    }
+
+    /// Uploads the next frame if the current one has been shown long enough.
+    /// True when a new frame was uploaded
+    pub fn update(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) -> bool {
+        let now = Instant::now();
+        if self.paused || now - self.shown_at < self.frame_duration {
+            return false;
+        }
+        match self.frames.try_recv() {
+            Ok(Ok(frame)) => {
+                // keep to the schedule unless we are really late
+                self.shown_at = if now - self.shown_at < 2 * self.frame_duration {
+                    self.shown_at + self.frame_duration
+                } else {
+                    now
+                };
+                self.upload(device, queue, &frame);
+                true
+            },
+            Ok(Err(error)) => {
+                log::error!("Video stopped: {:#}", error);
+                false
+            },
+            // the decoder is late, or the video is over
+            Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => false,
+        }
+    }
+
+    fn upload(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, frame: &YuvFrame) {
+        let same_sizes = self.planes.len() == 3 && self.planes.iter().zip(&frame.planes)
+            .all(|(texture, plane)| texture.width() == plane.width && texture.height() == plane.height);
+        if !same_sizes {
+            self.planes = frame.planes.iter().map(|plane| device.create_texture(&wgpu::TextureDescriptor {
+                label: Some("video-plane"),
+                size: wgpu::Extent3d { width: plane.width, height: plane.height, depth_or_array_layers: 1 },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::R8Unorm,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+                view_formats: &[],
+            })).collect();
+            let views: Vec<wgpu::TextureView> = self.planes.iter()
+                .map(|plane| plane.create_view(&wgpu::TextureViewDescriptor::default()))
+                .collect();
+            self.bind_group = Some(device.create_bind_group(&wgpu::BindGroupDescriptor {
+                layout: &self.bind_group_layout,
+                entries: &[
+                    wgpu::BindGroupEntry { binding: 0, resource: wgpu::BindingResource::TextureView(&views[0]) },
+                    wgpu::BindGroupEntry { binding: 1, resource: wgpu::BindingResource::TextureView(&views[1]) },
+                    wgpu::BindGroupEntry { binding: 2, resource: wgpu::BindingResource::TextureView(&views[2]) },
+                    wgpu::BindGroupEntry { binding: 3, resource: wgpu::BindingResource::Sampler(&self.sampler) },
+                    wgpu::BindGroupEntry { binding: 4, resource: self.uniform_buffer.as_entire_binding() },
+                ],
+                label: Some("yuv_bind_group"),
+            }));
+        }
+
+        for (texture, plane) in self.planes.iter().zip(&frame.planes) {
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    aspect: wgpu::TextureAspect::All,
+                    texture,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d::ZERO,
+                },
+                &plane.data,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(plane.width),
+                    rows_per_image: Some(plane.height),
+                },
+                texture.size(),
+            );
+        }
+        let uniform = ConversionUniform { full_range: frame.full_range as u32, _padding: [0; 3] };
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[uniform]));
+        self.pending = true;
+    }
+
+    /// Converts the latest uploaded frame to RGB in `target`, returns false
+    /// if there was no new frame.
+    pub fn convert(&mut self, encoder: &mut wgpu::CommandEncoder, target: &wgpu::TextureView) -> bool {
+        let bind_group = match &self.bind_group {
+            Some(bind_group) if self.pending => bind_group,
+            _ => return false,
+        };
+        self.pending = false;
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("YUV Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: target,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            occlusion_query_set: None,
+            timestamp_writes: None,
+        });
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+        true
+    }
+}
@@ -0,0 +1,317 @@
+use std::io::{Read, Seek, SeekFrom};
+use anyhow::{bail, Context, Result};
+use byteorder::{LittleEndian, ReadBytesExt};
+use jpeg_decoder::PixelFormat;
+
+use super::{Plane, VideoDecoder, YuvFrame};
+
+type FourCC = [u8; 4];
+
+/// Reader for Motion JPEG in AVI (RIFF) files, including OpenDML files
+/// bigger than 1 GB.
+pub struct AviDecoder<R: Read + Seek> {
+    reader: R,
+    width: u32,
+    height: u32,
+    frame_rate: f64,
+    /// Offset and size of the JPEG of every frame of the video stream
+    frames: Vec<(u64, u32)>,
+    next_frame: usize,
+    last_frame: Option<YuvFrame>,
+}
+
+/// What we pick up while walking the RIFF tree
+#[derive(Default)]
+struct Headers {
+    microseconds_per_frame: u32,
+    nb_streams: usize,
+    video_stream: Option<usize>,
+    rate: Option<(u32, u32)>,
+    dimensions: Option<(u32, u32)>,
+    frames: Vec<(u64, u32)>,
+}
+
+fn read_fourcc<R: Read>(reader: &mut R) -> Result<FourCC> {
+    let mut fourcc = [0; 4];
+    reader.read_exact(&mut fourcc)?;
+    Ok(fourcc)
+}
+
+/// Chunks are padded to an even size
+fn padded(size: u32) -> u64 {
+    size as u64 + (size & 1) as u64
+}
+
+impl<R: Read + Seek> AviDecoder<R> {
+    pub fn new(mut reader: R) -> Result<Self> {
+        let end = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(0))?;
+        let mut headers = Headers::default();
+        let mut offset = 0;
+        while offset + 12 <= end {
+            reader.seek(SeekFrom::Start(offset))?;
+            let id = read_fourcc(&mut reader)?;
+            let size = reader.read_u32::<LittleEndian>()?;
+            let form = read_fourcc(&mut reader)?;
+            match (&id, &form) {
+                (b"RIFF", b"AVI ") if offset == 0 => (),
+                // OpenDML extensions carrying more frames
+                (b"RIFF", b"AVIX") if offset > 0 => (),
+                _ if offset == 0 => bail!("Not an AVI file"),
+                _ => break,
+            }
+            let list_end = (offset + 8 + size as u64).min(end);
+            Self::parse_list(&mut reader, offset + 12, list_end, &mut headers)?;
+            offset += 8 + padded(size);
+        }
+
+        let (width, height) = headers.dimensions.context("No video stream in the AVI file")?;
+        let frame_rate = match headers.rate {
+            Some((scale, rate)) if scale > 0 && rate > 0 => rate as f64 / scale as f64,
+            _ if headers.microseconds_per_frame > 0 => 1e6 / headers.microseconds_per_frame as f64,
+            _ => bail!("No frame rate in the AVI file"),
+        };
+        if headers.frames.is_empty() {
+            bail!("No frame in the AVI file");
+        }
+        Ok(Self {
+            reader,
+            width,
+            height,
+            frame_rate,
+            frames: headers.frames,
+            next_frame: 0,
+            last_frame: None,
+        })
+    }
+
+    fn parse_list(reader: &mut R, start: u64, end: u64, headers: &mut Headers) -> Result<()> {
+        let mut offset = start;
+        while offset + 8 <= end {
+            reader.seek(SeekFrom::Start(offset))?;
+            let id = read_fourcc(reader)?;
+            let size = reader.read_u32::<LittleEndian>()?;
+            let data_end = (offset + 8 + size as u64).min(end);
+            match &id {
+                b"LIST" => match &read_fourcc(reader)? {
+                    b"strl" => {
+                        headers.nb_streams += 1;
+                        Self::parse_list(reader, offset + 12, data_end, headers)?;
+                    },
+                    b"hdrl" | b"movi" | b"rec " => Self::parse_list(reader, offset + 12, data_end, headers)?,
+                    _ => (),
+                },
+                b"avih" => headers.microseconds_per_frame = reader.read_u32::<LittleEndian>()?,
+                b"strh" => {
+                    let kind = read_fourcc(reader)?;
+                    if &kind == b"vids" && headers.video_stream.is_none() {
+                        headers.video_stream = Some(headers.nb_streams - 1);
+                        // handler, flags, priority, language and initial frames
+                        reader.seek(SeekFrom::Current(16))?;
+                        let scale = reader.read_u32::<LittleEndian>()?;
+                        let rate = reader.read_u32::<LittleEndian>()?;
+                        headers.rate = Some((scale, rate));
+                    }
+                },
+                b"strf" if headers.video_stream == Some(headers.nb_streams - 1) && headers.dimensions.is_none() => {
+                    // BITMAPINFOHEADER
+                    let _size = reader.read_u32::<LittleEndian>()?;
+                    let width = reader.read_i32::<LittleEndian>()?;
+                    let height = reader.read_i32::<LittleEndian>()?;
+                    let _planes = reader.read_u16::<LittleEndian>()?;
+                    let _bit_count = reader.read_u16::<LittleEndian>()?;
+                    let compression = read_fourcc(reader)?;
+                    if !compression.eq_ignore_ascii_case(b"MJPG") {
+                        bail!("Unsupported AVI codec {}, only Motion JPEG is", String::from_utf8_lossy(&compression));
+                    }
+                    headers.dimensions = Some((width.unsigned_abs(), height.unsigned_abs()));
+                },
+                // frames are tagged with the number of their stream, like 00dc
+                [tens, units, b'd', b'c' | b'b'] => {
+                    let stream = (*tens as char).to_digit(10)
+                        .zip((*units as char).to_digit(10))
+                        .map(|(tens, units)| (tens * 10 + units) as usize);
+                    if stream.is_some() && stream == headers.video_stream {
+                        headers.frames.push((offset + 8, size));
+                    }
+                },
+                _ => (),
+            }
+            offset += 8 + padded(size);
+        }
+        Ok(())
+    }
+
+    fn decode_frame(&mut self, offset: u64, size: u32) -> Result<YuvFrame> {
+        let mut jpeg = vec![0; size as usize];
+        self.reader.seek(SeekFrom::Start(offset))?;
+        self.reader.read_exact(&mut jpeg).context("Truncated AVI frame")?;
+
+        let mut decoder = jpeg_decoder::Decoder::new(&jpeg[..]);
+        let pixels = decoder.decode().context("Failed to decode a Motion JPEG frame")?;
+        let info = decoder.info().context("Failed to decode a Motion JPEG frame")?;
+        let (width, height) = (info.width as u32, info.height as u32);
+        let planes = match info.pixel_format {
+            PixelFormat::L8 => [Plane { width, height, data: pixels }, Plane::neutral(), Plane::neutral()],
+            PixelFormat::RGB24 => {
+                // back to Y'CbCr from RGB, the decoder only upsamples
+                // subsampled chroma on the way to RGB
+                let plane = |[r, g, b]: [f32; 3], offset: f32| Plane {
+                    width,
+                    height,
+                    data: pixels.chunks_exact(3)
+                        .map(|rgb| (offset + r * rgb[0] as f32 + g * rgb[1] as f32 + b * rgb[2] as f32)
+                            .round().clamp(0., 255.) as u8)
+                        .collect(),
+                };
+                [
+                    plane([0.299, 0.587, 0.114], 0.),
+                    plane([-0.168736, -0.331264, 0.5], 128.),
+                    plane([0.5, -0.418688, -0.081312], 128.),
+                ]
+            },
+            format => bail!("Unsupported Motion JPEG pixel format {:?}", format),
+        };
+        // JPEG always uses the full range
+        Ok(YuvFrame { planes, full_range: true })
+    }
+}
+
+impl<R: Read + Seek + Send> VideoDecoder for AviDecoder<R> {
+    fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    fn frame_rate(&self) -> f64 {
+        self.frame_rate
+    }
+
+    fn next_frame(&mut self) -> Result<Option<YuvFrame>> {
+        let (offset, size) = match self.frames.get(self.next_frame) {
+            Some(frame) => *frame,
+            None => return Ok(None),
+        };
+        self.next_frame += 1;
+        let frame = match (size, &self.last_frame) {
+            // empty chunks repeat the previous frame
+            (0, Some(last_frame)) => last_frame.clone(),
+            (0, None) => return self.next_frame(),
+            _ => self.decode_frame(offset, size)?,
+        };
+        self.last_frame = Some(frame.clone());
+        Ok(Some(frame))
+    }
+
+    fn rewind(&mut self) -> Result<()> {
+        self.next_frame = 0;
+        self.last_frame = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+    use byteorder::WriteBytesExt;
+    use image::codecs::jpeg::JpegEncoder;
+    use super::*;
+
+    fn chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.write_u32::<LittleEndian>(data.len() as u32).unwrap();
+        chunk.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    fn list(kind: &[u8; 4], chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = kind.to_vec();
+        chunks.iter().for_each(|chunk| data.extend_from_slice(chunk));
+        chunk(b"LIST", &data)
+    }
+
+    fn jpeg(colour: [u8; 3], width: u32, height: u32) -> Vec<u8> {
+        let image = image::RgbImage::from_pixel(width, height, image::Rgb(colour));
+        let mut jpeg = Vec::new();
+        JpegEncoder::new_with_quality(&mut jpeg, 100).encode_image(&image).unwrap();
+        jpeg
+    }
+
+    fn write_avi(frames: &[Vec<u8>], width: u32, height: u32) -> Vec<u8> {
+        let mut avih = Vec::new();
+        avih.write_u32::<LittleEndian>(40_000).unwrap();
+        avih.write_all(&[0; 52]).unwrap();
+        let mut strh = b"vidsMJPG".to_vec();
+        strh.write_all(&[0; 12]).unwrap();
+        strh.write_u32::<LittleEndian>(1).unwrap();
+        strh.write_u32::<LittleEndian>(30).unwrap();
+        strh.write_all(&[0; 28]).unwrap();
+        let mut strf = Vec::new();
+        strf.write_u32::<LittleEndian>(40).unwrap();
+        strf.write_i32::<LittleEndian>(width as i32).unwrap();
+        strf.write_i32::<LittleEndian>(height as i32).unwrap();
+        strf.write_u16::<LittleEndian>(1).unwrap();
+        strf.write_u16::<LittleEndian>(24).unwrap();
+        strf.write_all(b"MJPG").unwrap();
+        strf.write_all(&[0; 20]).unwrap();
+
+        let mut movi = vec![chunk(b"01wb", &[0; 16])];
+        for jpeg in frames {
+            movi.push(chunk(b"00dc", jpeg));
+        }
+        movi.push(chunk(b"00dc", &[]));
+
+        let mut riff = b"AVI ".to_vec();
+        riff.extend(list(b"hdrl", &[
+            chunk(b"avih", &avih),
+            list(b"strl", &[chunk(b"strh", &strh), chunk(b"strf", &strf)]),
+        ]));
+        riff.extend(chunk(b"JUNK", &[0; 7]));
+        riff.extend(list(b"movi", &movi));
+        chunk(b"RIFF", &riff)
+    }
+
+    #[test]
+    fn decode_mjpeg() {
+        let avi = write_avi(&[jpeg([128, 128, 128], 16, 8), jpeg([255, 0, 0], 16, 8)], 16, 8);
+        let mut decoder = AviDecoder::new(Cursor::new(avi)).unwrap();
+        assert_eq!(decoder.dimensions(), (16, 8));
+        assert_eq!(decoder.frame_rate(), 30.);
+
+        let close = |plane: &Plane, value: u8| plane.data.iter().all(|sample| sample.abs_diff(value) <= 2);
+        let grey = decoder.next_frame().unwrap().unwrap();
+        assert_eq!((grey.planes[0].width, grey.planes[0].height), (16, 8));
+        assert!(grey.full_range);
+        assert!(grey.planes.iter().all(|plane| close(plane, 128)));
+        // BT.601 full range red
+        let red = decoder.next_frame().unwrap().unwrap();
+        assert!(close(&red.planes[0], 76));
+        assert!(close(&red.planes[1], 85));
+        assert!(close(&red.planes[2], 255));
+        // the empty chunk repeats the last frame
+        assert!(close(&decoder.next_frame().unwrap().unwrap().planes[0], 76));
+        assert!(decoder.next_frame().unwrap().is_none());
+
+        decoder.rewind().unwrap();
+        assert!(close(&decoder.next_frame().unwrap().unwrap().planes[0], 128));
+    }
+
+    #[test]
+    fn decode_subsampled_mjpeg() {
+        // 4:2:0 like ffmpeg writes by default, red on the left and blue on the right
+        let avi = write_avi(&[include_bytes!("testdata/red_blue_420.jpg").to_vec()], 32, 16);
+        let frame = AviDecoder::new(Cursor::new(avi)).unwrap().next_frame().unwrap().unwrap();
+        assert!(frame.planes.iter().all(|plane| (plane.width, plane.height, plane.data.len()) == (32, 16, 512)));
+        // away from the edge where the chroma is interpolated
+        let sample = |plane: usize, x: usize| frame.planes[plane].data[8 * 32 + x];
+        assert!([(0, 76), (1, 85), (2, 255)].iter().all(|&(plane, value)| sample(plane, 2).abs_diff(value) <= 2));
+        assert!([(0, 29), (1, 255), (2, 107)].iter().all(|&(plane, value)| sample(plane, 29).abs_diff(value) <= 2));
+    }
+
+    #[test]
+    fn reject_other_files() {
+        assert!(AviDecoder::new(Cursor::new(b"RIFF\x04\x00\x00\x00WAVE".to_vec())).is_err());
+    }
+}
@@ -0,0 +1,156 @@
+use std::io::{BufRead, Seek, SeekFrom};
+use anyhow::{bail, Context, Result};
+
+use super::{Plane, VideoDecoder, YuvFrame};
+
+/// Larger than any texture a GPU takes, it keeps a bad header from allocating gigabytes
+const MAX_DIMENSION: u32 = 16384;
+
+/// Reader for YUV4MPEG2 streams, uncompressed 8-bit planar YUV.
+pub struct Y4mDecoder<R: BufRead + Seek> {
+    reader: R,
+    width: u32,
+    height: u32,
+    chroma: (u32, u32),
+    frame_rate: f64,
+    full_range: bool,
+    // start of the first frame, for looping
+    data_start: u64,
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<String>> {
+    let mut line = Vec::new();
+    if reader.read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        bail!("Truncated YUV4MPEG2 stream");
+    }
+    Ok(Some(String::from_utf8(line).context("Invalid YUV4MPEG2 header")?))
+}
+
+fn parse_ratio(value: &str) -> Result<f64> {
+    let (numerator, denominator) = value.split_once(':')
+        .with_context(|| format!("Invalid ratio {}", value))?;
+    let (numerator, denominator): (f64, f64) = (numerator.parse()?, denominator.parse()?);
+    if numerator <= 0. || denominator <= 0. {
+        bail!("Invalid ratio {}", value);
+    }
+    Ok(numerator / denominator)
+}
+
+impl<R: BufRead + Seek> Y4mDecoder<R> {
+    pub fn new(mut reader: R) -> Result<Self> {
+        let header = read_line(&mut reader)?.context("Empty YUV4MPEG2 stream")?;
+        let mut parameters = header.split(' ');
+        if parameters.next() != Some("YUV4MPEG2") {
+            bail!("Not a YUV4MPEG2 stream");
+        }
+        let (mut width, mut height) = (0u32, 0u32);
+        let mut frame_rate = 25.;
+        let mut colour_space = "420jpeg";
+        let mut full_range = false;
+        for parameter in parameters.filter(|parameter| !parameter.is_empty()) {
+            let (tag, value) = parameter.split_at(1);
+            match tag {
+                "W" => width = value.parse().context("Invalid width")?,
+                "H" => height = value.parse().context("Invalid height")?,
+                "F" => frame_rate = parse_ratio(value)?,
+                "C" => colour_space = value,
+                "I" if value != "p" && value != "?" => bail!("Interlaced videos are not supported"),
+                "X" if value == "COLORRANGE=FULL" => full_range = true,
+                // aspect ratio and comments
+                _ => (),
+            }
+        }
+        if width == 0 || height == 0 {
+            bail!("Missing dimensions in the YUV4MPEG2 header");
+        }
+        if width > MAX_DIMENSION || height > MAX_DIMENSION {
+            bail!("YUV4MPEG2 dimensions {}x{} are too large", width, height);
+        }
+        let chroma = match colour_space {
+            "420jpeg" | "420paldv" | "420mpeg2" | "420" => (width.div_ceil(2), height.div_ceil(2)),
+            "422" => (width.div_ceil(2), height),
+            "444" => (width, height),
+            "mono" => (0, 0),
+            _ => bail!("Unsupported YUV4MPEG2 colour space {}", colour_space),
+        };
+        let data_start = reader.stream_position()?;
+        Ok(Self { reader, width, height, chroma, frame_rate, full_range, data_start })
+    }
+
+    fn read_plane(&mut self, width: u32, height: u32) -> Result<Plane> {
+        let mut data = vec![0; width as usize * height as usize];
+        self.reader.read_exact(&mut data).context("Truncated YUV4MPEG2 frame")?;
+        Ok(Plane { width, height, data })
+    }
+}
+
+impl<R: BufRead + Seek + Send> VideoDecoder for Y4mDecoder<R> {
+    fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    fn frame_rate(&self) -> f64 {
+        self.frame_rate
+    }
+
+    fn next_frame(&mut self) -> Result<Option<YuvFrame>> {
+        let header = match read_line(&mut self.reader)? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        if !header.starts_with("FRAME") {
+            bail!("Invalid YUV4MPEG2 frame header");
+        }
+        let luma = self.read_plane(self.width, self.height)?;
+        let planes = match self.chroma {
+            (0, 0) => [luma, Plane::neutral(), Plane::neutral()],
+            (width, height) => [luma, self.read_plane(width, height)?, self.read_plane(width, height)?],
+        };
+        Ok(Some(YuvFrame { planes, full_range: self.full_range }))
+    }
+
+    fn rewind(&mut self) -> Result<()> {
+        self.reader.seek(SeekFrom::Start(self.data_start))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use super::*;
+
+    #[test]
+    fn decode_420() {
+        let mut stream = b"YUV4MPEG2 W4 H2 F30000:1001 Ip A1:1 C420jpeg XYSCSS=420JPEG\n".to_vec();
+        for i in 0..2u8 {
+            stream.extend_from_slice(b"FRAME\n");
+            stream.extend_from_slice(&[i; 8]);
+            stream.extend_from_slice(&[10, 11, 20, 21]);
+        }
+        let mut decoder = Y4mDecoder::new(Cursor::new(stream)).unwrap();
+        assert_eq!(decoder.dimensions(), (4, 2));
+        assert!((decoder.frame_rate() - 29.97).abs() < 1e-3);
+
+        let frame = decoder.next_frame().unwrap().unwrap();
+        assert_eq!(frame.planes[0].data, vec![0; 8]);
+        assert_eq!((frame.planes[1].width, frame.planes[1].height), (2, 1));
+        assert_eq!(frame.planes[1].data, vec![10, 11]);
+        assert_eq!(frame.planes[2].data, vec![20, 21]);
+        assert_eq!(decoder.next_frame().unwrap().unwrap().planes[0].data, vec![1; 8]);
+        assert!(decoder.next_frame().unwrap().is_none());
+
+        decoder.rewind().unwrap();
+        assert_eq!(decoder.next_frame().unwrap().unwrap().planes[0].data, vec![0; 8]);
+    }
+
+    #[test]
+    fn huge_dimensions() {
+        let stream = b"YUV4MPEG2 W65536 H65536 C420jpeg\nFRAME\n".to_vec();
+        let error = Y4mDecoder::new(Cursor::new(stream)).err().unwrap();
+        assert_eq!(error.to_string(), "YUV4MPEG2 dimensions 65536x65536 are too large");
+    }
+}
@@ -0,0 +1,340 @@
+use serde::{Deserialize, Serialize};
+use wgpu::util::DeviceExt;
+
+use crate::audio::{AudioFeatures, SAMPLE_RATE};
+use crate::audio::spectrum::SpectrumAnalyser;
+
+const VISUALISER_SHADER: &str = include_str!("shaders/visualiser.wgsl");
+/// Samples analysed for every update, 1024 frequency bins
+const FFT_SIZE: usize = 2048;
+/// Columns of the spectrogram, one per update
+const SPECTROGRAM_HISTORY: u32 = 256;
+const MAX_BANDS: u32 = 1024;
+
+/// How the audio is drawn, the values match the constants of the shader.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum VisualiserKind {
+    /// Spectrum bars on a logarithmic frequency axis
+    #[default]
+    Bars,
+    /// Oscilloscope of the latest samples
+    Waveform,
+    /// Spectrum bars around a circle
+    Radial,
+    /// Scrolling spectrum history, the newest on the right
+    Spectrogram,
+}
+
+/// A visualiser as written in the config.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VisualiserDescription {
+    pub kind: VisualiserKind,
+    /// RGBA from 0 to 1
+    pub color: [f32; 4],
+    /// Centre in the window, from (0, 0) at the top left to (1, 1)
+    pub position: [f32; 2],
+    /// Width and height as fractions of the window
+    pub size: [f32; 2],
+    /// Number of bars, of frequency rows for the spectrogram or of points of the waveform
+    pub bands: u32,
+    /// 0 follows the audio instantly, close to 1 barely moves
+    pub smoothing: f32,
+}
+impl Default for VisualiserDescription {
+    fn default() -> Self {
+        Self {
+            kind: VisualiserKind::default(),
+            color: [1., 1., 1., 0.8],
+            position: [0.5, 0.85],
+            size: [0.6, 0.2],
+            bands: 64,
+            smoothing: 0.6,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct VisualiserUniform {
+    rect: [f32; 4],
+    color: [f32; 4],
+    pixels: [f32; 2],
+    head: u32,
+    kind: u32,
+    bands: u32,
+    _padding: [u32; 3],
+}
+
+fn uniform(
+    description: &VisualiserDescription,
+    bands: u32,
+    head: u32,
+    size: winit::dpi::PhysicalSize<u32>,
+) -> VisualiserUniform {
+    let [x, y] = description.position;
+    let [width, height] = description.size;
+    VisualiserUniform {
+        rect: [x - width / 2., y - height / 2., width, height],
+        color: description.color,
+        pixels: [width * size.width as f32, height * size.height as f32],
+        head,
+        kind: description.kind as u32,
+        bands,
+        _padding: [0; 3],
+    }
+}
+
+/// Maximum of the magnitudes in `bands` bands of logarithmic width, from the
+/// first bin (the DC offset is left out) to the last one
+fn log_bands(magnitudes: &[f64], bands: usize) -> Vec<f64> {
+    let nb_bins = magnitudes.len() as f64;
+    let edge = |band: usize| nb_bins.powf(band as f64 / bands as f64) as usize;
+    (0..bands).map(|band| {
+        let start = edge(band).min(magnitudes.len() - 1);
+        let end = edge(band + 1).clamp(start + 1, magnitudes.len());
+        magnitudes[start..end].iter().copied().fold(0., f64::max)
+    }).collect()
+}
+
+/// `points` samples evenly spread over the latest `FFT_SIZE` samples, from
+/// 0 to 1
+fn waveform(samples: &[f64], points: usize) -> Vec<f64> {
+    let samples = &samples[samples.len().saturating_sub(FFT_SIZE)..];
+    (0..points).map(|point| {
+        let sample = samples.get(point * samples.len() / points).copied().unwrap_or(0.);
+        (sample.clamp(-1., 1.) + 1.) / 2.
+    }).collect()
+}
+
+struct Visualiser {
+    description: VisualiserDescription,
+    bands: u32,
+    values: Vec<f32>,
+    head: u32,
+    texture: wgpu::Texture,
+    uniform_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+}
+
+impl Visualiser {
+    fn update(&mut self, queue: &wgpu::Queue, spectrum: &[f64], samples: &[f64], size: winit::dpi::PhysicalSize<u32>) {
+        let targets = match self.description.kind {
+            VisualiserKind::Waveform => waveform(samples, self.bands as usize),
+            _ => log_bands(spectrum, self.bands as usize),
+        };
+        let smoothing = self.description.smoothing.clamp(0., 1.);
+        for (value, target) in self.values.iter_mut().zip(targets) {
+            *value = smoothing * *value + (1. - smoothing) * target as f32;
+        }
+        let texels: Vec<u8> = self.values.iter().map(|value| (value.clamp(0., 1.) * 255.) as u8).collect();
+
+        let (origin, extent) = if self.description.kind == VisualiserKind::Spectrogram {
+            // a column per update, written over the oldest one
+            self.head = (self.head + 1) % SPECTROGRAM_HISTORY;
+            (wgpu::Origin3d { x: self.head, y: 0, z: 0 }, (1, self.bands))
+        } else {
+            (wgpu::Origin3d::ZERO, (self.bands, 1))
+        };
+        queue.write_texture(
+            wgpu::ImageCopyTexture {
+                aspect: wgpu::TextureAspect::All,
+                texture: &self.texture,
+                mip_level: 0,
+                origin,
+            },
+            &texels,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(extent.0),
+                rows_per_image: Some(extent.1),
+            },
+            wgpu::Extent3d { width: extent.0, height: extent.1, depth_or_array_layers: 1 },
+        );
+        let uniform = uniform(&self.description, self.bands, self.head, size);
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[uniform]));
+    }
+}
+
+/// Draws the visualisers of the config over the wallpaper, fed with the
+/// spectrum of the captured audio.
+pub struct VisualiserRenderer {
+    pipeline: wgpu::RenderPipeline,
+    analyser: SpectrumAnalyser,
+    visualisers: Vec<Visualiser>,
+    size: winit::dpi::PhysicalSize<u32>,
+}
+
+impl VisualiserRenderer {
+    pub fn new(
+        device: &wgpu::Device,
+        descriptions: &[VisualiserDescription],
+        format: wgpu::TextureFormat,
+        size: winit::dpi::PhysicalSize<u32>,
+    ) -> Self {
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("visualiser_bind_group_layout"),
+        });
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Visualiser Shader"),
+            source: wgpu::ShaderSource::Wgsl(VISUALISER_SHADER.into()),
+        });
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Visualiser Pipeline Layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Visualiser Pipeline"),
+            layout: Some(&layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format,
+                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        });
+
+        let visualisers = descriptions.iter().map(|description| {
+            let bands = description.bands.clamp(2, MAX_BANDS);
+            let (width, height) = match description.kind {
+                VisualiserKind::Spectrogram => (SPECTROGRAM_HISTORY, bands),
+                _ => (bands, 1),
+            };
+            let texture = device.create_texture(&wgpu::TextureDescriptor {
+                label: Some("visualiser-texture"),
+                size: wgpu::Extent3d { width, height, depth_or_array_layers: 1 },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::R8Unorm,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+                view_formats: &[],
+            });
+            let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+            let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Visualiser Buffer"),
+                contents: bytemuck::cast_slice(&[uniform(description, bands, 0, size)]),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            });
+            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                layout: &bind_group_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: uniform_buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::TextureView(&view),
+                    },
+                ],
+                label: Some("visualiser_bind_group"),
+            });
+            Visualiser {
+                description: description.clone(),
+                bands,
+                values: vec![0.; bands as usize],
+                head: 0,
+                texture,
+                uniform_buffer,
+                bind_group,
+            }
+        }).collect();
+
+        Self {
+            pipeline,
+            analyser: SpectrumAnalyser::new(FFT_SIZE, SAMPLE_RATE.into(), 0.),
+            visualisers,
+            size,
+        }
+    }
+
+    pub fn resize(&mut self, size: winit::dpi::PhysicalSize<u32>) {
+        // the uniforms are written with the next update
+        self.size = size;
+    }
+
+    /// Without audio, the visualisers fall back to silence.
+    pub fn update(&mut self, queue: &wgpu::Queue, audio: Option<&AudioFeatures>) {
+        let samples: Vec<f64> = match audio {
+            Some(audio) => {
+                self.analyser.set_sample_rate(audio.sample_rate.into());
+                audio.samples.iter().copied().collect()
+            }
+            None => vec![0.; FFT_SIZE],
+        };
+        let spectrum = self.analyser.process(&samples);
+        for visualiser in &mut self.visualisers {
+            visualiser.update(queue, spectrum, &samples, self.size);
+        }
+    }
+
+    pub fn draw<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>) {
+        render_pass.set_pipeline(&self.pipeline);
+        for visualiser in &self.visualisers {
+            render_pass.set_bind_group(0, &visualiser.bind_group, &[]);
+            render_pass.draw(0..6, 0..1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn logarithmic_bands() {
+        let mut magnitudes = vec![0.; 1024];
+        magnitudes[1] = 0.5;
+        magnitudes[700] = 1.;
+        let bands = log_bands(&magnitudes, 10);
+        assert_eq!(bands.len(), 10);
+        // the first band is the lowest bin alone, the last one is the widest
+        assert_eq!(bands[0], 0.5);
+        assert_eq!(bands[9], 1.);
+        assert!(bands[1..9].iter().all(|&band| band == 0.));
+    }
+
+    #[test]
+    fn waveform_points() {
+        let samples: Vec<f64> = (0..4096).map(|i| if i < 3072 { -1. } else { 1. }).collect();
+        // only the latest FFT_SIZE samples are drawn
+        assert_eq!(waveform(&samples, 4), vec![0., 0., 1., 1.]);
+        assert_eq!(waveform(&[], 2), vec![0.5, 0.5]);
+    }
+}
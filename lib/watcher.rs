@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Context, Result};
+use inotify::{Inotify, WatchDescriptor, WatchMask};
+
+/// Watches files for modifications without blocking the render loop.
+///
+/// The parent directory is watched rather than the file itself since most
+/// editors save by writing a new file and renaming it over the old one.
+pub struct FileWatcher {
+    inotify: Inotify,
+    buffer: [u8; 4096],
+    directories: HashMap<WatchDescriptor, PathBuf>,
+    files: HashMap<PathBuf, Vec<PathBuf>>,
+}
+
+impl FileWatcher {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            inotify: Inotify::init().context("Failed to initialise inotify")?,
+            buffer: [0; 4096],
+            directories: HashMap::new(),
+            files: HashMap::new(),
+        })
+    }
+
+    pub fn watch(&mut self, path: &Path) -> Result<()> {
+        let directory = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        if path.file_name().is_none() {
+            bail!("{} is not a file", path.display());
+        }
+
+        if !self.files.contains_key(&directory) {
+            let descriptor = self.inotify.watches()
+                .add(&directory, WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO)
+                .with_context(|| format!("Failed to watch {}", directory.display()))?;
+            self.directories.insert(descriptor, directory.clone());
+        }
+        let paths = self.files.entry(directory).or_default();
+        if !paths.iter().any(|watched| watched == path) {
+            paths.push(path.to_path_buf());
+        }
+        Ok(())
+    }
+
+    /// Watched files that were written since the last call, as they were given to `watch`.
+    pub fn changed(&mut self) -> Vec<PathBuf> {
+        let mut changed = Vec::new();
+        loop {
+            let events = match self.inotify.read_events(&mut self.buffer) {
+                Ok(events) => events,
+                Err(error) => {
+                    if error.kind() != io::ErrorKind::WouldBlock {
+                        log::error!("Failed to read file events: {}", error);
+                    }
+                    break;
+                },
+            };
+            let mut nb_events = 0;
+            for event in events {
+                nb_events += 1;
+                let (Some(directory), Some(name)) = (self.directories.get(&event.wd), event.name)
+                    else { continue };
+                let paths = self.files.get(directory).into_iter().flatten();
+                for path in paths.filter(|path| path.file_name() == Some(name)) {
+                    if !changed.contains(path) {
+                        changed.push(path.clone());
+                    }
+                }
+            }
+            if nb_events == 0 {
+                break;
+            }
+        }
+        changed
+    }
+}
@@ -1,5 +1,54 @@
-use walllib::run;
+use std::env;
+use std::path::PathBuf;
+use std::process;
+use walllib::{ctl, run, screenshot};
+
+const USAGE: &str = "Usage: wallfuck [--screenshot <file.png> [--size <width>x<height>]]\n       wallfuck ctl <command>";
+
+fn parse_size(size: &str) -> Option<(u32, u32)> {
+    let (width, height) = size.split_once('x')?;
+    Some((width.parse().ok()?, height.parse().ok()?))
+}
 
 fn main() {
-    pollster::block_on(run());
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().is_some_and(|arg| arg == "ctl") {
+        match ctl(&args[1..]) {
+            Ok(response) => println!("{}", response),
+            Err(error) => {
+                eprintln!("{:#}", error);
+                process::exit(1);
+            },
+        }
+        return;
+    }
+
+    let mut screenshot_path = None;
+    let mut size = (1920, 1080);
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match (arg.as_str(), args.next()) {
+            ("--screenshot", Some(path)) => screenshot_path = Some(PathBuf::from(path)),
+            ("--size", Some(value)) => match parse_size(&value) {
+                Some(value) => size = value,
+                None => {
+                    eprintln!("Invalid size {}\n{}", value, USAGE);
+                    process::exit(1);
+                },
+            },
+            _ => {
+                eprintln!("{}", USAGE);
+                process::exit(1);
+            },
+        }
+    }
+
+    let result = match screenshot_path {
+        Some(path) => pollster::block_on(screenshot(&path, size.0, size.1)),
+        None => pollster::block_on(run()),
+    };
+    if let Err(error) = result {
+        eprintln!("{:#}", error);
+        process::exit(1);
+    }
 }